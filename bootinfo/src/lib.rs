@@ -0,0 +1,268 @@
+//! Types the loader hands to the kernel. Both sides depend on this crate, so the layout only has
+//! to be defined once.
+//!
+//! The kernel receives a pointer to [`KernelArgs`] and should check it with
+//! [`KernelArgs::from_ptr`] before touching anything else.
+#![no_std]
+
+use core::mem::{size_of, MaybeUninit};
+use core::{ptr, slice};
+
+pub mod tags;
+
+use tags::Tags;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct MemoryRegionKind(pub u32);
+
+impl MemoryRegionKind {
+    pub const USABLE: MemoryRegionKind = MemoryRegionKind(1);
+    pub const RESERVED: MemoryRegionKind = MemoryRegionKind(2);
+    pub const ACPI_RECLAIMABLE: MemoryRegionKind = MemoryRegionKind(3);
+    pub const ACPI_NVS: MemoryRegionKind = MemoryRegionKind(4);
+    pub const MMIO: MemoryRegionKind = MemoryRegionKind(5);
+    pub const BOOTLOADER_RECLAIMABLE: MemoryRegionKind = MemoryRegionKind(6);
+    pub const KERNEL_AND_MODULES: MemoryRegionKind = MemoryRegionKind(7);
+    pub const FRAMEBUFFER: MemoryRegionKind = MemoryRegionKind(8);
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: MemoryRegionKind,
+}
+
+impl MemoryRegion {
+    pub fn new(start: u64, len: u64, kind: MemoryRegionKind) -> MemoryRegion {
+        MemoryRegion {
+            start,
+            end: start + len,
+            kind,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct FrameBufferInfo {
+    pub address: usize,
+    pub len: usize,
+    pub screen_width: u32,
+    pub screen_height: u32,
+    pub pixels_per_scan_line: u32,
+}
+
+/// A file the loader placed in memory next to the kernel
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Module {
+    pub start: u64,
+    pub len: u64,
+    /// Null terminated UTF-8 name, usually the path the module was loaded from
+    pub name: [u8; Module::NAME_LEN],
+}
+
+impl Module {
+    pub const NAME_LEN: usize = 64;
+
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(Self::NAME_LEN);
+
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+pub struct MemoryMapType;
+
+impl MemoryMapType {
+    /// `memory_map` points to UEFI memory descriptors
+    pub const UEFI: u8 = 1;
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KernelArgsError {
+    /// The pointer is null or does not point to boot information
+    BadMagic,
+    /// The loader speaks a newer version of this ABI
+    UnsupportedVersion(u32),
+    /// The structure is shorter than its header
+    TooSmall(u32),
+}
+
+/// Boot information handed to the kernel.
+///
+/// The first three fields never change. [`KernelArgs::from_ptr`] accepts boot information of
+/// this or an older version and only reads the fields `size` covers, the rest stays empty. New
+/// kinds of information are added as tags to the list at `tags` instead of growing this
+/// structure.
+///
+/// The pointers are only valid as long as the kernel has not reused the memory they point to,
+/// which is why the accessors reading through them are unsafe.
+#[repr(C)]
+pub struct KernelArgs {
+    pub magic: u64,
+    pub version: u32,
+    /// Size of the structure in bytes as written by the loader
+    pub size: u32,
+
+    pub kernel_ptr: *const u8,
+    pub kernel_len: u64,
+
+    pub rsd_ptr: *const u8,
+
+    pub memory_map: *const u8,
+    pub memory_map_size: u64,
+    pub memory_map_type: u8,
+
+    //Sorted, non-overlapping and merged map built from the UEFI memory map
+    pub memory_regions: *const MemoryRegion,
+    pub memory_regions_len: u64,
+
+    //Pool used by the loader after exiting the boot services, reclaimable once the kernel is
+    //done with the boot information
+    pub loader_pool: *const u8,
+    pub loader_pool_len: u64,
+
+    pub framebuffer: FrameBufferInfo,
+
+    pub modules: *const Module,
+    pub modules_len: u64,
+
+    /// Tag list, see [`tags`]
+    pub tags: *const u8,
+    pub tags_len: u64,
+}
+
+impl KernelArgs {
+    pub const MAGIC: u64 = u64::from_le_bytes(*b"NightBI\0");
+    pub const VERSION: u32 = 1;
+
+    /// Boot information with the header filled in and everything else empty
+    pub const fn new(framebuffer: FrameBufferInfo) -> KernelArgs {
+        KernelArgs {
+            magic: Self::MAGIC,
+            version: Self::VERSION,
+            size: size_of::<KernelArgs>() as u32,
+            kernel_ptr: core::ptr::null(),
+            kernel_len: 0,
+            rsd_ptr: core::ptr::null(),
+            memory_map: core::ptr::null(),
+            memory_map_size: 0,
+            memory_map_type: 0,
+            memory_regions: core::ptr::null(),
+            memory_regions_len: 0,
+            loader_pool: core::ptr::null(),
+            loader_pool_len: 0,
+            framebuffer,
+            modules: core::ptr::null(),
+            modules_len: 0,
+            tags: core::ptr::null(),
+            tags_len: 0,
+        }
+    }
+
+    /// Bytes of the fields every version has
+    const HEADER_SIZE: usize = 16;
+
+    /// Checks the header of the boot information at `ptr` and copies the fields it covers,
+    /// fields of a newer version than the loader's are left empty
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be null or point to readable memory of at least 16 bytes and, if the header
+    /// checks out, of `size` bytes, which is the case for the pointer passed to the kernel entry
+    /// point.
+    pub unsafe fn from_ptr(ptr: *const KernelArgs) -> Result<KernelArgs, KernelArgsError> {
+        if ptr.is_null() || ptr::addr_of!((*ptr).magic).read_unaligned() != Self::MAGIC {
+            return Err(KernelArgsError::BadMagic);
+        }
+
+        let version = ptr::addr_of!((*ptr).version).read_unaligned();
+        if version == 0 || version > Self::VERSION {
+            return Err(KernelArgsError::UnsupportedVersion(version));
+        }
+        let size = ptr::addr_of!((*ptr).size).read_unaligned();
+        if (size as usize) < Self::HEADER_SIZE {
+            return Err(KernelArgsError::TooSmall(size));
+        }
+
+        //Every field is valid when zeroed, null pointers read as empty
+        let mut args = MaybeUninit::<KernelArgs>::zeroed();
+        ptr::copy_nonoverlapping(
+            ptr as *const u8,
+            args.as_mut_ptr() as *mut u8,
+            (size as usize).min(size_of::<KernelArgs>()),
+        );
+
+        Ok(args.assume_init())
+    }
+
+    /// # Safety
+    ///
+    /// The memory of the regions must not have been reused.
+    pub unsafe fn memory_regions(&self) -> &[MemoryRegion] {
+        if self.memory_regions.is_null() {
+            return &[];
+        }
+
+        slice::from_raw_parts(self.memory_regions, self.memory_regions_len as usize)
+    }
+
+    pub fn framebuffer(&self) -> Option<&FrameBufferInfo> {
+        if self.framebuffer.address == 0 {
+            None
+        } else {
+            Some(&self.framebuffer)
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The memory of the module list must not have been reused.
+    pub unsafe fn modules(&self) -> &[Module] {
+        if self.modules.is_null() {
+            return &[];
+        }
+
+        slice::from_raw_parts(self.modules, self.modules_len as usize)
+    }
+
+    /// # Safety
+    ///
+    /// The memory of the tag list must not have been reused.
+    pub unsafe fn tags(&self) -> Tags<'_> {
+        if self.tags.is_null() {
+            return Tags::new(&[]);
+        }
+
+        Tags::new(slice::from_raw_parts(self.tags, self.tags_len as usize))
+    }
+
+    /// The kernel image as loaded, relocations already applied
+    ///
+    /// # Safety
+    ///
+    /// The memory of the kernel image must not have been reused.
+    pub unsafe fn kernel(&self) -> &[u8] {
+        if self.kernel_ptr.is_null() {
+            return &[];
+        }
+
+        slice::from_raw_parts(self.kernel_ptr, self.kernel_len as usize)
+    }
+}
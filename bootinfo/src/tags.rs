@@ -0,0 +1,350 @@
+//! A list of typed, length prefixed records. Every tag starts with a [`TagHeader`] and is padded
+//! to 8 bytes, the list ends with a tag of type [`TagType::END`]. Kernels skip tags they do not
+//! know, so new information can be added without changing [`crate::KernelArgs`].
+
+use crate::{FrameBufferInfo, MemoryRegion};
+use core::mem::{align_of, size_of, size_of_val};
+use core::{ptr, slice, str};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct TagType(pub u32);
+
+impl TagType {
+    pub const END: TagType = TagType(0);
+    /// An array of [`MemoryRegion`]
+    pub const MEMORY_MAP: TagType = TagType(1);
+    /// A [`FrameBufferInfo`]
+    pub const FRAMEBUFFER: TagType = TagType(2);
+    /// The physical address of the ACPI RSDP as `u64`
+    pub const RSDP: TagType = TagType(3);
+    /// A [`ModuleTag`] followed by the UTF-8 name of the module
+    pub const MODULE: TagType = TagType(4);
+    /// The UTF-8 kernel command line
+    pub const CMDLINE: TagType = TagType(5);
+    /// The raw EDID of the display
+    pub const EDID: TagType = TagType(6);
+    /// The physical address of the SMBIOS entry point as `u64`
+    pub const SMBIOS: TagType = TagType(7);
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct TagHeader {
+    pub tag_type: TagType,
+    /// Size of the tag including this header, without the padding that follows it
+    pub size: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct ModuleTag {
+    pub start: u64,
+    pub len: u64,
+}
+
+/// Tags are aligned to this many bytes
+pub const TAG_ALIGN: usize = 8;
+
+const HEADER_SIZE: usize = size_of::<TagHeader>();
+
+/// Bytes taken by the end tag
+pub const END_TAG_SIZE: usize = HEADER_SIZE;
+
+const fn align_up(size: usize) -> usize {
+    (size + TAG_ALIGN - 1) & !(TAG_ALIGN - 1)
+}
+
+/// Bytes a tag with `payload` bytes occupies in the list, padding included
+pub const fn tag_size(payload: usize) -> usize {
+    align_up(HEADER_SIZE + payload)
+}
+
+/// A tag read from the list, payloads the kernel can not use are handed out as bytes
+#[derive(Copy, Clone, Debug)]
+pub enum Tag<'a> {
+    MemoryMap(&'a [MemoryRegion]),
+    FrameBuffer(&'a FrameBufferInfo),
+    Rsdp(u64),
+    Module { module: ModuleTag, name: &'a str },
+    Cmdline(&'a str),
+    Edid(&'a [u8]),
+    Smbios(u64),
+    Unknown(TagType, &'a [u8]),
+}
+
+/// Iterates over a tag list, stops at the end tag or a malformed tag
+pub struct Tags<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Tags<'a> {
+    /// `data` must start at a tag and be aligned to [`TAG_ALIGN`]
+    pub fn new(data: &'a [u8]) -> Tags<'a> {
+        Tags { data }
+    }
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Tag<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < HEADER_SIZE || self.data.as_ptr().align_offset(TAG_ALIGN) != 0 {
+            return None;
+        }
+
+        let header = unsafe { ptr::read(self.data.as_ptr() as *const TagHeader) };
+        let size = header.size as usize;
+        if header.tag_type == TagType::END || size < HEADER_SIZE || size > self.data.len() {
+            return None;
+        }
+
+        let payload = &self.data[HEADER_SIZE..size];
+        self.data = &self.data[align_up(size).min(self.data.len())..];
+
+        Some(match header.tag_type {
+            TagType::MEMORY_MAP => Tag::MemoryMap(cast_slice(payload)),
+            TagType::FRAMEBUFFER => match cast_slice(payload).first() {
+                Some(framebuffer) => Tag::FrameBuffer(framebuffer),
+                None => Tag::Unknown(header.tag_type, payload),
+            },
+            TagType::RSDP => Tag::Rsdp(read_u64(payload)),
+            TagType::SMBIOS => Tag::Smbios(read_u64(payload)),
+            TagType::MODULE if payload.len() >= size_of::<ModuleTag>() => {
+                let module = unsafe { ptr::read(payload.as_ptr() as *const ModuleTag) };
+                let name = str::from_utf8(&payload[size_of::<ModuleTag>()..]).unwrap_or("");
+
+                Tag::Module { module, name }
+            }
+            TagType::CMDLINE => Tag::Cmdline(str::from_utf8(payload).unwrap_or("")),
+            TagType::EDID => Tag::Edid(payload),
+            tag_type => Tag::Unknown(tag_type, payload),
+        })
+    }
+}
+
+/// Reinterprets the aligned `payload` as whole elements of `T`
+fn cast_slice<T>(payload: &[u8]) -> &[T] {
+    unsafe { slice::from_raw_parts(payload.as_ptr() as *const T, payload.len() / size_of::<T>()) }
+}
+
+fn read_u64(payload: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    let len = payload.len().min(8);
+    bytes[..len].copy_from_slice(&payload[..len]);
+
+    u64::from_le_bytes(bytes)
+}
+
+/// The buffer can not hold the tag
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BufferTooSmall;
+
+/// Writes a tag list into a buffer provided by the loader, so the list ends up in a single
+/// allocation. The buffer has to be aligned to [`TAG_ALIGN`].
+pub struct TagWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> TagWriter<'a> {
+    /// `buffer` has to hold at least the end tag
+    pub fn new(buffer: &'a mut [u8]) -> TagWriter<'a> {
+        assert_eq!(buffer.as_ptr().align_offset(TAG_ALIGN), 0);
+        assert!(buffer.len() >= END_TAG_SIZE);
+
+        TagWriter { buffer, len: 0 }
+    }
+
+    /// Appends a tag whose payload is the elements of `values` followed by `bytes`
+    fn push_raw<T: Copy>(
+        &mut self,
+        tag_type: TagType,
+        values: &[T],
+        bytes: &[u8],
+    ) -> Result<(), BufferTooSmall> {
+        debug_assert!(align_of::<T>() <= TAG_ALIGN);
+
+        let values_size = size_of_val(values);
+        let size = HEADER_SIZE + values_size + bytes.len();
+        //The end tag always has to fit behind the tag
+        if self.len + align_up(size) + END_TAG_SIZE > self.buffer.len() || size > u32::MAX as usize
+        {
+            return Err(BufferTooSmall);
+        }
+
+        let start = self.buffer[self.len..].as_mut_ptr();
+        unsafe {
+            ptr::write(
+                start as *mut TagHeader,
+                TagHeader {
+                    tag_type,
+                    size: size as u32,
+                },
+            );
+            ptr::copy_nonoverlapping(
+                values.as_ptr(),
+                start.add(HEADER_SIZE) as *mut T,
+                values.len(),
+            );
+        }
+
+        let bytes_start = self.len + HEADER_SIZE + values_size;
+        self.buffer[bytes_start..bytes_start + bytes.len()].copy_from_slice(bytes);
+        self.buffer[self.len + size..self.len + align_up(size)].fill(0);
+        self.len += align_up(size);
+
+        Ok(())
+    }
+
+    pub fn memory_map(&mut self, regions: &[MemoryRegion]) -> Result<(), BufferTooSmall> {
+        self.push_raw(TagType::MEMORY_MAP, regions, &[])
+    }
+
+    pub fn framebuffer(&mut self, framebuffer: &FrameBufferInfo) -> Result<(), BufferTooSmall> {
+        self.push_raw(TagType::FRAMEBUFFER, slice::from_ref(framebuffer), &[])
+    }
+
+    pub fn rsdp(&mut self, address: u64) -> Result<(), BufferTooSmall> {
+        self.push_raw(TagType::RSDP, &[address], &[])
+    }
+
+    pub fn smbios(&mut self, address: u64) -> Result<(), BufferTooSmall> {
+        self.push_raw(TagType::SMBIOS, &[address], &[])
+    }
+
+    pub fn module(&mut self, module: ModuleTag, name: &str) -> Result<(), BufferTooSmall> {
+        self.push_raw(TagType::MODULE, &[module], name.as_bytes())
+    }
+
+    pub fn cmdline(&mut self, cmdline: &str) -> Result<(), BufferTooSmall> {
+        self.push_raw::<u8>(TagType::CMDLINE, &[], cmdline.as_bytes())
+    }
+
+    pub fn edid(&mut self, edid: &[u8]) -> Result<(), BufferTooSmall> {
+        self.push_raw::<u8>(TagType::EDID, &[], edid)
+    }
+
+    /// Any other tag, e.g. one this version of the crate does not know yet
+    pub fn raw(&mut self, tag_type: TagType, payload: &[u8]) -> Result<(), BufferTooSmall> {
+        self.push_raw::<u8>(tag_type, &[], payload)
+    }
+
+    /// Terminates the list and returns its size in bytes
+    pub fn finish(self) -> usize {
+        //push_raw always leaves room for the end tag
+        unsafe {
+            ptr::write(
+                self.buffer[self.len..].as_mut_ptr() as *mut TagHeader,
+                TagHeader {
+                    tag_type: TagType::END,
+                    size: END_TAG_SIZE as u32,
+                },
+            );
+        }
+
+        self.len + END_TAG_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryRegionKind;
+
+    /// A tag buffer with the alignment [`TagWriter`] needs
+    fn buffer(words: &mut [u64]) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, size_of_val(words)) }
+    }
+
+    #[test]
+    fn round_trip() {
+        let regions = [
+            MemoryRegion::new(0, 0x1000, MemoryRegionKind::RESERVED),
+            MemoryRegion::new(0x1000, 0x9F000, MemoryRegionKind::USABLE),
+        ];
+        let framebuffer = FrameBufferInfo {
+            address: 0x8000_0000,
+            len: 1024 * 768 * 4,
+            screen_width: 1024,
+            screen_height: 768,
+            pixels_per_scan_line: 1024,
+        };
+        let module = ModuleTag {
+            start: 0x20_0000,
+            len: 1234,
+        };
+
+        let mut words = [0u64; 64];
+        let data = buffer(&mut words);
+        let mut writer = TagWriter::new(data);
+        writer.memory_map(&regions).unwrap();
+        writer.framebuffer(&framebuffer).unwrap();
+        writer.rsdp(0xE_0000).unwrap();
+        writer.cmdline("console=ttyS0").unwrap();
+        writer.module(module, "/initrd.img").unwrap();
+        writer.smbios(0xF_0000).unwrap();
+        let len = writer.finish();
+        assert_eq!(len % TAG_ALIGN, 0);
+
+        let mut tags = Tags::new(&data[..len]);
+        match tags.next() {
+            Some(Tag::MemoryMap(read)) => assert_eq!(read, &regions),
+            tag => panic!("expected the memory map, got {:?}", tag),
+        }
+        match tags.next() {
+            Some(Tag::FrameBuffer(read)) => {
+                assert_eq!(read.address, framebuffer.address);
+                assert_eq!(read.screen_width, 1024);
+                assert_eq!(read.screen_height, 768);
+            }
+            tag => panic!("expected the framebuffer, got {:?}", tag),
+        }
+        assert!(matches!(tags.next(), Some(Tag::Rsdp(0xE_0000))));
+        assert!(matches!(tags.next(), Some(Tag::Cmdline("console=ttyS0"))));
+        match tags.next() {
+            Some(Tag::Module { module: read, name }) => {
+                assert_eq!((read.start, read.len), (module.start, module.len));
+                assert_eq!(name, "/initrd.img");
+            }
+            tag => panic!("expected the module, got {:?}", tag),
+        }
+        assert!(matches!(tags.next(), Some(Tag::Smbios(0xF_0000))));
+        assert!(tags.next().is_none());
+    }
+
+    #[test]
+    fn skips_unknown_tags() {
+        let mut words = [0u64; 16];
+        let data = buffer(&mut words);
+        let mut writer = TagWriter::new(data);
+        writer.raw(TagType(0x1234), &[1, 2, 3]).unwrap();
+        writer.rsdp(0xE_0000).unwrap();
+        let len = writer.finish();
+
+        let mut tags = Tags::new(&data[..len]);
+        match tags.next() {
+            Some(Tag::Unknown(tag_type, payload)) => {
+                assert_eq!(tag_type, TagType(0x1234));
+                assert_eq!(payload, &[1, 2, 3]);
+            }
+            tag => panic!("expected the unknown tag, got {:?}", tag),
+        }
+        //The padding of the unknown tag is skipped as well
+        assert!(matches!(tags.next(), Some(Tag::Rsdp(0xE_0000))));
+        assert!(tags.next().is_none());
+    }
+
+    #[test]
+    fn leaves_room_for_the_end_tag() {
+        let mut words = [0u64; 3];
+        let data = buffer(&mut words);
+        let mut writer = TagWriter::new(data);
+        writer.rsdp(0xE_0000).unwrap();
+        assert_eq!(writer.smbios(0xF_0000), Err(BufferTooSmall));
+
+        let len = writer.finish();
+        assert_eq!(len, tag_size(size_of::<u64>()) + END_TAG_SIZE);
+        assert_eq!(Tags::new(&data[..len]).count(), 1);
+    }
+}
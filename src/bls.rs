@@ -0,0 +1,410 @@
+//! Discovers boot entries written in the Boot Loader Specification format. Every volume may hold
+//! entries in `/loader/entries/*.conf`, one file per entry:
+//!
+//! ```text
+//! title      Fedora Linux
+//! version    6.8.5-301.fc40.x86_64
+//! architecture x64
+//! linux      /vmlinuz-6.8.5-301.fc40.x86_64
+//! initrd     /initramfs-6.8.5-301.fc40.x86_64.img
+//! options    root=UUID=... ro quiet
+//! ```
+//!
+//! `efi` takes the place of `linux` for entries that chainload an EFI application.
+//!
+//! Entries named like `fedora+3.conf` count their boot attempts. The loader renames the file to
+//! `fedora+2-1.conf` before booting it, and so on until `fedora+0-3.conf`, which is considered
+//! bad and only booted if nothing else is left. The booted system marks the entry as good by
+//! dropping the counter from the name once it came up.
+
+use crate::efi::simple_fs::{FileInfo, FILE_MODE_READ, FILE_MODE_WRITE};
+use crate::efi::{EfiStatus, EFI_NOT_FOUND};
+use crate::menu::{BootEntry, BootEntryKind};
+use crate::read_file;
+use crate::volume::Volume;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+pub const ENTRIES_PATH: &str = "/loader/entries";
+
+/// The architecture entries name to be bootable by this loader
+const ARCHITECTURE: &str = "x64";
+
+/// An entry file as read from a volume
+#[derive(Clone, Debug, Default)]
+pub struct BlsEntry {
+    /// File name without the `.conf` suffix and the boot counter
+    pub id: String,
+    /// Tries left and tries done, if the entry counts its boot attempts
+    pub counter: Option<(u32, u32)>,
+    pub title: Option<String>,
+    pub version: Option<String>,
+    pub sort_key: Option<String>,
+    pub architecture: Option<String>,
+    pub linux: Option<String>,
+    pub efi: Option<String>,
+    pub initrd: Vec<String>,
+    pub options: Vec<String>,
+}
+
+impl BlsEntry {
+    /// Parses an entry file, unknown keys are ignored
+    pub fn parse(id: &str, data: &str) -> BlsEntry {
+        let mut entry = BlsEntry {
+            id: id.to_string(),
+            ..BlsEntry::default()
+        };
+
+        for line in data.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once(char::is_whitespace) {
+                Some((key, value)) => (key, value.trim().to_string()),
+                None => continue,
+            };
+
+            match key {
+                "title" => entry.title = Some(value),
+                "version" => entry.version = Some(value),
+                "sort-key" => entry.sort_key = Some(value),
+                "architecture" => entry.architecture = Some(value),
+                "linux" => entry.linux = Some(value),
+                "efi" => entry.efi = Some(value),
+                "initrd" => entry.initrd.push(value),
+                "options" => entry.options.push(value),
+                _ => {}
+            }
+        }
+
+        entry
+    }
+
+    /// Whether the entry can be booted here, it needs something to boot and a matching
+    /// architecture if it names one
+    pub fn is_bootable(&self) -> bool {
+        let architecture = self
+            .architecture
+            .as_ref()
+            .is_none_or(|a| a.eq_ignore_ascii_case(ARCHITECTURE));
+
+        architecture && (self.linux.is_some() || self.efi.is_some())
+    }
+
+    pub fn is_bad(&self) -> bool {
+        matches!(self.counter, Some((0, _)))
+    }
+
+    /// Menu order: bad entries last, entries with a sort key first, ordered by it, then newer
+    /// versions before older ones and finally the file names
+    pub fn order(&self, other: &BlsEntry) -> Ordering {
+        let bad = self.is_bad().cmp(&other.is_bad());
+
+        let sort_key = match (&self.sort_key, &other.sort_key) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+
+        let version = match (&self.version, &other.version) {
+            (Some(a), Some(b)) => compare_versions(b, a),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+
+        bad.then(sort_key)
+            .then(version)
+            .then_with(|| compare_versions(&other.id, &self.id))
+    }
+
+    /// The menu entry, its files are loaded from `volume`
+    pub fn to_boot_entry(&self, volume: &Volume) -> BootEntry {
+        let title = self.title.as_deref().unwrap_or(&self.id);
+        let title = match &self.version {
+            Some(version) => format!("{} ({})", title, version),
+            None => title.to_string(),
+        };
+
+        let (kind, kernel) = match (&self.linux, &self.efi) {
+            (Some(linux), _) => (BootEntryKind::Kernel, linux),
+            (None, Some(efi)) => (BootEntryKind::Chainload, efi),
+            (None, None) => (BootEntryKind::Kernel, &self.id),
+        };
+
+        BootEntry {
+            id: self.id.clone(),
+            title,
+            kind,
+            volume: Some(volume.handle),
+            kernel: kernel.clone(),
+            cmdline: self.options.join(" "),
+            modules: self.initrd.clone(),
+            tries_left: self.counter.map(|(left, _)| left),
+        }
+    }
+}
+
+/// Reads the bootable entries of every volume and returns them in menu order
+pub fn discover(volumes: &[Volume]) -> Vec<BootEntry> {
+    let mut entries: Vec<(BlsEntry, &Volume)> = volumes
+        .iter()
+        .flat_map(|volume| {
+            read_entries(volume)
+                .into_iter()
+                .map(move |entry| (entry, volume))
+        })
+        .filter(|(entry, _)| entry.is_bootable())
+        .collect();
+
+    entries.sort_by(|(a, _), (b, _)| a.order(b));
+
+    entries
+        .iter()
+        .map(|(entry, volume)| entry.to_boot_entry(volume))
+        .collect()
+}
+
+/// Uses up one try of the counting entry `id` on `volume` by renaming its file. The name is
+/// taken from the volume, so attempts counted earlier during this boot are not lost.
+#[allow(unsafe_code)]
+pub fn count_attempt(volume: &Volume, id: &str) -> Result<(), EfiStatus> {
+    let (name, left, done) = entry_files(volume)?
+        .iter()
+        .find_map(|info| {
+            let (entry_id, counter) = split_file_name(&info.file_name)?;
+            let (left, done) = counter?;
+            (entry_id == id).then(|| (info.file_name.clone(), left, done))
+        })
+        .ok_or(EFI_NOT_FOUND)?;
+
+    if left == 0 {
+        return Ok(());
+    }
+
+    let path = format!("{}/{}", ENTRIES_PATH, name);
+    let file = unsafe { (*volume.root).open_path(&path, FILE_MODE_READ | FILE_MODE_WRITE) }
+        .map_err(|error| error.status)?;
+    let result = unsafe { (*file).rename(&format!("{}+{}-{}.conf", id, left - 1, done + 1)) };
+    unsafe {
+        (*file).flush();
+        (*file).close();
+    }
+
+    result
+}
+
+/// The files in the entries directory of `volume`
+#[allow(unsafe_code)]
+fn entry_files(volume: &Volume) -> Result<Vec<FileInfo>, EfiStatus> {
+    let directory = unsafe { (*volume.root).open_path(ENTRIES_PATH, FILE_MODE_READ) }
+        .map_err(|error| error.status)?;
+
+    let files = unsafe { (*directory).read_dir() }
+        .filter_map(Result::ok)
+        .filter(|info| !info.is_directory())
+        .collect();
+    unsafe { (*directory).close() };
+
+    Ok(files)
+}
+
+/// Parses every `.conf` file in the entries directory of `volume`
+#[allow(unsafe_code)]
+fn read_entries(volume: &Volume) -> Vec<BlsEntry> {
+    let files = entry_files(volume).unwrap_or_default();
+
+    files
+        .iter()
+        .filter_map(|info| {
+            let (id, counter) = split_file_name(&info.file_name)?;
+
+            let path = format!("{}/{}", ENTRIES_PATH, info.file_name);
+            let data = read_file(unsafe { &mut *volume.root }, &path, |_, _| {}).ok()?;
+
+            Some(BlsEntry {
+                counter,
+                ..BlsEntry::parse(id, &String::from_utf8_lossy(&data))
+            })
+        })
+        .collect()
+}
+
+/// Splits `id+left-done.conf` into the id and the boot counter, returns `None` if the file is
+/// no entry
+fn split_file_name(name: &str) -> Option<(&str, Option<(u32, u32)>)> {
+    //FAT keeps the case of names but ignores it
+    let split = name.len().checked_sub(".conf".len())?;
+    if !name.is_char_boundary(split) || !name[split..].eq_ignore_ascii_case(".conf") {
+        return None;
+    }
+    let stem = &name[..split];
+
+    let counter = stem.rsplit_once('+').and_then(|(id, counter)| {
+        let (left, done) = counter.split_once('-').unwrap_or((counter, "0"));
+        let left = left.parse().ok()?;
+        let done = done.parse().ok()?;
+        Some((id, (left, done)))
+    });
+
+    Some(match counter {
+        Some((id, counter)) => (id, Some(counter)),
+        None => (stem, None),
+    })
+}
+
+/// Compares versions like `6.8.10-300` the way people read them: runs of digits are compared as
+/// numbers, runs of letters alphabetically and everything else only separates the runs
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a = a.as_bytes();
+    let mut b = b.as_bytes();
+
+    loop {
+        a = skip_separators(a);
+        b = skip_separators(b);
+
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            //A trailing tag marks a pre-release, `6.8-rc1` is older than `6.8`
+            (None, Some(y)) if y.is_ascii_alphabetic() => return Ordering::Greater,
+            (Some(x), None) if x.is_ascii_alphabetic() => return Ordering::Less,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => {
+                //A number is newer than a tag like `rc`
+                let ordering = match (x.is_ascii_digit(), y.is_ascii_digit()) {
+                    (true, false) => Ordering::Greater,
+                    (false, true) => Ordering::Less,
+                    (true, true) => {
+                        let (x, rest_a) = split_run(a, u8::is_ascii_digit);
+                        let (y, rest_b) = split_run(b, u8::is_ascii_digit);
+                        a = rest_a;
+                        b = rest_b;
+
+                        let x = trim_zeros(x);
+                        let y = trim_zeros(y);
+                        x.len().cmp(&y.len()).then(x.cmp(y))
+                    }
+                    (false, false) => {
+                        let (x, rest_a) = split_run(a, u8::is_ascii_alphabetic);
+                        let (y, rest_b) = split_run(b, u8::is_ascii_alphabetic);
+                        a = rest_a;
+                        b = rest_b;
+
+                        x.cmp(y)
+                    }
+                };
+
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+        }
+    }
+}
+
+fn skip_separators(s: &[u8]) -> &[u8] {
+    let start = s
+        .iter()
+        .position(u8::is_ascii_alphanumeric)
+        .unwrap_or(s.len());
+
+    &s[start..]
+}
+
+fn split_run(s: &[u8], matches: fn(&u8) -> bool) -> (&[u8], &[u8]) {
+    let end = s.iter().position(|c| !matches(c)).unwrap_or(s.len());
+
+    s.split_at(end)
+}
+
+fn trim_zeros(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|c| *c != b'0').unwrap_or(s.len());
+
+    &s[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        assert_eq!(compare_versions("6.8.10", "6.8.9"), Ordering::Greater);
+        assert_eq!(compare_versions("6.8.05", "6.8.5"), Ordering::Equal);
+        assert_eq!(compare_versions("6.8.5-301", "6.8.5"), Ordering::Greater);
+        assert_eq!(
+            compare_versions("6.8.5-301.fc40", "6.8.5-301.fc39"),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn release_candidates_come_first() {
+        assert_eq!(compare_versions("6.8-rc1", "6.8"), Ordering::Less);
+        assert_eq!(compare_versions("6.8", "6.8-rc7"), Ordering::Greater);
+        assert_eq!(compare_versions("6.8-rc2", "6.8-rc10"), Ordering::Less);
+        assert_eq!(compare_versions("6.8-rc7", "6.8.1"), Ordering::Less);
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(split_file_name("fedora.conf"), Some(("fedora", None)));
+        assert_eq!(split_file_name("fedora.CONF"), Some(("fedora", None)));
+        assert_eq!(
+            split_file_name("fedora+3.conf"),
+            Some(("fedora", Some((3, 0))))
+        );
+        assert_eq!(
+            split_file_name("fedora+2-1.conf"),
+            Some(("fedora", Some((2, 1))))
+        );
+        assert_eq!(
+            split_file_name("fedora+0-3.conf"),
+            Some(("fedora", Some((0, 3))))
+        );
+        assert_eq!(split_file_name("c++.conf"), Some(("c++", None)));
+        assert_eq!(split_file_name("fedora.txt"), None);
+        assert_eq!(split_file_name("conf"), None);
+    }
+
+    #[test]
+    fn parse() {
+        let entry = BlsEntry::parse(
+            "fedora",
+            "# comment\n\
+             title      Fedora Linux\n\
+             version    6.8.5-301.fc40.x86_64\n\
+             architecture x64\n\
+             linux      /vmlinuz\n\
+             initrd     /microcode.img\n\
+             initrd     /initramfs.img\n\
+             options    root=UUID=1234 ro\n\
+             options    quiet\n\
+             devicetree\n\
+             unknown    value\n",
+        );
+
+        assert_eq!(entry.id, "fedora");
+        assert_eq!(entry.title.as_deref(), Some("Fedora Linux"));
+        assert_eq!(entry.version.as_deref(), Some("6.8.5-301.fc40.x86_64"));
+        assert_eq!(entry.linux.as_deref(), Some("/vmlinuz"));
+        assert_eq!(entry.efi, None);
+        assert_eq!(entry.initrd, ["/microcode.img", "/initramfs.img"]);
+        assert_eq!(entry.options, ["root=UUID=1234 ro", "quiet"]);
+        assert!(entry.is_bootable());
+    }
+
+    #[test]
+    fn other_architectures_are_not_bootable() {
+        let entry = BlsEntry::parse("arm", "architecture aa64\nlinux /Image\n");
+        assert!(!entry.is_bootable());
+
+        let entry = BlsEntry::parse("empty", "title Nothing to boot\n");
+        assert!(!entry.is_bootable());
+    }
+}
@@ -0,0 +1,164 @@
+//! Starts other EFI applications, like the Windows Boot Manager or the UEFI shell, through the
+//! firmware. Unlike kernels they may return, the loader then shows its menu again.
+
+use crate::efi::io::DevicePathFromText;
+use crate::efi::loaded_image::LoadedImage;
+use crate::efi::simple_fs::{to_uefi_path, FileError};
+use crate::efi::{
+    efi_status_name, EfiHandle, EfiStatus, OpenProtocolAttributes, SystemTable, EFI_NOT_FOUND,
+};
+use crate::menu::BootEntry;
+use crate::volume::Volume;
+use crate::{logger, read_file};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::fmt::{Display, Formatter, Write};
+
+#[derive(Debug)]
+pub enum ChainloadError {
+    File(FileError),
+    /// The firmware could not parse the device path of the entry
+    DevicePath,
+    /// The firmware refused to load the image, e.g. because Secure Boot rejected it
+    Load(EfiStatus),
+    Efi(EfiStatus),
+}
+
+impl From<EfiStatus> for ChainloadError {
+    fn from(status: EfiStatus) -> Self {
+        ChainloadError::Efi(status)
+    }
+}
+
+impl From<FileError> for ChainloadError {
+    fn from(error: FileError) -> Self {
+        ChainloadError::File(error)
+    }
+}
+
+impl Display for ChainloadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ChainloadError::File(error) => write!(f, "{}", error),
+            ChainloadError::DevicePath => write!(f, "invalid device path"),
+            ChainloadError::Load(status) => {
+                write!(
+                    f,
+                    "the firmware refused the image: {}",
+                    efi_status_name(*status)
+                )
+            }
+            ChainloadError::Efi(status) => {
+                write!(f, "firmware error: {}", efi_status_name(*status))
+            }
+        }
+    }
+}
+
+/// How a chainloaded application returned
+pub struct ImageExit {
+    pub status: EfiStatus,
+    /// The text the application left with its exit status
+    pub data: String,
+}
+
+impl Display for ImageExit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", efi_status_name(self.status))?;
+
+        if !self.data.is_empty() {
+            write!(f, " ({})", self.data)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `path` is the text form of a device path like `PciRoot(0x0)/Pci(0x1D,0x0)/...`
+/// rather than a file on a volume
+pub fn is_device_path(path: &str) -> bool {
+    path.split(['/', '\\'])
+        .find(|node| !node.is_empty())
+        .and_then(|node| node.split_once('('))
+        .is_some_and(|(name, rest)| {
+            !name.is_empty() && name.chars().all(char::is_alphanumeric) && rest.ends_with(')')
+        })
+}
+
+/// Loads the application of `entry`, either from `volume` or, if its path is a device path,
+/// by the firmware itself. Passes the entry's command line as its load options and runs it until
+/// it exits.
+#[allow(unsafe_code)]
+pub fn boot(
+    image_handle: EfiHandle,
+    st: &SystemTable,
+    volume: Option<&Volume>,
+    entry: &BootEntry,
+) -> Result<ImageExit, ChainloadError> {
+    let logger = logger();
+    let boot_services = st.boot_services();
+
+    let handle = if is_device_path(&entry.kernel) {
+        let from_text = boot_services.locate_protocol::<DevicePathFromText>()?;
+        let device_path = unsafe { &*from_text }
+            .convert(boot_services, &entry.kernel)
+            .ok_or(ChainloadError::DevicePath)?;
+
+        boot_services.load_image(image_handle, Some(device_path.as_device_path()), None)
+    } else {
+        let volume = volume.ok_or_else(|| FileError::new(&entry.kernel, EFI_NOT_FOUND))?;
+        let data = read_file(
+            unsafe { &mut *volume.root },
+            &entry.kernel,
+            |done, total| logger.progress(done, total),
+        )?;
+        writeln!(logger, "\r").unwrap();
+
+        //The application finds its own files through the device path it was loaded from
+        let device_path = volume
+            .device_path()?
+            .with_file_path(&to_uefi_path(&entry.kernel));
+
+        boot_services.load_image(
+            image_handle,
+            Some(device_path.as_device_path()),
+            Some(&data),
+        )
+    }
+    .map_err(ChainloadError::Load)?;
+
+    //Null terminated UCS-2, it has to stay alive until the application returns
+    let options: Vec<u16> = entry
+        .cmdline
+        .encode_utf16()
+        .chain(core::iter::once(0))
+        .collect();
+    if !entry.cmdline.is_empty() {
+        let loaded_image = boot_services.open_protocol::<LoadedImage>(
+            handle,
+            image_handle,
+            OpenProtocolAttributes::GET_PROTOCOL,
+        );
+
+        match loaded_image {
+            Ok(mut loaded_image) => {
+                loaded_image.load_options_size = (options.len() * 2) as u32;
+                loaded_image.load_options = options.as_ptr() as *const c_void;
+            }
+            Err(status) => {
+                boot_services.unload_image(handle);
+                return Err(ChainloadError::Efi(status));
+            }
+        }
+    }
+
+    writeln!(logger, "Starting {}\r", entry.title).unwrap();
+
+    let (status, exit_data) = boot_services.start_image(handle);
+    let data = char::decode_utf16(exit_data.iter().copied().take_while(|c| *c != 0))
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect();
+
+    Ok(ImageExit { status, data })
+}
@@ -0,0 +1,17 @@
+use core::mem::size_of_val;
+
+/// Operand of `lgdt`, describes a table of segment descriptors
+#[repr(C, packed)]
+pub struct GdtPointer {
+    limit: u16,
+    base: u64,
+}
+
+impl GdtPointer {
+    pub fn new(descriptors: &'static [u64]) -> GdtPointer {
+        GdtPointer {
+            limit: (size_of_val(descriptors) - 1) as u16,
+            base: descriptors.as_ptr() as u64,
+        }
+    }
+}
@@ -20,15 +20,7 @@ pub trait Logger {
     }
 }
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug)]
-pub struct FrameBufferInfo {
-    pub address: usize,
-    pub len: usize,
-    pub screen_width: u32,
-    pub screen_height: u32,
-    pub pixels_per_scan_line: u32,
-}
+pub use bootinfo::FrameBufferInfo;
 
 #[derive(Debug)]
 pub struct FrameBuffer {
@@ -58,6 +50,26 @@ impl FrameBuffer {
         self.slice[pos as usize] = color;
     }
 
+    pub fn clear(&mut self, color: u32) {
+        for y in 0..self.info.screen_height {
+            let line = (y * self.info.pixels_per_scan_line) as u64;
+            self.draw_offset(line, line + self.info.screen_width as u64, color);
+        }
+    }
+
+    /// Fills the rectangle at `x` and `y`, clipped to the screen
+    pub fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, color: u32) {
+        let x_end = x.saturating_add(width).min(self.info.screen_width);
+        let y_end = y.saturating_add(height).min(self.info.screen_height);
+
+        for y in y..y_end {
+            let line = (y * self.info.pixels_per_scan_line) as u64;
+            if x < x_end {
+                self.draw_offset(line + x as u64, line + x_end as u64, color);
+            }
+        }
+    }
+
     pub fn draw_offset(&mut self, offset_start: u64, offset_end: u64, color: u32) {
         for i in offset_start as usize..offset_end as usize {
             self.slice[i] = color
@@ -0,0 +1,127 @@
+use crate::efi::{EfiMemoryDescriptor, EfiMemoryType};
+use alloc::vec::Vec;
+pub use bootinfo::{MemoryRegion, MemoryRegionKind};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Maps a firmware memory type to the kind the kernel sees after boot services are gone
+pub fn kind_from_efi(memory_type: EfiMemoryType) -> MemoryRegionKind {
+    match memory_type {
+        EfiMemoryType::EFI_CONVENTIONAL_MEMORY
+        | EfiMemoryType::EFI_BOOT_SERVICES_CODE
+        | EfiMemoryType::EFI_BOOT_SERVICES_DATA => MemoryRegionKind::USABLE,
+        EfiMemoryType::EFI_LOADER_CODE
+        | EfiMemoryType::EFI_LOADER_DATA
+        | EfiMemoryType::KERNEL_STACK
+        | EfiMemoryType::PAGE_TABLES
+        | EfiMemoryType::BOOT_INFO
+        | EfiMemoryType::LOADER_POOL => MemoryRegionKind::BOOTLOADER_RECLAIMABLE,
+        EfiMemoryType::KERNEL | EfiMemoryType::MODULES => MemoryRegionKind::KERNEL_AND_MODULES,
+        EfiMemoryType::EFI_ACPIRECLAIM_MEMORY => MemoryRegionKind::ACPI_RECLAIMABLE,
+        EfiMemoryType::EFI_ACPIMEMORY_NVS => MemoryRegionKind::ACPI_NVS,
+        EfiMemoryType::EFI_MEMORY_MAPPED_IO | EfiMemoryType::EFI_MEMORY_MAPPED_IOPORT_SPACE => {
+            MemoryRegionKind::MMIO
+        }
+        _ => MemoryRegionKind::RESERVED,
+    }
+}
+
+/// If two regions overlap, the kind with the higher priority wins
+fn priority(kind: &MemoryRegionKind) -> u8 {
+    match *kind {
+        MemoryRegionKind::USABLE => 0,
+        MemoryRegionKind::BOOTLOADER_RECLAIMABLE => 1,
+        MemoryRegionKind::ACPI_RECLAIMABLE => 2,
+        MemoryRegionKind::KERNEL_AND_MODULES => 5,
+        MemoryRegionKind::FRAMEBUFFER => 6,
+        _ => 4,
+    }
+}
+
+fn contains(region: &MemoryRegion, start: u64, end: u64) -> bool {
+    region.start <= start && end <= region.end
+}
+
+impl From<&EfiMemoryDescriptor> for MemoryRegion {
+    fn from(value: &EfiMemoryDescriptor) -> Self {
+        MemoryRegion::new(
+            value.physical_start,
+            value.num_pages * PAGE_SIZE,
+            kind_from_efi(value.memory_type),
+        )
+    }
+}
+
+/// Builds the sorted, non-overlapping and merged memory map for the kernel.
+///
+/// All buffers are reserved up front, so [`MemoryMapBuilder::build`] does not allocate and can
+/// run after the boot services have been exited.
+#[derive(Default)]
+pub struct MemoryMapBuilder {
+    overlays: Vec<MemoryRegion>,
+    boundaries: Vec<u64>,
+    regions: Vec<MemoryRegion>,
+}
+
+impl MemoryMapBuilder {
+    pub fn new() -> MemoryMapBuilder {
+        MemoryMapBuilder {
+            overlays: Vec::new(),
+            boundaries: Vec::new(),
+            regions: Vec::new(),
+        }
+    }
+
+    /// Marks a range the loader knows more about than the firmware, e.g. the kernel or the framebuffer
+    pub fn overlay(&mut self, start: u64, len: u64, kind: MemoryRegionKind) {
+        if len > 0 {
+            self.overlays.push(MemoryRegion::new(start, len, kind));
+        }
+    }
+
+    /// Reserves enough space for a firmware map with `descriptors` entries
+    pub fn reserve(&mut self, descriptors: usize) {
+        let max = (descriptors + self.overlays.len()) * 2;
+
+        self.boundaries.reserve(max);
+        self.regions.reserve(max);
+    }
+
+    pub fn build(mut self, descriptors: &[EfiMemoryDescriptor]) -> Vec<MemoryRegion> {
+        for region in descriptors
+            .iter()
+            .map(MemoryRegion::from)
+            .chain(self.overlays.iter().copied())
+            .filter(|r| !r.is_empty())
+        {
+            self.boundaries.push(region.start);
+            self.boundaries.push(region.end);
+        }
+        self.boundaries.sort_unstable();
+        self.boundaries.dedup();
+
+        for window in self.boundaries.windows(2) {
+            let (start, end) = (window[0], window[1]);
+
+            let kind = descriptors
+                .iter()
+                .map(MemoryRegion::from)
+                .chain(self.overlays.iter().copied())
+                .filter(|r| contains(r, start, end))
+                .map(|r| r.kind)
+                .max_by_key(priority);
+
+            let kind = match kind {
+                Some(kind) => kind,
+                None => continue,
+            };
+
+            match self.regions.last_mut() {
+                Some(last) if last.end == start && last.kind == kind => last.end = end,
+                _ => self.regions.push(MemoryRegion { start, end, kind }),
+            }
+        }
+
+        self.regions
+    }
+}
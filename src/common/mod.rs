@@ -1,2 +1,5 @@
+pub mod bytes;
+pub mod gdt;
 pub mod log;
+pub mod memory;
 pub mod paging;
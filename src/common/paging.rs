@@ -1,3 +1,24 @@
+use crate::allocator;
+use crate::common::memory::PAGE_SIZE;
+use crate::efi::{EfiMemoryType, EfiStatus, EFI_INVALID_PARAMETER};
+
+/// One level of the page table hierarchy, the CPU requires it to be page aligned
+#[repr(C, align(4096))]
+#[derive(Clone)]
+pub struct PageTable(pub [u64; 512]);
+
+impl PageTable {
+    pub const fn new() -> PageTable {
+        PageTable([0; 512])
+    }
+}
+
+impl Default for PageTable {
+    fn default() -> Self {
+        PageTable::new()
+    }
+}
+
 #[repr(transparent)]
 #[derive(Debug, Clone)]
 pub struct PageMapTable(u64);
@@ -167,3 +188,116 @@ impl From<PageMapTableBuilder> for u64 {
         value.0
     }
 }
+
+pub const LARGE_PAGE_SIZE: u64 = 512 * PAGE_SIZE;
+
+/// Builds 4-level page tables for a kernel that runs in its own address space. The tables are
+/// allocated as `PAGE_TABLES`, so the boot services have to be available while mapping.
+pub struct PageTableMapper {
+    pml4: &'static mut PageTable,
+}
+
+impl PageTableMapper {
+    pub fn new() -> Result<PageTableMapper, EfiStatus> {
+        Ok(PageTableMapper {
+            pml4: Self::allocate_table()?,
+        })
+    }
+
+    #[allow(unsafe_code)]
+    fn allocate_table() -> Result<&'static mut PageTable, EfiStatus> {
+        let page = allocator().allocate_pages_below(
+            u64::MAX,
+            EfiMemoryType::PAGE_TABLES,
+            PAGE_SIZE as usize,
+        )?;
+
+        //The page is zeroed, aligned and large enough
+        Ok(unsafe { &mut *(page.as_mut_ptr() as *mut PageTable) })
+    }
+
+    /// Physical address of the top level table, the value for `cr3`
+    pub fn root(&self) -> u64 {
+        self.pml4 as *const PageTable as u64
+    }
+
+    /// Maps `len` bytes at `virtual_address` to `physical_address` as present and writable,
+    /// using 2 MiB pages where both addresses allow it. Addresses are rounded down to pages.
+    pub fn map(
+        &mut self,
+        virtual_address: u64,
+        physical_address: u64,
+        len: u64,
+    ) -> Result<(), EfiStatus> {
+        let offset = virtual_address % PAGE_SIZE;
+        let mut virtual_address = virtual_address - offset;
+        let mut physical_address = physical_address - physical_address % PAGE_SIZE;
+        let end = virtual_address + (len + offset).next_multiple_of(PAGE_SIZE);
+
+        while virtual_address < end {
+            let large = virtual_address.is_multiple_of(LARGE_PAGE_SIZE)
+                && physical_address.is_multiple_of(LARGE_PAGE_SIZE)
+                && end - virtual_address >= LARGE_PAGE_SIZE;
+
+            self.map_page(virtual_address, physical_address, large)?;
+
+            let size = if large { LARGE_PAGE_SIZE } else { PAGE_SIZE };
+            virtual_address += size;
+            physical_address += size;
+        }
+
+        Ok(())
+    }
+
+    fn map_page(
+        &mut self,
+        virtual_address: u64,
+        physical_address: u64,
+        large: bool,
+    ) -> Result<(), EfiStatus> {
+        let index = |level: u32| ((virtual_address >> (12 + 9 * level)) & 0x1FF) as usize;
+
+        let pdpt = Self::next_table(self.pml4, index(3))?;
+        let pd = Self::next_table(pdpt, index(2))?;
+        let entry = PageMapTableBuilder::from(0)
+            .address(physical_address >> 12)
+            .write_allowed(true)
+            .present(true);
+
+        if large {
+            pd.0[index(1)] = entry.page_size(true).into();
+        } else {
+            let pt = Self::next_table(pd, index(1))?;
+            pt.0[index(0)] = entry.into();
+        }
+
+        Ok(())
+    }
+
+    /// The table the entry at `index` points to, created if the entry is empty
+    #[allow(unsafe_code)]
+    fn next_table(
+        table: &mut PageTable,
+        index: usize,
+    ) -> Result<&'static mut PageTable, EfiStatus> {
+        let entry = PageMapTable::from(table.0[index]);
+        //A 2 MiB page already covers the range, it can not be split
+        if entry.present() && entry.page_size() {
+            return Err(EFI_INVALID_PARAMETER);
+        }
+        if entry.present() {
+            //Tables are identity mapped while the loader runs
+            let address = entry.address(12) << 12;
+            return Ok(unsafe { &mut *(address as *mut PageTable) });
+        }
+
+        let next = Self::allocate_table()?;
+        table.0[index] = PageMapTableBuilder::from(0)
+            .address(next as *const PageTable as u64 >> 12)
+            .write_allowed(true)
+            .present(true)
+            .into();
+
+        Ok(next)
+    }
+}
@@ -0,0 +1,121 @@
+use crate::menu::{BootEntry, BootEntryKind};
+use crate::volume::VolumeSelector;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// Loader configuration, read from `loader.conf` on the boot volume.
+///
+/// Every line holds a key and its value separated by whitespace, lines starting with `#` are
+/// comments:
+///
+/// ```text
+/// timeout 5
+/// default fedora-6.8.5
+/// watchdog 120
+/// volume label:DATA
+/// kernel /boot/kernel.elf
+/// cmdline console=fb verbose
+/// module /boot/initrd.tar
+/// module /boot/font.psf
+/// chainload Windows: /EFI/Microsoft/Boot/bootmgfw.efi
+/// chainload /EFI/tools/shell.efi -nostartup
+/// chainload Network: PciRoot(0x0)/Pci(0x3,0x0)/MAC(525400123456,0x1)/IPv4(0.0.0.0)
+/// ```
+pub struct Config {
+    /// Seconds the boot menu waits before booting the default entry
+    pub timeout: u64,
+    /// Id of the entry booted once the timeout expires, `nightos` for the kernel configured here,
+    /// the file name of a BLS entry with or without `.conf` or the title of a chainload entry. `@saved`
+    /// boots the entry chosen last. The `LoaderEntryOneShot` and `LoaderEntryDefault` variables
+    /// take precedence, the first good entry is used if none is set.
+    pub default: Option<String>,
+    /// Seconds the watchdog gives the boot after the menu before the firmware resets the
+    /// machine, 0 disables it
+    pub watchdog: u64,
+    /// Volume holding the kernel, all volumes are searched if it is not set
+    pub volume: Option<VolumeSelector>,
+    /// Path of the kernel on its volume
+    pub kernel: String,
+    /// Command line passed to kernels that take one
+    pub cmdline: String,
+    /// Files loaded next to the kernel, each with the path of the file followed by optional
+    /// arguments for the kernel. Linux gets them concatenated as its initrd.
+    pub modules: Vec<String>,
+    /// Other EFI applications offered in the boot menu, each written as an optional title
+    /// followed by `: `, the path of the application or its device path and its load options
+    pub chainload: Vec<BootEntry>,
+}
+
+impl Config {
+    pub const PATH: &'static str = "/loader.conf";
+
+    /// Parses the configuration, unknown keys and invalid values are ignored
+    pub fn parse(data: &str) -> Config {
+        let mut config = Config::default();
+
+        for line in data.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once(char::is_whitespace) {
+                Some((key, value)) => (key, value.trim()),
+                None => (line, ""),
+            };
+
+            match key {
+                "timeout" => config.timeout = value.parse().unwrap_or(config.timeout),
+                "default" if !value.is_empty() => config.default = Some(value.to_string()),
+                "watchdog" => config.watchdog = value.parse().unwrap_or(config.watchdog),
+                "volume" => config.volume = VolumeSelector::parse(value).or(config.volume),
+                "kernel" if !value.is_empty() => config.kernel = value.to_string(),
+                "cmdline" => config.cmdline = value.to_string(),
+                "module" if !value.is_empty() => config.modules.push(value.to_string()),
+                "chainload" if !value.is_empty() => config.chainload.push(chainload_entry(value)),
+                _ => {}
+            }
+        }
+
+        config
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            timeout: 3,
+            default: None,
+            watchdog: 5 * 60,
+            volume: None,
+            kernel: "/kernel".to_string(),
+            cmdline: String::new(),
+            modules: Vec::new(),
+            chainload: Vec::new(),
+        }
+    }
+}
+
+fn chainload_entry(value: &str) -> BootEntry {
+    let (title, value) = value.split_once(": ").unwrap_or(("", value));
+    let (path, options) = value
+        .trim()
+        .split_once(char::is_whitespace)
+        .unwrap_or((value.trim(), ""));
+
+    //Without a title the file name has to do
+    let title = match title.trim() {
+        "" => path.rsplit(['/', '\\']).next().unwrap_or(path),
+        title => title,
+    };
+
+    BootEntry {
+        id: title.to_string(),
+        title: title.to_string(),
+        kind: BootEntryKind::Chainload,
+        volume: None,
+        kernel: path.to_string(),
+        cmdline: options.trim().to_string(),
+        modules: Vec::new(),
+        tries_left: None,
+    }
+}
@@ -1,45 +1,385 @@
-use crate::efi::{EfiMemoryType, SystemTable};
+use crate::efi::{
+    EfiAllocateType, EfiMemoryType, EfiStatus, PhysicalAddress, SystemTable, ALLOCATE_ADDRESS,
+    ALLOCATE_ANY_PAGES, ALLOCATE_MAX_ADDRESS, EFI_NOT_READY,
+};
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
+use core::slice;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
 
-pub struct EfiAllocator(pub *const SystemTable);
+pub struct EfiAllocator {
+    /// Null once the boot services have been exited
+    system_table: AtomicPtr<SystemTable>,
+    memory_type: AtomicU32,
+    /// Serves all allocations after the boot services have been exited
+    pool: BumpAllocator,
+}
 
 impl EfiAllocator {
     pub const fn new(system_table: *const SystemTable) -> EfiAllocator {
-        EfiAllocator(system_table)
+        EfiAllocator {
+            system_table: AtomicPtr::new(system_table as *mut SystemTable),
+            memory_type: AtomicU32::new(EfiMemoryType::EFI_LOADER_DATA.0),
+            pool: BumpAllocator::new(),
+        }
+    }
+
+    #[allow(unsafe_code)]
+    fn system_table(&self) -> Option<&SystemTable> {
+        unsafe { self.system_table.load(Ordering::Relaxed).as_ref() }
+    }
+
+    /// Runs `f` with every allocation tagged as `memory_type` instead of `EFI_LOADER_DATA`, so the
+    /// kernel can tell the data apart in the memory map.
+    pub fn with_memory_type<R>(&self, memory_type: EfiMemoryType, f: impl FnOnce() -> R) -> R {
+        let previous = self.memory_type.swap(memory_type.0, Ordering::Relaxed);
+        let result = f();
+        self.memory_type.store(previous, Ordering::Relaxed);
+
+        result
+    }
+
+    /// Allocates zeroed pages covering `size` bytes at the fixed physical `address`
+    pub fn allocate_pages_at(
+        &self,
+        address: PhysicalAddress,
+        memory_type: EfiMemoryType,
+        size: usize,
+    ) -> Result<&'static mut [u8], EfiStatus> {
+        self.allocate_zeroed_pages(ALLOCATE_ADDRESS, address, memory_type, size)
+    }
+
+    /// Allocates zeroed pages covering `size` bytes that end at or below `max_address`, e.g. for
+    /// data a 32-bit kernel has to reach
+    pub fn allocate_pages_below(
+        &self,
+        max_address: PhysicalAddress,
+        memory_type: EfiMemoryType,
+        size: usize,
+    ) -> Result<&'static mut [u8], EfiStatus> {
+        self.allocate_zeroed_pages(ALLOCATE_MAX_ADDRESS, max_address, memory_type, size)
+    }
+
+    /// Gives back pages allocated by [`EfiAllocator::allocate_pages_at`] or
+    /// [`EfiAllocator::allocate_pages_below`], e.g. when a kernel could not be loaded after all
+    pub fn free_pages(&self, memory: &'static mut [u8]) {
+        if let Some(st) = self.system_table() {
+            st.boot_services()
+                .free_pages(memory.as_mut_ptr() as PhysicalAddress, pages(memory.len()));
+        }
+    }
+
+    #[allow(unsafe_code)]
+    fn allocate_zeroed_pages(
+        &self,
+        alloc_type: EfiAllocateType,
+        address: PhysicalAddress,
+        memory_type: EfiMemoryType,
+        size: usize,
+    ) -> Result<&'static mut [u8], EfiStatus> {
+        let st = self.system_table().ok_or(EFI_NOT_READY)?;
+
+        let mut memory = address;
+        let status =
+            st.boot_services()
+                .allocate_pages(alloc_type, memory_type, pages(size), &mut memory);
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        let slice = unsafe { slice::from_raw_parts_mut(memory as *mut u8, size) };
+        slice.fill(0);
+
+        Ok(slice)
+    }
+
+    /// Reserves the pool that serves allocations once the boot services are gone, it shows up as
+    /// `LOADER_POOL` in the memory map.
+    pub fn reserve_pool(&self, size: usize) -> Result<(), EfiStatus> {
+        let st = self.system_table().ok_or(EFI_NOT_READY)?;
+
+        let mut memory = 0;
+        let status = st.boot_services().allocate_pages(
+            ALLOCATE_ANY_PAGES,
+            EfiMemoryType::LOADER_POOL,
+            pages(size),
+            &mut memory,
+        );
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        self.pool
+            .init(memory as usize, pages(size) as usize * PAGE_SIZE);
+
+        Ok(())
+    }
+
+    /// Location and size of the pool reserved by [`EfiAllocator::reserve_pool`]
+    pub fn pool(&self) -> (usize, usize) {
+        self.pool.region()
+    }
+
+    /// Stops calling into the firmware, must be called right after `ExitBootServices` succeeded
+    pub fn exit_boot_services(&self) {
+        self.system_table.store(null_mut(), Ordering::Relaxed);
     }
 }
 
 #[allow(unsafe_code)]
 unsafe impl Sync for EfiAllocator {}
 
+/// `AllocatePool` only guarantees this alignment
+const POOL_ALIGN: usize = 8;
+const PAGE_SIZE: usize = 4096;
+/// Over-aligned allocations store the pointer returned by the firmware right in front of the
+/// pointer handed out, so `dealloc` can find it again
+const HEADER_SIZE: usize = core::mem::size_of::<usize>();
+
+/// Number of bytes that have to be requested from the firmware for `layout`
+fn backing_size(layout: Layout) -> Option<usize> {
+    if layout.align() <= POOL_ALIGN || layout.align() == PAGE_SIZE {
+        Some(layout.size())
+    } else {
+        layout.size().checked_add(layout.align())
+    }
+}
+
+fn pages(size: usize) -> u64 {
+    size.div_ceil(PAGE_SIZE) as u64
+}
+
+#[allow(unsafe_code)]
+impl EfiAllocator {
+    unsafe fn allocate_backing(&self, st: &SystemTable, layout: Layout, size: usize) -> *mut u8 {
+        let memory_type = EfiMemoryType(self.memory_type.load(Ordering::Relaxed));
+
+        if layout.align() >= PAGE_SIZE {
+            let mut memory = 0;
+            let status = st.boot_services().allocate_pages(
+                ALLOCATE_ANY_PAGES,
+                memory_type,
+                pages(size),
+                &mut memory,
+            );
+
+            if status != 0 {
+                return null_mut();
+            }
+
+            memory as *mut u8
+        } else {
+            let mut ptr = null_mut();
+            let status = st
+                .boot_services()
+                .allocate_pool(memory_type, size as u64, &mut ptr);
+
+            if status != 0 {
+                return null_mut();
+            }
+
+            ptr
+        }
+    }
+
+    unsafe fn free_backing(&self, st: &SystemTable, ptr: *mut u8, layout: Layout) {
+        if layout.align() >= PAGE_SIZE {
+            let size = backing_size(layout).unwrap_or(layout.size());
+            st.boot_services()
+                .free_pages(ptr as PhysicalAddress, pages(size));
+        } else {
+            st.boot_services().free_pool(ptr);
+        }
+    }
+}
+
 #[allow(unsafe_code)]
 unsafe impl GlobalAlloc for EfiAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let st = self.0.as_ref();
+        let st = match self.system_table() {
+            Some(st) => st,
+            None => return self.pool.alloc(layout),
+        };
 
-        if st.is_none() {
-            return null_mut();
+        let size = match backing_size(layout) {
+            Some(size) => size,
+            None => return null_mut(),
+        };
+
+        let ptr = self.allocate_backing(st, layout, size);
+        if ptr.is_null() || size == layout.size() {
+            return ptr;
         }
 
-        let mut ptr = null_mut();
-        st.unwrap().boot_services().allocate_pool(
-            EfiMemoryType::EFI_LOADER_DATA,
-            layout.size() as u64,
-            &mut ptr,
-        );
+        //Move forward to the requested alignment, leaving room for the header
+        let aligned = (ptr as usize + HEADER_SIZE + layout.align() - 1) & !(layout.align() - 1);
+        let aligned = aligned as *mut u8;
+        (aligned as *mut usize).sub(1).write(ptr as usize);
+
+        aligned
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc(layout);
+
+        //Firmware memory is not zeroed, but only the requested bytes have to be cleared
+        if !ptr.is_null() {
+            ptr.write_bytes(0, layout.size());
+        }
 
         ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let st = self.0.as_ref();
+        if self.pool.contains(ptr) {
+            return self.pool.dealloc(ptr, layout);
+        }
+
+        let st = self.system_table();
 
         if st.is_none() {
             //Ignore any dealloc attempts as we are now in kernel and the kernel is responsible for managing the memory.
             return;
         }
 
-        st.unwrap().boot_services().free_pool(ptr);
+        let ptr = if backing_size(layout) == Some(layout.size()) {
+            ptr
+        } else {
+            *(ptr as *const usize).sub(1) as *mut u8
+        };
+
+        self.free_backing(st.unwrap(), ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.pool.contains(ptr) {
+            return self.pool.realloc(ptr, layout, new_size);
+        }
+
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        //Page backed allocations can grow or shrink in place as long as they need as many pages
+        if layout.align() == PAGE_SIZE
+            && !self.pool.contains(ptr)
+            && pages(layout.size()) == pages(new_size)
+        {
+            return ptr;
+        }
+
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            new_ptr.copy_from_nonoverlapping(ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+
+        new_ptr
+    }
+}
+
+/// Hands out memory from a fixed region by moving a pointer forward. Only the most recent
+/// allocation can be given back, everything else lives until the kernel reclaims the region.
+pub struct BumpAllocator {
+    start: AtomicUsize,
+    next: AtomicUsize,
+    end: AtomicUsize,
+}
+
+impl BumpAllocator {
+    pub const fn new() -> BumpAllocator {
+        BumpAllocator {
+            start: AtomicUsize::new(0),
+            next: AtomicUsize::new(0),
+            end: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn init(&self, start: usize, len: usize) {
+        self.start.store(start, Ordering::Relaxed);
+        self.next.store(start, Ordering::Relaxed);
+        self.end.store(start + len, Ordering::Relaxed);
+    }
+
+    pub fn region(&self) -> (usize, usize) {
+        let start = self.start.load(Ordering::Relaxed);
+
+        (start, self.end.load(Ordering::Relaxed) - start)
+    }
+
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        let ptr = ptr as usize;
+
+        self.start.load(Ordering::Relaxed) <= ptr && ptr < self.end.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for BumpAllocator {
+    fn default() -> Self {
+        BumpAllocator::new()
+    }
+}
+
+#[allow(unsafe_code)]
+unsafe impl GlobalAlloc for BumpAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let end = self.end.load(Ordering::Relaxed);
+        let mut start = 0;
+
+        let result = self
+            .next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                start = next.checked_add(layout.align() - 1)? & !(layout.align() - 1);
+                let new_next = start.checked_add(layout.size())?;
+
+                if new_next <= end {
+                    Some(new_next)
+                } else {
+                    None
+                }
+            });
+
+        match result {
+            Ok(_) => start as *mut u8,
+            Err(_) => null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        //Roll back if this was the last allocation
+        let _ = self.next.compare_exchange(
+            ptr as usize + layout.size(),
+            ptr as usize,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        //The last allocation grows and shrinks in place, which makes growing a Vec cheap
+        let fits = (ptr as usize)
+            .checked_add(new_size)
+            .is_some_and(|new_end| new_end <= self.end.load(Ordering::Relaxed));
+        if fits
+            && self
+                .next
+                .compare_exchange(
+                    ptr as usize + layout.size(),
+                    ptr as usize + new_size,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+        {
+            return ptr;
+        }
+
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            new_ptr.copy_from_nonoverlapping(ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+
+        new_ptr
     }
 }
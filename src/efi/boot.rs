@@ -1,11 +1,12 @@
 use crate::efi::io::DevicePathProtocol;
 use crate::efi::{
-    Char16, EfiAllocateType, EfiGuid, EfiHandle, EfiMemoryType, EfiStatus, EfiTpl, PhysicalAddress,
-    TableHeader,
+    Char16, EfiAllocateType, EfiEvent, EfiGuid, EfiHandle, EfiMemoryType, EfiStatus, EfiTpl,
+    PhysicalAddress, Protocol, TableHeader, EFI_NOT_READY, EFI_SUCCESS, EFI_UNSUPPORTED,
 };
 use alloc::vec::Vec;
 use core::ffi::c_void;
-use core::fmt::{Display, Formatter, Pointer};
+use core::fmt::{Debug, Display, Formatter};
+use core::ops::{Deref, DerefMut};
 use core::ptr::{null, null_mut};
 use core::slice;
 
@@ -37,17 +38,35 @@ pub struct BootServices {
     ) -> EfiStatus,
     free_pool: unsafe extern "efiapi" fn(buffer: *mut u8) -> EfiStatus,
 
-    create_event: unsafe extern "efiapi" fn() -> EfiStatus,
-    set_timer: unsafe extern "efiapi" fn() -> EfiStatus,
-    wait_for_event: unsafe extern "efiapi" fn() -> EfiStatus,
-    signal_event: unsafe extern "efiapi" fn() -> EfiStatus,
-    close_event: unsafe extern "efiapi" fn() -> EfiStatus,
-    check_event: unsafe extern "efiapi" fn() -> EfiStatus,
+    create_event: unsafe extern "efiapi" fn(
+        event_type: EventType,
+        notify_tpl: EfiTpl,
+        notify_function: Option<unsafe extern "efiapi" fn(event: EfiEvent, context: *mut c_void)>,
+        notify_context: *mut c_void,
+        event: *mut EfiEvent,
+    ) -> EfiStatus,
+    set_timer: unsafe extern "efiapi" fn(
+        event: EfiEvent,
+        timer_type: TimerDelay,
+        trigger_time: u64,
+    ) -> EfiStatus,
+    wait_for_event: unsafe extern "efiapi" fn(
+        number_of_events: u64,
+        events: *const EfiEvent,
+        index: *mut u64,
+    ) -> EfiStatus,
+    signal_event: unsafe extern "efiapi" fn(event: EfiEvent) -> EfiStatus,
+    close_event: unsafe extern "efiapi" fn(event: EfiEvent) -> EfiStatus,
+    check_event: unsafe extern "efiapi" fn(event: EfiEvent) -> EfiStatus,
 
     install_protocol_interface: unsafe extern "efiapi" fn() -> EfiStatus,
     reinstall_protocol_interface: unsafe extern "efiapi" fn() -> EfiStatus,
     uninstall_protocol_interface: unsafe extern "efiapi" fn() -> EfiStatus,
-    handle_protocol: unsafe extern "efiapi" fn() -> EfiStatus,
+    handle_protocol: unsafe extern "efiapi" fn(
+        handle: EfiHandle,
+        protocol: *const EfiGuid,
+        interface: *mut *mut c_void,
+    ) -> EfiStatus,
     reserved: *const c_void,
     register_protocol_notify: unsafe extern "efiapi" fn() -> EfiStatus,
     locate_handle: unsafe extern "efiapi" fn(
@@ -74,11 +93,11 @@ pub struct BootServices {
         exit_data: *mut *mut Char16,
     ) -> EfiStatus,
     exit: unsafe extern "efiapi" fn() -> EfiStatus,
-    unload_image: unsafe extern "efiapi" fn() -> EfiStatus,
+    unload_image: unsafe extern "efiapi" fn(handle: EfiHandle) -> EfiStatus,
     exit_boot_services: unsafe extern "efiapi" fn(handle: EfiHandle, map_key: usize) -> EfiStatus,
 
     get_next_monotonic_count: unsafe extern "efiapi" fn() -> EfiStatus,
-    stall: unsafe extern "efiapi" fn() -> EfiStatus,
+    stall: unsafe extern "efiapi" fn(microseconds: u64) -> EfiStatus,
     set_watchdog_timer: unsafe extern "efiapi" fn(
         timeout: u64,
         watchdog_code: u64,
@@ -86,21 +105,35 @@ pub struct BootServices {
         watchdog_data: *mut Char16,
     ) -> EfiStatus,
 
-    connect_controller: unsafe extern "efiapi" fn() -> EfiStatus,
+    connect_controller: unsafe extern "efiapi" fn(
+        controller: EfiHandle,
+        driver_image: *const EfiHandle,
+        remaining_device_path: *const DevicePathProtocol,
+        recursive: bool,
+    ) -> EfiStatus,
     disconnect_controller: unsafe extern "efiapi" fn() -> EfiStatus,
 
     open_protocol: unsafe extern "efiapi" fn(
         efi_handle: EfiHandle,
-        guid: EfiGuid,
+        guid: *const EfiGuid,
         interface: *mut *mut c_void,
         agent: EfiHandle,
         controller: EfiHandle,
-        attributes: u32,
+        attributes: OpenProtocolAttributes,
+    ) -> EfiStatus,
+    close_protocol: unsafe extern "efiapi" fn(
+        efi_handle: EfiHandle,
+        guid: *const EfiGuid,
+        agent: EfiHandle,
+        controller: EfiHandle,
     ) -> EfiStatus,
-    CloseProtocol: unsafe extern "efiapi" fn() -> EfiStatus,
     OpenProtocolInformation: unsafe extern "efiapi" fn() -> EfiStatus,
 
-    ProtocolsPerHandle: unsafe extern "efiapi" fn() -> EfiStatus,
+    protocols_per_handle: unsafe extern "efiapi" fn(
+        handle: EfiHandle,
+        protocol_buffer: *mut *mut *const EfiGuid,
+        protocol_buffer_count: *mut u64,
+    ) -> EfiStatus,
     locate_handle_buffer: unsafe extern "efiapi" fn(
         search_type: SearchType,
         protocol: *const EfiGuid,
@@ -108,7 +141,11 @@ pub struct BootServices {
         buffer_size: *mut u64,
         buffer: *mut *mut EfiHandle,
     ) -> EfiStatus,
-    LocateProtocol: unsafe extern "efiapi" fn() -> EfiStatus,
+    locate_protocol: unsafe extern "efiapi" fn(
+        protocol: *const EfiGuid,
+        registration: *const c_void,
+        interface: *mut *mut c_void,
+    ) -> EfiStatus,
     InstallMultipleProtocolInterface: unsafe extern "efiapi" fn() -> EfiStatus,
     UninstallMultipleProtocolInterface: unsafe extern "efiapi" fn() -> EfiStatus,
 
@@ -131,6 +168,10 @@ impl BootServices {
         unsafe { (self.allocate_pages)(alloc_type, memory_type, pages, memory) }
     }
 
+    pub fn free_pages(&self, memory: PhysicalAddress, pages: u64) -> EfiStatus {
+        unsafe { (self.free_pages)(memory, pages) }
+    }
+
     pub fn allocate_pool(
         &self,
         pool_type: EfiMemoryType,
@@ -144,6 +185,115 @@ impl BootServices {
         unsafe { (self.free_pool)(buffer) }
     }
 
+    pub fn create_event(&self, event_type: EventType) -> Result<Event<'_>, EfiStatus> {
+        let mut event = null_mut();
+
+        let status = unsafe {
+            (self.create_event)(event_type, TPL_APPLICATION, None, null_mut(), &mut event)
+        };
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        Ok(Event {
+            event,
+            boot_services: self,
+        })
+    }
+
+    /// Creates a timer event, arm it with [`Event::set_timer`]
+    pub fn create_timer(&self) -> Result<Event<'_>, EfiStatus> {
+        self.create_event(EventType::TIMER)
+    }
+
+    /// Blocks until one of `events` is signaled and returns its index
+    pub fn wait_for_event(&self, events: &[EfiEvent]) -> Result<usize, EfiStatus> {
+        let mut index = 0;
+
+        let status =
+            unsafe { (self.wait_for_event)(events.len() as u64, events.as_ptr(), &mut index) };
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        Ok(index as usize)
+    }
+
+    /// Returns whether `event` is signaled without blocking
+    pub fn check_event(&self, event: EfiEvent) -> Result<bool, EfiStatus> {
+        match unsafe { (self.check_event)(event) } {
+            EFI_SUCCESS => Ok(true),
+            EFI_NOT_READY => Ok(false),
+            status => Err(status),
+        }
+    }
+
+    /// Arms the firmware watchdog, the machine is reset unless it is re-armed or disabled within
+    /// `timeout` seconds. A timeout of 0 disables the watchdog.
+    pub fn set_watchdog_timer(&self, timeout: u64) -> EfiStatus {
+        unsafe { (self.set_watchdog_timer)(timeout, WATCHDOG_CODE, 0, null_mut()) }
+    }
+
+    /// Busy waits for at least `microseconds`
+    pub fn stall(&self, microseconds: u64) -> EfiStatus {
+        unsafe { (self.stall)(microseconds) }
+    }
+
+    /// Loads the EFI image in `source` and returns its handle. `device_path` tells the image
+    /// where it was loaded from, without `source` the firmware reads the image from there.
+    pub fn load_image(
+        &self,
+        parent: EfiHandle,
+        device_path: Option<&DevicePathProtocol>,
+        source: Option<&[u8]>,
+    ) -> Result<EfiHandle, EfiStatus> {
+        let mut handle = null_mut();
+
+        let status = unsafe {
+            (self.load_image)(
+                false,
+                parent,
+                device_path.map_or(null(), |d| d as *const DevicePathProtocol),
+                source.map_or(null(), |s| s.as_ptr() as *const c_void),
+                source.map_or(0, |s| s.len() as u64),
+                &mut handle,
+            )
+        };
+
+        if status != 0 {
+            //An image failing the security checks is loaded nonetheless and has to be unloaded
+            if !handle.is_null() {
+                self.unload_image(handle);
+            }
+
+            return Err(status);
+        }
+
+        Ok(handle)
+    }
+
+    /// Runs a loaded image until it exits, returns its exit status and the exit data it left
+    pub fn start_image(&self, handle: EfiHandle) -> (EfiStatus, PoolBuffer<'_, Char16>) {
+        let mut len = 0;
+        let mut buffer = null_mut();
+
+        let status = unsafe { (self.start_image)(handle, &mut len, &mut buffer) };
+
+        let exit_data = PoolBuffer {
+            buffer,
+            len: len as usize / core::mem::size_of::<Char16>(),
+            boot_services: self,
+        };
+
+        (status, exit_data)
+    }
+
+    pub fn unload_image(&self, handle: EfiHandle) -> EfiStatus {
+        unsafe { (self.unload_image)(handle) }
+    }
+
     #[inline(always)]
     pub fn get_memory_map(
         &self,
@@ -158,53 +308,139 @@ impl BootServices {
         }
     }
 
-    pub fn locate_handle_for_protocol(
+    pub fn locate_handle_buffer(
         &self,
-        protocol: *const EfiGuid,
-    ) -> Result<EfiHandle, EfiStatus> {
+        search_type: SearchType,
+        protocol: Option<&EfiGuid>,
+    ) -> Result<PoolBuffer<'_, EfiHandle>, EfiStatus> {
         let mut buffer = null_mut();
         let mut len = 0;
 
-        unsafe {
-            let status = (self.locate_handle_buffer)(
-                SearchType::BY_PROTOCOL,
-                protocol,
+        let status = unsafe {
+            (self.locate_handle_buffer)(
+                search_type,
+                protocol.map_or(null(), |p| p as *const EfiGuid),
                 null(),
                 &mut len,
                 &mut buffer,
-            );
+            )
+        };
 
-            if status != 0 {
-                return Err(status);
-            }
+        if status != 0 {
+            return Err(status);
+        }
+
+        Ok(PoolBuffer {
+            buffer,
+            len: len as usize,
+            boot_services: self,
+        })
+    }
+
+    /// Returns every handle that supports the protocol `P`
+    pub fn locate_handles_for_protocol<P: Protocol>(
+        &self,
+    ) -> Result<PoolBuffer<'_, EfiHandle>, EfiStatus> {
+        self.locate_handle_buffer(SearchType::BY_PROTOCOL, Some(&P::GUID))
+    }
+
+    /// Returns the first interface of the protocol `P` found by the firmware. Nothing keeps it
+    /// from going away or being used elsewhere, so it is handed out as a raw pointer.
+    pub fn locate_protocol<P: Protocol>(&self) -> Result<*mut P, EfiStatus> {
+        let mut ptr = null_mut();
+
+        let status = unsafe { (self.locate_protocol)(&P::GUID, null(), &mut ptr) };
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        if ptr.is_null() {
+            return Err(EFI_UNSUPPORTED);
+        }
+
+        Ok(ptr as *mut P)
+    }
+
+    /// Returns the interface of the protocol `P` on `handle` without registering the loader as
+    /// its user, prefer [`BootServices::open_protocol`]
+    pub fn handle_protocol<P: Protocol>(&self, handle: EfiHandle) -> Result<*mut P, EfiStatus> {
+        let mut ptr = null_mut();
+
+        let status = unsafe { (self.handle_protocol)(handle, &P::GUID, &mut ptr) };
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        if ptr.is_null() {
+            return Err(EFI_UNSUPPORTED);
+        }
+
+        Ok(ptr as *mut P)
+    }
+
+    /// Connects all drivers that can manage `controller`, e.g. the firmware console again after the
+    /// loader closed its exclusive open of the GOP
+    pub fn connect_controller(&self, controller: EfiHandle) -> Result<(), EfiStatus> {
+        let status = unsafe { (self.connect_controller)(controller, null(), null(), true) };
+
+        if status != 0 {
+            return Err(status);
         }
-        let slice = unsafe { slice::from_raw_parts(buffer, len as usize) };
-        let first: EfiHandle = slice[0].clone();
 
-        //Dealloc buffer
-        unsafe { Vec::from_raw_parts(buffer, len as usize, len as usize) };
+        Ok(())
+    }
+
+    /// Returns the GUIDs of all protocols installed on `handle`. The GUIDs belong to the
+    /// firmware, so they are copied.
+    pub fn protocols_per_handle(&self, handle: EfiHandle) -> Result<Vec<EfiGuid>, EfiStatus> {
+        let mut buffer = null_mut();
+        let mut len = 0;
 
-        Ok(first)
+        let status = unsafe { (self.protocols_per_handle)(handle, &mut buffer, &mut len) };
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        let guids = PoolBuffer {
+            buffer,
+            len: len as usize,
+            boot_services: self,
+        };
+
+        Ok(guids.iter().map(|guid| unsafe { **guid }).collect())
     }
 
-    pub fn open_protocol<P>(
+    /// Opens the protocol `P` on `handle` on behalf of the image `agent`, it is closed again once
+    /// the returned [`ScopedProtocol`] is dropped
+    pub fn open_protocol<P: Protocol>(
         &self,
         handle: EfiHandle,
-        guid: EfiGuid,
         agent: EfiHandle,
-    ) -> Result<*mut P, EfiStatus> {
+        attributes: OpenProtocolAttributes,
+    ) -> Result<ScopedProtocol<'_, P>, EfiStatus> {
         let mut ptr = null_mut();
 
         let status = unsafe {
-            (self.open_protocol)(handle, guid, &mut ptr, agent, null_mut(), 0x20u32)
-            //Attribute = Exclusive
+            (self.open_protocol)(handle, &P::GUID, &mut ptr, agent, null_mut(), attributes)
         };
 
         if status != 0 {
             return Err(status);
         }
 
-        Ok(ptr as *mut P)
+        if ptr.is_null() {
+            return Err(EFI_UNSUPPORTED);
+        }
+
+        Ok(ScopedProtocol {
+            interface: ptr as *mut P,
+            handle,
+            agent,
+            boot_services: self,
+        })
     }
 
     #[inline(always)]
@@ -283,6 +519,24 @@ impl Display for EfiMemoryDescriptor {
                 EfiMemoryType::EFI_MAX_MEMORY_TYPE => {
                     "EfiMaxMemoryType"
                 }
+                EfiMemoryType::KERNEL => {
+                    "Kernel"
+                }
+                EfiMemoryType::KERNEL_STACK => {
+                    "KernelStack"
+                }
+                EfiMemoryType::PAGE_TABLES => {
+                    "PageTables"
+                }
+                EfiMemoryType::BOOT_INFO => {
+                    "BootInfo"
+                }
+                EfiMemoryType::MODULES => {
+                    "Modules"
+                }
+                EfiMemoryType::LOADER_POOL => {
+                    "LoaderPool"
+                }
                 _ => {
                     "Undefined"
                 }
@@ -303,6 +557,170 @@ impl Default for EfiMemoryDescriptor {
     }
 }
 
+/// An array allocated by the firmware, it is given back with `FreePool` once dropped
+pub struct PoolBuffer<'a, T> {
+    buffer: *mut T,
+    len: usize,
+    boot_services: &'a BootServices,
+}
+
+#[allow(unsafe_code)]
+impl<'a, T> Deref for PoolBuffer<'a, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        if self.buffer.is_null() {
+            return &[];
+        }
+
+        unsafe { slice::from_raw_parts(self.buffer, self.len) }
+    }
+}
+
+impl<'a, T: Debug> Debug for PoolBuffer<'a, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, T> Drop for PoolBuffer<'a, T> {
+    fn drop(&mut self) {
+        if !self.buffer.is_null() {
+            self.boot_services.free_pool(self.buffer as *mut u8);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct OpenProtocolAttributes(pub u32);
+
+impl OpenProtocolAttributes {
+    pub const BY_HANDLE_PROTOCOL: OpenProtocolAttributes = OpenProtocolAttributes(0x01);
+    pub const GET_PROTOCOL: OpenProtocolAttributes = OpenProtocolAttributes(0x02);
+    pub const TEST_PROTOCOL: OpenProtocolAttributes = OpenProtocolAttributes(0x04);
+    pub const BY_CHILD_CONTROLLER: OpenProtocolAttributes = OpenProtocolAttributes(0x08);
+    pub const BY_DRIVER: OpenProtocolAttributes = OpenProtocolAttributes(0x10);
+    pub const EXCLUSIVE: OpenProtocolAttributes = OpenProtocolAttributes(0x20);
+}
+
+/// A protocol opened through [`BootServices::open_protocol`], it is closed once dropped
+pub struct ScopedProtocol<'a, P: Protocol> {
+    interface: *mut P,
+    handle: EfiHandle,
+    agent: EfiHandle,
+    boot_services: &'a BootServices,
+}
+
+impl<'a, P: Protocol> ScopedProtocol<'a, P> {
+    pub fn handle(&self) -> EfiHandle {
+        self.handle
+    }
+}
+
+impl<'a, P: Protocol> Debug for ScopedProtocol<'a, P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ScopedProtocol")
+            .field("interface", &self.interface)
+            .field("handle", &self.handle)
+            .finish()
+    }
+}
+
+#[allow(unsafe_code)]
+impl<'a, P: Protocol> Deref for ScopedProtocol<'a, P> {
+    type Target = P;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.interface }
+    }
+}
+
+#[allow(unsafe_code)]
+impl<'a, P: Protocol> DerefMut for ScopedProtocol<'a, P> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.interface }
+    }
+}
+
+#[allow(unsafe_code)]
+impl<'a, P: Protocol> Drop for ScopedProtocol<'a, P> {
+    fn drop(&mut self) {
+        unsafe {
+            (self.boot_services.close_protocol)(self.handle, &P::GUID, self.agent, null_mut())
+        };
+    }
+}
+
+/// An event created by the loader, it is closed once dropped
+pub struct Event<'a> {
+    event: EfiEvent,
+    boot_services: &'a BootServices,
+}
+
+#[allow(unsafe_code)]
+impl<'a> Event<'a> {
+    pub fn raw(&self) -> EfiEvent {
+        self.event
+    }
+
+    /// Arms the timer of this event, `trigger_time` is given in 100ns units
+    pub fn set_timer(&self, delay: TimerDelay, trigger_time: u64) -> Result<(), EfiStatus> {
+        let status = unsafe { (self.boot_services.set_timer)(self.event, delay, trigger_time) };
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        Ok(())
+    }
+
+    pub fn signal(&self) -> EfiStatus {
+        unsafe { (self.boot_services.signal_event)(self.event) }
+    }
+
+    pub fn is_signaled(&self) -> Result<bool, EfiStatus> {
+        self.boot_services.check_event(self.event)
+    }
+}
+
+#[allow(unsafe_code)]
+impl<'a> Drop for Event<'a> {
+    fn drop(&mut self) {
+        unsafe { (self.boot_services.close_event)(self.event) };
+    }
+}
+
+/// Codes below 0x10000 are reserved for the firmware
+const WATCHDOG_CODE: u64 = 0x10000;
+
+pub const TPL_APPLICATION: EfiTpl = 4;
+
+/// Timer intervals are given in units of 100ns
+pub const TIMER_TICKS_PER_SECOND: u64 = 10_000_000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct EventType(pub u32);
+
+impl EventType {
+    pub const NONE: EventType = EventType(0);
+    pub const TIMER: EventType = EventType(0x80000000);
+    pub const RUNTIME: EventType = EventType(0x40000000);
+    pub const NOTIFY_WAIT: EventType = EventType(0x00000100);
+    pub const NOTIFY_SIGNAL: EventType = EventType(0x00000200);
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct TimerDelay(pub u32);
+
+impl TimerDelay {
+    pub const CANCEL: TimerDelay = TimerDelay(0);
+    pub const PERIODIC: TimerDelay = TimerDelay(1);
+    pub const RELATIVE: TimerDelay = TimerDelay(2);
+}
+
 #[repr(transparent)]
 pub struct SearchType(pub u32);
 
@@ -33,6 +33,11 @@ impl Logger for EfiLogger {
         match char {
             NEW_LINE => {
                 self.pos_y += 16;
+
+                //Without scrolling the only way on is to start over at the top
+                if self.pos_y + 16 > self.buffer.info.screen_height {
+                    self.clear();
+                }
             }
             CARRIAGE_RETURN => {
                 self.pos_x = 0;
@@ -41,7 +46,7 @@ impl Logger for EfiLogger {
                 let pos_char = self.pos_x / 8;
 
                 if pos_char >= self.chars_per_line {
-                    self.pos_y += 16;
+                    self.log_char(NEW_LINE);
                     self.pos_x = 0;
                     return;
                 }
@@ -72,6 +77,36 @@ impl Logger for EfiLogger {
     }
 }
 
+impl EfiLogger {
+    /// Clears the screen and starts again at the top left corner
+    pub fn clear(&mut self) {
+        self.buffer.clear(0);
+        self.pos_x = 0;
+        self.pos_y = 0;
+    }
+
+    /// Erases the character before the cursor on the current line
+    pub fn erase_char(&mut self) {
+        self.pos_x = self.pos_x.saturating_sub(8);
+        self.buffer.fill_rect(self.pos_x, self.pos_y, 8, 16, 0);
+    }
+
+    /// Draws a bar filled to `done / total` on the current line, the next line starts below it
+    pub fn progress(&mut self, done: usize, total: usize) {
+        let width = self.buffer.info.screen_width / 2;
+        let filled = if total == 0 {
+            width
+        } else {
+            (width as u64 * done.min(total) as u64 / total as u64) as u32
+        };
+
+        self.buffer
+            .fill_rect(0, self.pos_y + 2, filled, 12, 0xFFFFFF);
+        self.buffer
+            .fill_rect(filled, self.pos_y + 2, width - filled, 12, 0x404040);
+    }
+}
+
 impl Write for EfiLogger {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         Ok(self.log(s))
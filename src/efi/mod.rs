@@ -2,9 +2,12 @@ pub mod alloc;
 mod boot;
 pub mod logger;
 mod protocol;
-mod runtime;
+pub mod runtime;
 mod system;
 
+use ::alloc::string::String;
+use core::fmt::{Display, Formatter};
+
 pub use boot::*;
 pub use protocol::*;
 pub use system::*;
@@ -27,6 +30,25 @@ pub type EfiHandle = *mut core::ffi::c_void;
 pub type EfiEvent = *mut core::ffi::c_void;
 
 pub type EfiStatus = u64;
+
+const ERROR_BIT: EfiStatus = 1 << 63;
+pub const EFI_SUCCESS: EfiStatus = 0;
+pub const EFI_LOAD_ERROR: EfiStatus = ERROR_BIT | 1;
+pub const EFI_INVALID_PARAMETER: EfiStatus = ERROR_BIT | 2;
+pub const EFI_UNSUPPORTED: EfiStatus = ERROR_BIT | 3;
+pub const EFI_BAD_BUFFER_SIZE: EfiStatus = ERROR_BIT | 4;
+pub const EFI_BUFFER_TOO_SMALL: EfiStatus = ERROR_BIT | 5;
+pub const EFI_NOT_READY: EfiStatus = ERROR_BIT | 6;
+pub const EFI_DEVICE_ERROR: EfiStatus = ERROR_BIT | 7;
+pub const EFI_WRITE_PROTECTED: EfiStatus = ERROR_BIT | 8;
+pub const EFI_OUT_OF_RESOURCES: EfiStatus = ERROR_BIT | 9;
+pub const EFI_VOLUME_CORRUPTED: EfiStatus = ERROR_BIT | 10;
+pub const EFI_NOT_FOUND: EfiStatus = ERROR_BIT | 14;
+pub const EFI_ACCESS_DENIED: EfiStatus = ERROR_BIT | 15;
+pub const EFI_TIMEOUT: EfiStatus = ERROR_BIT | 18;
+pub const EFI_ABORTED: EfiStatus = ERROR_BIT | 21;
+pub const EFI_SECURITY_VIOLATION: EfiStatus = ERROR_BIT | 26;
+pub const EFI_END_OF_FILE: EfiStatus = ERROR_BIT | 31;
 pub type EfiTpl = u64;
 
 pub type EfiAllocateType = u32;
@@ -57,8 +79,17 @@ impl EfiMemoryType {
     pub const EFI_PERSISTENT_MEMORY: EfiMemoryType = EfiMemoryType(14);
     pub const EFI_UNNACCEPTED_MEMORY_TYPE: EfiMemoryType = EfiMemoryType(15);
     pub const EFI_MAX_MEMORY_TYPE: EfiMemoryType = EfiMemoryType(16);
+
+    //OEM range (0x80000000+), used to tag the data handed over to the kernel
+    pub const KERNEL: EfiMemoryType = EfiMemoryType(0x80000000);
+    pub const KERNEL_STACK: EfiMemoryType = EfiMemoryType(0x80000001);
+    pub const PAGE_TABLES: EfiMemoryType = EfiMemoryType(0x80000002);
+    pub const BOOT_INFO: EfiMemoryType = EfiMemoryType(0x80000003);
+    pub const MODULES: EfiMemoryType = EfiMemoryType(0x80000004);
+    pub const LOADER_POOL: EfiMemoryType = EfiMemoryType(0x80000005);
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 #[repr(C)]
 pub struct EfiGuid {
     data1: u32,
@@ -76,6 +107,172 @@ impl EfiGuid {
             data4,
         }
     }
+
+    /// Reads a GUID in its mixed-endian on-disk layout, as found in GPT headers and device paths
+    pub fn from_bytes(bytes: [u8; 16]) -> EfiGuid {
+        EfiGuid {
+            data1: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            data2: u16::from_le_bytes([bytes[4], bytes[5]]),
+            data3: u16::from_le_bytes([bytes[6], bytes[7]]),
+            data4: [
+                bytes[8], bytes[9], bytes[10], bytes[11], bytes[12], bytes[13], bytes[14],
+                bytes[15],
+            ],
+        }
+    }
+
+    /// Parses the textual form `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`, case is ignored
+    pub fn parse(s: &str) -> Option<EfiGuid> {
+        let mut parts = s.trim().split('-');
+        let mut next = |len: usize| {
+            let part = parts.next().filter(|p| p.len() == len)?;
+            u64::from_str_radix(part, 16).ok()
+        };
+
+        let data1 = next(8)? as u32;
+        let data2 = next(4)? as u16;
+        let data3 = next(4)? as u16;
+        let clock = next(4)? as u16;
+        let node = next(12)?;
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        let mut data4 = [0u8; 8];
+        data4[..2].copy_from_slice(&clock.to_be_bytes());
+        data4[2..].copy_from_slice(&node.to_be_bytes()[2..]);
+
+        Some(EfiGuid::new(data1, data2, data3, data4))
+    }
+}
+
+impl Display for EfiGuid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-",
+            self.data1, self.data2, self.data3, self.data4[0], self.data4[1]
+        )?;
+
+        for byte in &self.data4[2..] {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// `EFI_TIME`
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(C)]
+pub struct EfiTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pad1: u8,
+    pub nanosecond: u32,
+    /// Offset to UTC in minutes, [`EfiTime::UNSPECIFIED_TIMEZONE`] for local time
+    pub time_zone: i16,
+    pub daylight: u8,
+    pad2: u8,
+}
+
+impl EfiTime {
+    pub const SIZE: usize = 16;
+    pub const UNSPECIFIED_TIMEZONE: i16 = 0x07FF;
+
+    pub fn parse(data: &[u8]) -> Option<EfiTime> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+
+        Some(EfiTime {
+            year: u16::from_le_bytes([data[0], data[1]]),
+            month: data[2],
+            day: data[3],
+            hour: data[4],
+            minute: data[5],
+            second: data[6],
+            pad1: 0,
+            nanosecond: u32::from_le_bytes([data[8], data[9], data[10], data[11]]),
+            time_zone: i16::from_le_bytes([data[12], data[13]]),
+            daylight: data[14],
+            pad2: 0,
+        })
+    }
+
+    /// Seconds since 1970-01-01 00:00:00 UTC, a local time without time zone counts as UTC
+    pub fn unix_time(&self) -> i64 {
+        //Days from the civil calendar, with years starting in March so the leap day comes last
+        let (year, month) = match self.month {
+            1 | 2 => (self.year as i64 - 1, self.month as i64 + 9),
+            month => (self.year as i64, month as i64 - 3),
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        let seconds =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+
+        //Local time is UTC minus the time zone
+        match self.time_zone {
+            Self::UNSPECIFIED_TIMEZONE => seconds,
+            time_zone => seconds + time_zone as i64 * 60,
+        }
+    }
+}
+
+impl Display for EfiTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Decodes a null terminated UCS-2 string stored as little endian bytes
+pub fn string_from_char16(bytes: &[u8]) -> String {
+    let chars = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0);
+
+    char::decode_utf16(chars)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// A short description of `status` for error messages
+pub fn efi_status_name(status: EfiStatus) -> &'static str {
+    match status {
+        EFI_SUCCESS => "success",
+        EFI_LOAD_ERROR => "load error",
+        EFI_INVALID_PARAMETER => "invalid parameter",
+        EFI_UNSUPPORTED => "unsupported",
+        EFI_BAD_BUFFER_SIZE => "bad buffer size",
+        EFI_BUFFER_TOO_SMALL => "buffer too small",
+        EFI_NOT_READY => "not ready",
+        EFI_DEVICE_ERROR => "device error",
+        EFI_WRITE_PROTECTED => "write protected",
+        EFI_OUT_OF_RESOURCES => "out of resources",
+        EFI_VOLUME_CORRUPTED => "volume corrupted",
+        EFI_NOT_FOUND => "not found",
+        EFI_ACCESS_DENIED => "access denied",
+        EFI_TIMEOUT => "timeout",
+        EFI_ABORTED => "aborted",
+        EFI_SECURITY_VIOLATION => "security violation",
+        EFI_END_OF_FILE => "end of file",
+        _ => "unknown error",
+    }
 }
 
 pub fn format_efi_status(status: EfiStatus) -> u64 {
@@ -0,0 +1,115 @@
+use crate::efi::{EfiGuid, EfiStatus, Protocol};
+
+pub const BLOCK_IO_GUID: EfiGuid = EfiGuid::new(
+    0x964E5B21,
+    0x6459,
+    0x11D2,
+    [0x8E, 0x39, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B],
+);
+
+pub const DISK_IO_GUID: EfiGuid = EfiGuid::new(
+    0xCE345171,
+    0xBA0B,
+    0x11D2,
+    [0x8E, 0x4F, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B],
+);
+
+#[repr(C)]
+pub struct BlockIo {
+    pub revision: u64,
+    media: *const BlockIoMedia,
+
+    reset:
+        unsafe extern "efiapi" fn(this: *const BlockIo, extended_verification: bool) -> EfiStatus,
+    read_blocks: unsafe extern "efiapi" fn(
+        this: *const BlockIo,
+        media_id: u32,
+        lba: u64,
+        buffer_size: u64,
+        buffer: *mut u8,
+    ) -> EfiStatus,
+    write_blocks: unsafe extern "efiapi" fn(
+        this: *const BlockIo,
+        media_id: u32,
+        lba: u64,
+        buffer_size: u64,
+        buffer: *const u8,
+    ) -> EfiStatus,
+    flush_blocks: unsafe extern "efiapi" fn(this: *const BlockIo) -> EfiStatus,
+}
+
+impl Protocol for BlockIo {
+    const GUID: EfiGuid = BLOCK_IO_GUID;
+}
+
+#[allow(unsafe_code)]
+impl BlockIo {
+    pub fn media(&self) -> &BlockIoMedia {
+        unsafe { &*self.media }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct BlockIoMedia {
+    pub media_id: u32,
+    pub removable_media: bool,
+    pub media_present: bool,
+    pub logical_partition: bool,
+    pub read_only: bool,
+    pub write_caching: bool,
+    pub block_size: u32,
+    pub io_align: u32,
+    pub last_block: u64,
+}
+
+#[repr(C)]
+pub struct DiskIo {
+    pub revision: u64,
+
+    read_disk: unsafe extern "efiapi" fn(
+        this: *const DiskIo,
+        media_id: u32,
+        offset: u64,
+        buffer_size: u64,
+        buffer: *mut u8,
+    ) -> EfiStatus,
+    write_disk: unsafe extern "efiapi" fn(
+        this: *const DiskIo,
+        media_id: u32,
+        offset: u64,
+        buffer_size: u64,
+        buffer: *const u8,
+    ) -> EfiStatus,
+}
+
+impl Protocol for DiskIo {
+    const GUID: EfiGuid = DISK_IO_GUID;
+}
+
+#[allow(unsafe_code)]
+impl DiskIo {
+    /// Reads `buffer.len()` bytes starting at the byte `offset`, no alignment is required
+    pub fn read_disk(
+        &self,
+        media_id: u32,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<(), EfiStatus> {
+        let status = unsafe {
+            (self.read_disk)(
+                self,
+                media_id,
+                offset,
+                buffer.len() as u64,
+                buffer.as_mut_ptr(),
+            )
+        };
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        Ok(())
+    }
+}
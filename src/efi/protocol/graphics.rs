@@ -1,4 +1,6 @@
-use crate::efi::{EfiGuid, EfiStatus};
+use crate::efi::boot::BootServices;
+use crate::efi::{EfiGuid, EfiStatus, Protocol};
+use core::ptr::null_mut;
 use core::slice;
 
 pub const GRAPHICS_OUTPUT_GUID: EfiGuid = EfiGuid::new(
@@ -10,7 +12,12 @@ pub const GRAPHICS_OUTPUT_GUID: EfiGuid = EfiGuid::new(
 
 #[repr(C)]
 pub struct GraphicsOutput {
-    query_mode: unsafe extern "efiapi" fn() -> EfiStatus,
+    query_mode: unsafe extern "efiapi" fn(
+        graphics_output: *const GraphicsOutput,
+        mode_number: u32,
+        info_len: *mut usize,
+        info: *mut *mut GraphicsOutputModeInfo,
+    ) -> EfiStatus,
     set_mode: unsafe extern "efiapi" fn(
         graphics_output: *const GraphicsOutput,
         mode_number: u32,
@@ -20,12 +27,47 @@ pub struct GraphicsOutput {
     mode: *const GraphicsOutputMode,
 }
 
+impl Protocol for GraphicsOutput {
+    const GUID: EfiGuid = GRAPHICS_OUTPUT_GUID;
+}
+
 #[allow(unsafe_code)]
 impl GraphicsOutput {
     pub fn mode(&self) -> &GraphicsOutputMode {
         unsafe { &*self.mode }
     }
 
+    /// Describes the mode `mode`, the firmware's copy is freed again
+    pub fn query_mode(
+        &self,
+        boot_services: &BootServices,
+        mode: u32,
+    ) -> Result<GraphicsOutputModeInfo, EfiStatus> {
+        let mut info_len = 0;
+        let mut info = null_mut();
+
+        let status = unsafe { (self.query_mode)(self, mode, &mut info_len, &mut info) };
+        if status != 0 {
+            return Err(status);
+        }
+
+        let mode_info = unsafe { *info };
+        boot_services.free_pool(info as *mut u8);
+
+        Ok(mode_info)
+    }
+
+    /// The first mode with a framebuffer of `width` x `height` pixels, 0 matches any size
+    pub fn find_mode(&self, boot_services: &BootServices, width: u32, height: u32) -> Option<u32> {
+        (0..self.mode().max_mode).find(|mode| {
+            self.query_mode(boot_services, *mode).is_ok_and(|info| {
+                info.pixel_layout().is_some()
+                    && (width == 0 || info.horizontal_resolution == width)
+                    && (height == 0 || info.vertical_resolution == height)
+            })
+        })
+    }
+
     pub fn set_mode(&self, mode: u32) -> Result<(), EfiStatus> {
         unsafe {
             let status = (self.set_mode)(self, mode);
@@ -85,12 +127,9 @@ impl GraphicsOutputMode {
         framebuffer[pos as usize] = color;
     }
 
+    /// `info` only describes the current mode, `info_len` is its size in bytes
     pub fn current_mode(&self) -> &GraphicsOutputModeInfo {
-        &self.modes()[self.mode as usize]
-    }
-
-    pub fn modes(&self) -> &[GraphicsOutputModeInfo] {
-        unsafe { slice::from_raw_parts(self.info, self.info_len as usize) }
+        unsafe { &*self.info }
     }
 }
 
@@ -105,6 +144,23 @@ pub struct GraphicsOutputModeInfo {
     pub pixels_per_scan_line: u32,
 }
 
+impl GraphicsOutputModeInfo {
+    /// Where the colors are in a pixel, `None` if the mode has no framebuffer
+    pub fn pixel_layout(&self) -> Option<PixelLayout> {
+        match self.pixel_format {
+            PixelFormat::PIXEL_RED_GREEN_BLUE_RESERVED_8BIT_PER_COLOR => Some(PixelLayout::RGB),
+            PixelFormat::PIXEL_BLUE_GREEN_RED_RESERVED_8BIT_PER_COLOR => Some(PixelLayout::BGR),
+            PixelFormat::PIXEL_BIT_MASK => Some(PixelLayout {
+                red: ColorField::from_mask(self.pixel_info.red_mask),
+                green: ColorField::from_mask(self.pixel_info.green_mask),
+                blue: ColorField::from_mask(self.pixel_info.blue_mask),
+                reserved: ColorField::from_mask(self.pixel_info.reserved_mask),
+            }),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 #[repr(transparent)]
 pub struct PixelFormat(u32);
@@ -125,3 +181,50 @@ pub struct PixelInfo {
     blue_mask: u32,
     reserved_mask: u32,
 }
+
+/// Bits of one color in a pixel
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ColorField {
+    pub size: u8,
+    pub shift: u8,
+}
+
+impl ColorField {
+    pub const fn new(size: u8, shift: u8) -> ColorField {
+        ColorField { size, shift }
+    }
+
+    /// The field covered by the contiguous bits of `mask`
+    pub fn from_mask(mask: u32) -> ColorField {
+        if mask == 0 {
+            return ColorField::new(0, 0);
+        }
+
+        let shift = mask.trailing_zeros();
+        ColorField::new((mask >> shift).trailing_ones() as u8, shift as u8)
+    }
+}
+
+/// Layout of the 32-bit pixels of a framebuffer, handed on to kernels that draw themselves
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PixelLayout {
+    pub red: ColorField,
+    pub green: ColorField,
+    pub blue: ColorField,
+    pub reserved: ColorField,
+}
+
+impl PixelLayout {
+    pub const RGB: PixelLayout = PixelLayout {
+        red: ColorField::new(8, 0),
+        green: ColorField::new(8, 8),
+        blue: ColorField::new(8, 16),
+        reserved: ColorField::new(8, 24),
+    };
+    pub const BGR: PixelLayout = PixelLayout {
+        red: ColorField::new(8, 16),
+        green: ColorField::new(8, 8),
+        blue: ColorField::new(8, 0),
+        reserved: ColorField::new(8, 24),
+    };
+}
@@ -1,13 +1,53 @@
-use crate::efi::{Char16, EfiEvent, EfiStatus};
+use crate::efi::boot::BootServices;
+use crate::efi::{Char16, EfiEvent, EfiGuid, EfiStatus, Protocol, EFI_NOT_READY, EFI_SUCCESS};
 use alloc::string::String;
 use alloc::vec::Vec;
 use core::fmt;
 use core::fmt::Write;
 
+pub const SIMPLE_TEXT_INPUT_GUID: EfiGuid = EfiGuid::new(
+    0x387477C1,
+    0x69C7,
+    0x11D2,
+    [0x8E, 0x39, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B],
+);
+
+pub const SIMPLE_TEXT_OUTPUT_GUID: EfiGuid = EfiGuid::new(
+    0x387477C2,
+    0x69C7,
+    0x11D2,
+    [0x8E, 0x39, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B],
+);
+
+pub const DEVICE_PATH_GUID: EfiGuid = EfiGuid::new(
+    0x09576E91,
+    0x6D3F,
+    0x11D2,
+    [0x8E, 0x39, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B],
+);
+
+pub const DEVICE_PATH_FROM_TEXT_GUID: EfiGuid = EfiGuid::new(
+    0x05C99A21,
+    0xC70F,
+    0x4AD2,
+    [0x8A, 0x5F, 0x35, 0xDF, 0x33, 0x43, 0xF5, 0x1E],
+);
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
 #[repr(C)]
 pub struct EfiInputKey {
-    scan_code: u16,
-    unicode_char: Char16,
+    pub scan_code: u16,
+    pub unicode_char: Char16,
+}
+
+impl EfiInputKey {
+    pub const SCAN_NULL: u16 = 0x00;
+    pub const SCAN_UP: u16 = 0x01;
+    pub const SCAN_DOWN: u16 = 0x02;
+    pub const SCAN_ESC: u16 = 0x17;
+
+    pub const CHAR_BACKSPACE: Char16 = 0x08;
+    pub const CHAR_CARRIAGE_RETURN: Char16 = 0x0D;
 }
 
 #[repr(C)]
@@ -24,6 +64,33 @@ pub struct SimpleTextInputProtocol {
     wait_for_key: EfiEvent,
 }
 
+impl Protocol for SimpleTextInputProtocol {
+    const GUID: EfiGuid = SIMPLE_TEXT_INPUT_GUID;
+}
+
+#[allow(unsafe_code)]
+impl SimpleTextInputProtocol {
+    pub fn reset(&mut self) -> EfiStatus {
+        unsafe { (self.reset)(self, false) }
+    }
+
+    /// Returns the next key stroke, or `None` if no key is pending
+    pub fn read_key(&self) -> Result<Option<EfiInputKey>, EfiStatus> {
+        let mut key = EfiInputKey::default();
+
+        match unsafe { (self.read_key)(self, &mut key) } {
+            EFI_SUCCESS => Ok(Some(key)),
+            EFI_NOT_READY => Ok(None),
+            status => Err(status),
+        }
+    }
+
+    /// Signaled by the firmware when a key is pending, it must not be closed
+    pub fn wait_for_key_event(&self) -> EfiEvent {
+        self.wait_for_key
+    }
+}
+
 #[repr(C)]
 pub struct EfiSimpleTextOutputMode {
     max_mode: i32,
@@ -80,14 +147,19 @@ pub struct SimpleTextOutputProtocol {
     mode: *const EfiSimpleTextOutputMode,
 }
 
+impl Protocol for SimpleTextOutputProtocol {
+    const GUID: EfiGuid = SIMPLE_TEXT_OUTPUT_GUID;
+}
+
 #[allow(unsafe_code)]
-impl Write for SimpleTextOutputProtocol {
+//The firmware serializes output itself, so a shared reference is enough to write
+impl Write for &SimpleTextOutputProtocol {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         let string = String::from(s) + "\r\0";
         let bytes: Vec<u16> = string.encode_utf16().collect();
 
         unsafe {
-            (self.output_string)(self, bytes.as_ptr());
+            (self.output_string)(*self, bytes.as_ptr());
         }
 
         Ok(())
@@ -100,3 +172,166 @@ pub struct DevicePathProtocol {
     sub_type: u8,
     length: [u8; 2],
 }
+
+impl Protocol for DevicePathProtocol {
+    const GUID: EfiGuid = DEVICE_PATH_GUID;
+}
+
+#[allow(unsafe_code)]
+impl DevicePathProtocol {
+    pub const TYPE_MEDIA: u8 = 0x04;
+    pub const TYPE_END: u8 = 0x7F;
+
+    pub const SUB_TYPE_HARD_DRIVE: u8 = 0x01;
+    pub const SUB_TYPE_FILE_PATH: u8 = 0x04;
+    pub const SUB_TYPE_END_ENTIRE: u8 = 0xFF;
+
+    pub fn device_type(&self) -> u8 {
+        self.device_type
+    }
+
+    pub fn sub_type(&self) -> u8 {
+        self.sub_type
+    }
+
+    /// Length of this node including its header
+    pub fn len(&self) -> usize {
+        u16::from_le_bytes(self.length) as usize
+    }
+
+    pub fn is_end(&self) -> bool {
+        self.device_type == Self::TYPE_END && self.sub_type == Self::SUB_TYPE_END_ENTIRE
+    }
+
+    /// The node specific data following the header
+    pub fn data(&self) -> &[u8] {
+        let len = self
+            .len()
+            .saturating_sub(core::mem::size_of::<DevicePathProtocol>());
+
+        unsafe {
+            core::slice::from_raw_parts(
+                (self as *const DevicePathProtocol as *const u8)
+                    .add(core::mem::size_of::<DevicePathProtocol>()),
+                len,
+            )
+        }
+    }
+
+    /// Iterates over the nodes of this device path, excluding the end node
+    pub fn nodes(&self) -> DevicePathNodes<'_> {
+        DevicePathNodes { node: Some(self) }
+    }
+
+    /// The nodes of this device path without the end node
+    fn node_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for node in self.nodes() {
+            bytes.extend_from_slice(unsafe {
+                core::slice::from_raw_parts(
+                    node as *const DevicePathProtocol as *const u8,
+                    node.len(),
+                )
+            });
+        }
+
+        bytes
+    }
+
+    /// A copy of this device path owned by the loader
+    pub fn to_device_path_buf(&self) -> DevicePathBuf {
+        let mut bytes = self.node_bytes();
+        bytes.extend_from_slice(&[Self::TYPE_END, Self::SUB_TYPE_END_ENTIRE, 4, 0]);
+
+        DevicePathBuf(bytes)
+    }
+
+    /// This device path followed by a file path node for `path`, which is given in UEFI form
+    pub fn with_file_path(&self, path: &str) -> DevicePathBuf {
+        let mut bytes = self.node_bytes();
+
+        let name: Vec<u16> = path.encode_utf16().chain(core::iter::once(0)).collect();
+        let len = core::mem::size_of::<DevicePathProtocol>() + name.len() * 2;
+        bytes.extend_from_slice(&[Self::TYPE_MEDIA, Self::SUB_TYPE_FILE_PATH]);
+        bytes.extend_from_slice(&(len as u16).to_le_bytes());
+        for c in name {
+            bytes.extend_from_slice(&c.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&[Self::TYPE_END, Self::SUB_TYPE_END_ENTIRE, 4, 0]);
+
+        DevicePathBuf(bytes)
+    }
+}
+
+/// A device path owned by the loader
+pub struct DevicePathBuf(Vec<u8>);
+
+#[allow(unsafe_code)]
+impl DevicePathBuf {
+    pub fn as_device_path(&self) -> &DevicePathProtocol {
+        //Device path nodes are byte aligned
+        unsafe { &*(self.0.as_ptr() as *const DevicePathProtocol) }
+    }
+}
+
+/// `EFI_DEVICE_PATH_FROM_TEXT_PROTOCOL`, turns device paths like
+/// `PciRoot(0x0)/Pci(0x1F,0x2)/Sata(0x0,0xFFFF,0x0)/HD(1,GPT,...)/\EFI\BOOT\BOOTX64.EFI` into
+/// their binary form
+#[repr(C)]
+pub struct DevicePathFromText {
+    convert_text_to_device_node:
+        unsafe extern "efiapi" fn(text: *const Char16) -> *mut DevicePathProtocol,
+    convert_text_to_device_path:
+        unsafe extern "efiapi" fn(text: *const Char16) -> *mut DevicePathProtocol,
+}
+
+impl Protocol for DevicePathFromText {
+    const GUID: EfiGuid = DEVICE_PATH_FROM_TEXT_GUID;
+}
+
+#[allow(unsafe_code)]
+impl DevicePathFromText {
+    /// Parses the text form of a device path, `None` if the firmware does not understand it
+    pub fn convert(&self, boot_services: &BootServices, text: &str) -> Option<DevicePathBuf> {
+        let text: Vec<Char16> = text.encode_utf16().chain(core::iter::once(0)).collect();
+
+        let device_path = unsafe { (self.convert_text_to_device_path)(text.as_ptr()) };
+        if device_path.is_null() {
+            return None;
+        }
+
+        //The firmware allocated the device path from pool, the loader keeps a copy
+        let buffer = unsafe { (*device_path).to_device_path_buf() };
+        boot_services.free_pool(device_path as *mut u8);
+
+        Some(buffer)
+    }
+}
+
+pub struct DevicePathNodes<'a> {
+    node: Option<&'a DevicePathProtocol>,
+}
+
+#[allow(unsafe_code)]
+impl<'a> Iterator for DevicePathNodes<'a> {
+    type Item = &'a DevicePathProtocol;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.node.filter(|n| !n.is_end())?;
+
+        //A malformed node without length would make us loop forever
+        self.node = if node.len() < core::mem::size_of::<DevicePathProtocol>() {
+            None
+        } else {
+            unsafe {
+                Some(
+                    &*((node as *const DevicePathProtocol as *const u8).add(node.len())
+                        as *const DevicePathProtocol),
+                )
+            }
+        };
+
+        Some(node)
+    }
+}
@@ -1,5 +1,6 @@
 use crate::efi::io::DevicePathProtocol;
-use crate::efi::{EfiGuid, EfiHandle, SystemTable};
+use crate::efi::{EfiGuid, EfiHandle, Protocol, SystemTable};
+use core::ffi::c_void;
 
 pub const LOADED_IMAGE_GUID: EfiGuid = EfiGuid::new(
     0x5B1B31A1,
@@ -18,4 +19,14 @@ pub struct LoadedImage {
 
     pub device_handle: EfiHandle,
     pub file_path: *const DevicePathProtocol,
+    reserved: *const c_void,
+
+    /// Size of the load options in bytes
+    pub load_options_size: u32,
+    /// Options the image was started with, usually a UCS-2 command line
+    pub load_options: *const c_void,
+}
+
+impl Protocol for LoadedImage {
+    const GUID: EfiGuid = LOADED_IMAGE_GUID;
 }
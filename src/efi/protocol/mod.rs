@@ -1,4 +1,13 @@
+use crate::efi::EfiGuid;
+
+pub mod block_io;
 pub mod graphics;
 pub mod io;
 pub mod loaded_image;
+pub mod mp_services;
 pub mod simple_fs;
+
+/// A protocol interface that can be opened on a handle
+pub trait Protocol {
+    const GUID: EfiGuid;
+}
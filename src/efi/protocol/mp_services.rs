@@ -0,0 +1,127 @@
+use crate::efi::{EfiEvent, EfiGuid, EfiStatus, Event, Protocol};
+use core::ffi::c_void;
+use core::ptr::null_mut;
+
+pub const MP_SERVICES_GUID: EfiGuid = EfiGuid::new(
+    0x3FDDA605,
+    0xA76E,
+    0x4F46,
+    [0xAD, 0x29, 0x12, 0xF4, 0x53, 0x1B, 0x3D, 0x08],
+);
+
+/// Code run on an application processor, it gets the argument passed when it was started
+pub type ApProcedure = unsafe extern "efiapi" fn(argument: *mut c_void);
+
+/// Starts code on the application processors while the boot services are running
+#[repr(C)]
+pub struct MpServices {
+    get_number_of_processors: unsafe extern "efiapi" fn(
+        this: *const MpServices,
+        number_of_processors: *mut u64,
+        number_of_enabled_processors: *mut u64,
+    ) -> EfiStatus,
+    get_processor_info: unsafe extern "efiapi" fn(
+        this: *const MpServices,
+        processor_number: u64,
+        processor_info: *mut ProcessorInformation,
+    ) -> EfiStatus,
+    startup_all_aps: unsafe extern "efiapi" fn() -> EfiStatus,
+    startup_this_ap: unsafe extern "efiapi" fn(
+        this: *const MpServices,
+        procedure: ApProcedure,
+        processor_number: u64,
+        wait_event: EfiEvent,
+        timeout_in_microseconds: u64,
+        procedure_argument: *mut c_void,
+        finished: *mut bool,
+    ) -> EfiStatus,
+    switch_bsp: unsafe extern "efiapi" fn() -> EfiStatus,
+    enable_disable_ap: unsafe extern "efiapi" fn() -> EfiStatus,
+    who_am_i: unsafe extern "efiapi" fn() -> EfiStatus,
+}
+
+impl Protocol for MpServices {
+    const GUID: EfiGuid = MP_SERVICES_GUID;
+}
+
+#[allow(unsafe_code)]
+impl MpServices {
+    /// The number of processors and how many of them are enabled
+    pub fn number_of_processors(&self) -> Result<(usize, usize), EfiStatus> {
+        let mut processors = 0;
+        let mut enabled = 0;
+
+        let status =
+            unsafe { (self.get_number_of_processors)(self, &mut processors, &mut enabled) };
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        Ok((processors as usize, enabled as usize))
+    }
+
+    pub fn processor_info(&self, processor: usize) -> Result<ProcessorInformation, EfiStatus> {
+        let mut info = ProcessorInformation::default();
+
+        let status = unsafe { (self.get_processor_info)(self, processor as u64, &mut info) };
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        Ok(info)
+    }
+
+    /// Starts `procedure` on the application processor `processor` and returns right away.
+    /// `event` is signaled once the procedure returns, it has to stay open until then.
+    pub fn startup_this_ap(
+        &self,
+        procedure: ApProcedure,
+        processor: usize,
+        event: &Event,
+        argument: *mut c_void,
+    ) -> Result<(), EfiStatus> {
+        let status = unsafe {
+            (self.startup_this_ap)(
+                self,
+                procedure,
+                processor as u64,
+                event.raw(),
+                0,
+                argument,
+                null_mut(),
+            )
+        };
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct ProcessorInformation {
+    /// The APIC ID of the processor
+    pub processor_id: u64,
+    pub status_flag: u32,
+    pub package: u32,
+    pub core: u32,
+    pub thread: u32,
+}
+
+impl ProcessorInformation {
+    pub const PROCESSOR_AS_BSP: u32 = 0x1;
+    pub const PROCESSOR_ENABLED: u32 = 0x2;
+
+    pub fn is_bsp(&self) -> bool {
+        self.status_flag & Self::PROCESSOR_AS_BSP != 0
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.status_flag & Self::PROCESSOR_ENABLED != 0
+    }
+}
@@ -1,5 +1,12 @@
-use crate::efi::{Char16, EfiGuid, EfiStatus};
+use crate::efi::{
+    efi_status_name, format_efi_status, string_from_char16, Char16, EfiGuid, EfiStatus, EfiTime,
+    Protocol, EFI_BAD_BUFFER_SIZE, EFI_BUFFER_TOO_SMALL, EFI_DEVICE_ERROR, EFI_END_OF_FILE,
+    EFI_INVALID_PARAMETER,
+};
+use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
 use core::ptr::null_mut;
 pub const SIMPLE_FILE_SYSTEM_GUID: EfiGuid = EfiGuid::new(
     0x964E5B22,
@@ -15,6 +22,18 @@ pub const FILE_INFO_GUID: EfiGuid = EfiGuid::new(
     [0x8E, 0x39, 0x0, 0xA0, 0xC9, 0x69, 0x72, 0x3B],
 );
 
+pub const FILE_SYSTEM_INFO_GUID: EfiGuid = EfiGuid::new(
+    0x09576E93,
+    0x6D3F,
+    0x11D2,
+    [0x8E, 0x39, 0x0, 0xA0, 0xC9, 0x69, 0x72, 0x3B],
+);
+
+pub const FILE_MODE_READ: u64 = 0x1;
+pub const FILE_MODE_WRITE: u64 = 0x2;
+
+pub const FILE_DIRECTORY: u64 = 0x10;
+
 #[repr(C)]
 pub struct SimpleFileSystem {
     pub revision: u64,
@@ -23,6 +42,10 @@ pub struct SimpleFileSystem {
         unsafe extern "efiapi" fn(this: *mut SimpleFileSystem, *mut *mut EfiFile) -> EfiStatus,
 }
 
+impl Protocol for SimpleFileSystem {
+    const GUID: EfiGuid = SIMPLE_FILE_SYSTEM_GUID;
+}
+
 #[allow(unsafe_code)]
 impl SimpleFileSystem {
     pub fn open_volume(&mut self) -> Result<*mut EfiFile, EfiStatus> {
@@ -63,15 +86,20 @@ pub struct EfiFile {
         buffer_size: *mut u64,
         buffer: *const u8,
     ) -> EfiStatus,
-    get_position: unsafe extern "efiapi" fn(this: *const EfiFile) -> EfiStatus,
-    set_position: unsafe extern "efiapi" fn(this: *const EfiFile) -> EfiStatus,
+    get_position: unsafe extern "efiapi" fn(this: *const EfiFile, position: *mut u64) -> EfiStatus,
+    set_position: unsafe extern "efiapi" fn(this: *mut EfiFile, position: u64) -> EfiStatus,
     get_info: unsafe extern "efiapi" fn(
         this: *const EfiFile,
         *const EfiGuid,
         buffer_size: *mut usize,
         buffer: *mut u8,
     ) -> EfiStatus,
-    set_info: unsafe extern "efiapi" fn(this: *const EfiFile) -> EfiStatus,
+    set_info: unsafe extern "efiapi" fn(
+        this: *const EfiFile,
+        *const EfiGuid,
+        buffer_size: usize,
+        buffer: *const u8,
+    ) -> EfiStatus,
     flush: unsafe extern "efiapi" fn(this: *const EfiFile) -> EfiStatus,
     open_ex: unsafe extern "efiapi" fn(this: *const EfiFile) -> EfiStatus,
     read_ex: unsafe extern "efiapi" fn(this: *const EfiFile) -> EfiStatus,
@@ -81,6 +109,11 @@ pub struct EfiFile {
 
 #[allow(unsafe_code)]
 impl EfiFile {
+    pub const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+    pub const MIN_CHUNK_SIZE: usize = 64 * 1024;
+    pub const END_POSITION: u64 = u64::MAX;
+
+    /// Opens `file_name` relative to this file, the name is passed to the firmware as is
     pub fn open(
         &self,
         file_name: &str,
@@ -96,53 +129,154 @@ impl EfiFile {
         vec.push(0);
 
         unsafe {
-            let status = (self.open)(self, file, vec.as_mut_ptr(), open_mode, attributes);
+            let status = (self.open)(self, &mut file, vec.as_mut_ptr(), open_mode, attributes);
 
             if status == 0 {
-                Ok(*file)
+                Ok(file)
             } else {
                 Err(status)
             }
         }
     }
 
-    pub fn file_size(&self) -> u64 {
-        let mut data = Vec::with_capacity(500);
-        let mut buffer_size = data.capacity();
+    /// Opens `path` relative to this directory one component at a time, so a failure names the
+    /// component that is missing. Both `/` and `\` separate components, `.` and `..` are resolved
+    /// before anything is opened. Names are compared by the file system driver, which ignores
+    /// case on FAT.
+    pub fn open_path(&self, path: &str, open_mode: u64) -> Result<*mut EfiFile, FileError> {
+        let components = path_components(path);
+        let mut current: *mut EfiFile = null_mut();
+        let mut opened = String::new();
 
-        unsafe {
-            (self.get_info)(self, &FILE_INFO_GUID, &mut buffer_size, data.as_mut_ptr());
+        for (i, component) in components.iter().enumerate() {
+            opened.push('\\');
+            opened.push_str(component);
+
+            //Only the last component is opened with the requested mode, directories are just read
+            let mode = if i + 1 == components.len() {
+                open_mode
+            } else {
+                FILE_MODE_READ
+            };
+
+            let parent = if current.is_null() {
+                self
+            } else {
+                unsafe { &*current }
+            };
+            let result = parent.open(component, mode, 0);
+
+            if !current.is_null() {
+                unsafe { (*current).close() };
+            }
+
+            current = result.map_err(|status| FileError::new(&opened, status))?;
+        }
+
+        if current.is_null() {
+            //An empty path refers to this directory, open a new handle to it
+            return self
+                .open(".", open_mode, 0)
+                .map_err(|status| FileError::new("\\", status));
+        }
+
+        Ok(current)
+    }
+
+    /// Reads the information structure identified by `guid`, growing the buffer as the firmware
+    /// asks for it
+    pub fn info(&self, guid: &EfiGuid) -> Result<Vec<u8>, EfiStatus> {
+        let mut data = vec![0u8; 128];
+
+        loop {
+            let mut buffer_size = data.len();
+            let status =
+                unsafe { (self.get_info)(self, guid, &mut buffer_size, data.as_mut_ptr()) };
+
+            match status {
+                0 => {
+                    data.truncate(buffer_size);
+                    return Ok(data);
+                }
+                EFI_BUFFER_TOO_SMALL => data.resize(buffer_size, 0),
+                status => return Err(status),
+            }
         }
+    }
 
-        unsafe { *(data.as_ptr() as *const u64).offset(1) }
+    /// Writes the information structure identified by `guid`
+    pub fn set_info(&self, guid: &EfiGuid, data: &[u8]) -> EfiStatus {
+        unsafe { (self.set_info)(self, guid, data.len(), data.as_ptr()) }
+    }
+
+    /// Renames this file within its directory, the file has to be opened for writing
+    pub fn rename(&self, file_name: &str) -> Result<(), EfiStatus> {
+        //Everything but the name is written back unchanged
+        let mut data = self.info(&FILE_INFO_GUID)?;
+        if data.len() < FileInfo::NAME_OFFSET {
+            return Err(EFI_BUFFER_TOO_SMALL);
+        }
+        data.truncate(FileInfo::NAME_OFFSET);
+        for c in file_name.encode_utf16().chain(core::iter::once(0)) {
+            data.extend_from_slice(&c.to_le_bytes());
+        }
+        let size = data.len() as u64;
+        data[..8].copy_from_slice(&size.to_le_bytes());
+
+        match self.set_info(&FILE_INFO_GUID, &data) {
+            0 => Ok(()),
+            status => Err(status),
+        }
+    }
+
+    /// Information about the volume this file lives on
+    pub fn file_system_info(&self) -> Result<FileSystemInfo, EfiStatus> {
+        FileSystemInfo::parse(&self.info(&FILE_SYSTEM_INFO_GUID)?).ok_or(EFI_BUFFER_TOO_SMALL)
+    }
+
+    /// Information about this file, such as its size, timestamps and name
+    pub fn file_info(&self) -> Result<FileInfo, EfiStatus> {
+        FileInfo::parse(&self.info(&FILE_INFO_GUID)?).ok_or(EFI_BUFFER_TOO_SMALL)
+    }
+
+    pub fn file_size(&self) -> Result<u64, EfiStatus> {
+        self.file_info().map(|info| info.file_size)
+    }
+
+    /// Iterates over the entries of this directory, leaving out `.` and `..`
+    pub fn read_dir(&mut self) -> ReadDir<'_> {
+        ReadDir {
+            directory: self,
+            buffer: vec![0u8; 128],
+        }
     }
 
     pub fn read(&mut self, buffer_size: *mut u64, buffer: *mut u8) -> EfiStatus {
         unsafe { (self.read)(self, buffer_size, buffer) }
     }
 
-    pub fn read_chunked(&mut self, chunk_size: usize, buffer: &mut [u8]) -> EfiStatus {
-        let len = buffer.len() - 1;
-        let iter = len / chunk_size;
-        let mut buf_size = chunk_size as u64;
-        let mut remaining = len;
-        let mut buffer_ptr = buffer.as_mut_ptr();
+    /// The current byte offset in the file
+    pub fn position(&self) -> Result<u64, EfiStatus> {
+        let mut position = 0;
+        let status = unsafe { (self.get_position)(self, &mut position) };
 
-        for i in 0..iter {
-            if remaining < chunk_size {
-                buf_size = remaining as u64;
-            }
+        if status == 0 {
+            Ok(position)
+        } else {
+            Err(status)
+        }
+    }
 
-            let status = unsafe { self.read(&mut buf_size, buffer_ptr) };
-            unsafe { buffer_ptr = buffer_ptr.add(chunk_size) }
+    /// Moves to the byte offset `position`, [`EfiFile::END_POSITION`] moves to the end of the
+    /// file. Directories can only be rewound to 0.
+    pub fn set_position(&mut self, position: u64) -> Result<(), EfiStatus> {
+        let status = unsafe { (self.set_position)(self, position) };
 
-            if status != 0 {
-                remaining -= chunk_size;
-                return status;
-            }
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(status)
         }
-
-        0
     }
 
     pub fn write_all(&self, buffer_size: *mut u64, buffer: *const u8) -> EfiStatus {
@@ -157,3 +291,288 @@ impl EfiFile {
         unsafe { (self.close)(self) }
     }
 }
+
+/// Where [`Seek::seek`] measures the new position from
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+}
+
+/// A source of bytes, implemented by files so loaders can work on both files and buffers
+pub trait Read {
+    /// Reads up to `buffer.len()` bytes and returns how many were read, 0 at the end
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, EfiStatus>;
+
+    /// Fills `buffer` completely, failing with `EFI_END_OF_FILE` if the data runs out
+    fn read_exact(&mut self, mut buffer: &mut [u8]) -> Result<(), EfiStatus> {
+        while !buffer.is_empty() {
+            match self.read(buffer)? {
+                0 => return Err(EFI_END_OF_FILE),
+                read => buffer = &mut buffer[read..],
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads until `buffer` is full or the end of the data is reached and returns the number of
+    /// bytes read. `progress` is called with the bytes read so far and the buffer length after
+    /// every chunk.
+    ///
+    /// Reads start with [`EfiFile::MAX_CHUNK_SIZE`] bytes. Some firmware fails large reads, in
+    /// that case the chunk is halved down to [`EfiFile::MIN_CHUNK_SIZE`] before giving up.
+    fn read_chunked(
+        &mut self,
+        buffer: &mut [u8],
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<usize, EfiStatus> {
+        let mut chunk_size = EfiFile::MAX_CHUNK_SIZE;
+        let mut read = 0;
+
+        progress(0, buffer.len());
+
+        while read < buffer.len() {
+            let end = buffer.len().min(read + chunk_size);
+
+            match self.read(&mut buffer[read..end]) {
+                Ok(0) => break,
+                Ok(size) => read += size,
+                Err(EFI_DEVICE_ERROR | EFI_BAD_BUFFER_SIZE)
+                    if chunk_size > EfiFile::MIN_CHUNK_SIZE =>
+                {
+                    chunk_size /= 2;
+                    continue;
+                }
+                Err(status) => return Err(status),
+            }
+
+            progress(read, buffer.len());
+        }
+
+        Ok(read)
+    }
+}
+
+pub trait Seek {
+    /// Moves to the given position and returns it as an offset from the start
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, EfiStatus>;
+}
+
+/// Adds a signed `offset` to `base`, positions before the start are invalid
+fn offset_position(base: u64, offset: i64) -> Result<u64, EfiStatus> {
+    base.checked_add_signed(offset).ok_or(EFI_INVALID_PARAMETER)
+}
+
+impl<T: Read + ?Sized> Read for &mut T {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, EfiStatus> {
+        (**self).read(buffer)
+    }
+}
+
+impl<T: Seek + ?Sized> Seek for &mut T {
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, EfiStatus> {
+        (**self).seek(position)
+    }
+}
+
+impl Read for EfiFile {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, EfiStatus> {
+        let mut size = buffer.len() as u64;
+
+        match EfiFile::read(self, &mut size, buffer.as_mut_ptr()) {
+            0 => Ok(size as usize),
+            status => Err(status),
+        }
+    }
+}
+
+impl Seek for EfiFile {
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, EfiStatus> {
+        let position = match position {
+            SeekFrom::Start(position) => position,
+            SeekFrom::End(offset) => offset_position(self.file_size()?, offset)?,
+        };
+
+        self.set_position(position)?;
+
+        Ok(position)
+    }
+}
+
+/// `EFI_FILE_INFO`
+#[derive(Clone, Debug)]
+pub struct FileInfo {
+    pub file_size: u64,
+    /// Bytes the file occupies on the volume, including slack in its last cluster
+    pub physical_size: u64,
+    pub create_time: EfiTime,
+    pub last_access_time: EfiTime,
+    pub modification_time: EfiTime,
+    pub attribute: u64,
+    pub file_name: String,
+}
+
+impl FileInfo {
+    /// Offset of the file name, the structure has a variable length
+    const NAME_OFFSET: usize = 80;
+
+    pub fn parse(data: &[u8]) -> Option<FileInfo> {
+        if data.len() < Self::NAME_OFFSET {
+            return None;
+        }
+
+        let u64_at = |offset: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&data[offset..offset + 8]);
+            u64::from_le_bytes(bytes)
+        };
+
+        Some(FileInfo {
+            file_size: u64_at(8),
+            physical_size: u64_at(16),
+            create_time: EfiTime::parse(&data[24..])?,
+            last_access_time: EfiTime::parse(&data[40..])?,
+            modification_time: EfiTime::parse(&data[56..])?,
+            attribute: u64_at(72),
+            file_name: string_from_char16(&data[Self::NAME_OFFSET..]),
+        })
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attribute & FILE_DIRECTORY != 0
+    }
+}
+
+/// Iterator over the entries of a directory, created by [`EfiFile::read_dir`]
+pub struct ReadDir<'a> {
+    directory: &'a mut EfiFile,
+    buffer: Vec<u8>,
+}
+
+impl<'a> Iterator for ReadDir<'a> {
+    type Item = Result<FileInfo, EfiStatus>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            //Every read on a directory returns the next entry, an empty read marks the end
+            let mut size = self.buffer.len() as u64;
+            let status = self.directory.read(&mut size, self.buffer.as_mut_ptr());
+
+            match status {
+                0 if size == 0 => return None,
+                0 => {}
+                EFI_BUFFER_TOO_SMALL => {
+                    self.buffer.resize(size as usize, 0);
+                    continue;
+                }
+                status => return Some(Err(status)),
+            }
+
+            let info = match FileInfo::parse(&self.buffer[..size as usize]) {
+                Some(info) => info,
+                None => return Some(Err(EFI_BUFFER_TOO_SMALL)),
+            };
+
+            if info.file_name != "." && info.file_name != ".." {
+                return Some(Ok(info));
+            }
+        }
+    }
+}
+
+/// `EFI_FILE_SYSTEM_INFO`
+#[derive(Clone, Debug)]
+pub struct FileSystemInfo {
+    pub read_only: bool,
+    pub volume_size: u64,
+    pub free_space: u64,
+    pub block_size: u32,
+    pub volume_label: String,
+}
+
+impl FileSystemInfo {
+    /// Offset of the volume label, the structure has a variable length
+    const LABEL_OFFSET: usize = 36;
+
+    pub fn parse(data: &[u8]) -> Option<FileSystemInfo> {
+        if data.len() < Self::LABEL_OFFSET {
+            return None;
+        }
+
+        let u64_at = |offset: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&data[offset..offset + 8]);
+            u64::from_le_bytes(bytes)
+        };
+
+        Some(FileSystemInfo {
+            read_only: data[8] != 0,
+            volume_size: u64_at(16),
+            free_space: u64_at(24),
+            block_size: u32::from_le_bytes([data[32], data[33], data[34], data[35]]),
+            volume_label: string_from_char16(&data[Self::LABEL_OFFSET..]),
+        })
+    }
+}
+
+/// Splits `path` into its components, resolving `.` and `..` on the way
+pub fn path_components(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+
+    for component in path.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+
+    components
+}
+
+/// Converts `path` into an absolute UEFI path, e.g. `/boot/../kernel.elf` into `\kernel.elf`
+pub fn to_uefi_path(path: &str) -> String {
+    let mut uefi_path = String::new();
+
+    for component in path_components(path) {
+        uefi_path.push('\\');
+        uefi_path.push_str(component);
+    }
+
+    if uefi_path.is_empty() {
+        uefi_path.push('\\');
+    }
+
+    uefi_path
+}
+
+/// A file operation failed on `path`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileError {
+    pub path: String,
+    pub status: EfiStatus,
+}
+
+impl FileError {
+    pub fn new(path: &str, status: EfiStatus) -> FileError {
+        FileError {
+            path: String::from(path),
+            status,
+        }
+    }
+}
+
+impl Display for FileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}: {} ({})",
+            self.path,
+            efi_status_name(self.status),
+            format_efi_status(self.status)
+        )
+    }
+}
@@ -1,11 +1,18 @@
-use crate::efi::{Char16, EfiGuid, EfiStatus, TableHeader};
-use core::ffi::CStr;
+use crate::efi::{
+    string_from_char16, Char16, EfiGuid, EfiStatus, EfiTime, TableHeader, EFI_BUFFER_TOO_SMALL,
+    EFI_NOT_FOUND,
+};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::ptr::null_mut;
 
 #[repr(C)]
 pub struct RuntimeServices {
     header: TableHeader,
 
-    get_time: unsafe extern "efiapi" fn() -> EfiStatus,
+    get_time: unsafe extern "efiapi" fn(time: *mut EfiTime, capabilities: *mut c_void) -> EfiStatus,
     set_time: unsafe extern "efiapi" fn() -> EfiStatus,
     get_wakeup_time: unsafe extern "efiapi" fn() -> EfiStatus,
     set_wakeup_time: unsafe extern "efiapi" fn() -> EfiStatus,
@@ -13,15 +20,25 @@ pub struct RuntimeServices {
     set_virtual_address_map: unsafe extern "efiapi" fn() -> EfiStatus,
     convert_ptr: unsafe extern "efiapi" fn() -> EfiStatus,
 
-    pub get_var: unsafe extern "efiapi" fn() -> EfiStatus,
-    get_next_variable_name: unsafe extern "efiapi" fn() -> EfiStatus,
-    pub set_var: unsafe extern "efiapi" fn(
-        key: *mut Char16,
-        vendor_guid: *const EfiGuid,
-        attributes: u32,
-        size: u64,
+    get_variable: unsafe extern "efiapi" fn(
+        name: *const Char16,
+        vendor: *const EfiGuid,
+        attributes: *mut u32,
+        data_size: *mut usize,
         data: *mut u8,
     ) -> EfiStatus,
+    get_next_variable_name: unsafe extern "efiapi" fn(
+        name_size: *mut usize,
+        name: *mut Char16,
+        vendor: *mut EfiGuid,
+    ) -> EfiStatus,
+    set_variable: unsafe extern "efiapi" fn(
+        name: *const Char16,
+        vendor: *const EfiGuid,
+        attributes: u32,
+        data_size: usize,
+        data: *const u8,
+    ) -> EfiStatus,
 
     unused: unsafe extern "efiapi" fn() -> EfiStatus,
     reset_system: unsafe extern "efiapi" fn() -> EfiStatus,
@@ -32,6 +49,191 @@ pub struct RuntimeServices {
     query_variable_info: unsafe extern "efiapi" fn() -> EfiStatus,
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct VariableAttributes(pub u32);
+
+impl VariableAttributes {
+    pub const NON_VOLATILE: VariableAttributes = VariableAttributes(0x01);
+    pub const BOOTSERVICE_ACCESS: VariableAttributes = VariableAttributes(0x02);
+    pub const RUNTIME_ACCESS: VariableAttributes = VariableAttributes(0x04);
+    pub const HARDWARE_ERROR_RECORD: VariableAttributes = VariableAttributes(0x08);
+    pub const TIME_BASED_AUTHENTICATED_WRITE_ACCESS: VariableAttributes = VariableAttributes(0x20);
+    pub const APPEND_WRITE: VariableAttributes = VariableAttributes(0x40);
+
+    pub fn contains(self, other: VariableAttributes) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for VariableAttributes {
+    type Output = VariableAttributes;
+
+    fn bitor(self, rhs: VariableAttributes) -> VariableAttributes {
+        VariableAttributes(self.0 | rhs.0)
+    }
+}
+
+/// A variable as named by the firmware
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VariableName {
+    pub name: String,
+    pub vendor: EfiGuid,
+}
+
+/// Null terminated UCS-2 form of `name` for the firmware
+fn char16_name(name: &str) -> Vec<Char16> {
+    name.encode_utf16().chain(core::iter::once(0)).collect()
+}
+
+#[allow(unsafe_code)]
 impl RuntimeServices {
-    pub fn set_var(&self, key: &str, data: &mut u8) {}
+    /// The current time of the real time clock
+    pub fn get_time(&self) -> Result<EfiTime, EfiStatus> {
+        let mut time = EfiTime::default();
+        let status = unsafe { (self.get_time)(&mut time, null_mut()) };
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        Ok(time)
+    }
+
+    /// Reads the variable `name` of `vendor` with its attributes, growing the buffer as the
+    /// firmware asks for it
+    pub fn get_variable(
+        &self,
+        name: &str,
+        vendor: &EfiGuid,
+    ) -> Result<(Vec<u8>, VariableAttributes), EfiStatus> {
+        let name = char16_name(name);
+        let mut data = vec![0u8; 64];
+
+        loop {
+            let mut attributes = 0;
+            let mut size = data.len();
+            let status = unsafe {
+                (self.get_variable)(
+                    name.as_ptr(),
+                    vendor,
+                    &mut attributes,
+                    &mut size,
+                    data.as_mut_ptr(),
+                )
+            };
+
+            match status {
+                0 => {
+                    data.truncate(size);
+                    return Ok((data, VariableAttributes(attributes)));
+                }
+                EFI_BUFFER_TOO_SMALL => data.resize(size, 0),
+                status => return Err(status),
+            }
+        }
+    }
+
+    /// Creates or replaces the variable `name` of `vendor`
+    pub fn set_variable(
+        &self,
+        name: &str,
+        vendor: &EfiGuid,
+        attributes: VariableAttributes,
+        data: &[u8],
+    ) -> Result<(), EfiStatus> {
+        let name = char16_name(name);
+        let status = unsafe {
+            (self.set_variable)(
+                name.as_ptr(),
+                vendor,
+                attributes.0,
+                data.len(),
+                data.as_ptr(),
+            )
+        };
+
+        if status != 0 {
+            return Err(status);
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the variable `name` of `vendor`, a variable that does not exist is not an error
+    pub fn delete_variable(&self, name: &str, vendor: &EfiGuid) -> Result<(), EfiStatus> {
+        let name = char16_name(name);
+        let status = unsafe { (self.set_variable)(name.as_ptr(), vendor, 0, 0, null_mut()) };
+
+        match status {
+            0 | EFI_NOT_FOUND => Ok(()),
+            status => Err(status),
+        }
+    }
+
+    /// Iterates over the names of all variables visible to the loader
+    pub fn variable_names(&self) -> VariableNames<'_> {
+        VariableNames {
+            runtime_services: self,
+            name: vec![0; 64],
+            vendor: EfiGuid::new(0, 0, 0, [0; 8]),
+            done: false,
+        }
+    }
+}
+
+/// Iterator over the names of all variables, created by [`RuntimeServices::variable_names`]
+pub struct VariableNames<'a> {
+    runtime_services: &'a RuntimeServices,
+    /// The previous name, the firmware continues after it
+    name: Vec<Char16>,
+    vendor: EfiGuid,
+    done: bool,
+}
+
+#[allow(unsafe_code)]
+impl<'a> Iterator for VariableNames<'a> {
+    type Item = Result<VariableName, EfiStatus>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            let mut size = self.name.len() * core::mem::size_of::<Char16>();
+            let status = unsafe {
+                (self.runtime_services.get_next_variable_name)(
+                    &mut size,
+                    self.name.as_mut_ptr(),
+                    &mut self.vendor,
+                )
+            };
+
+            match status {
+                0 => break,
+                EFI_NOT_FOUND => {
+                    self.done = true;
+                    return None;
+                }
+                EFI_BUFFER_TOO_SMALL => {
+                    //The name stays in place, only the buffer grows
+                    let len = size.div_ceil(core::mem::size_of::<Char16>());
+                    self.name.resize(len, 0);
+                }
+                status => {
+                    //Without the next name there is no way to go on
+                    self.done = true;
+                    return Some(Err(status));
+                }
+            }
+        }
+
+        let bytes: Vec<u8> = self.name.iter().flat_map(|c| c.to_le_bytes()).collect();
+
+        Some(Ok(VariableName {
+            name: string_from_char16(&bytes),
+            vendor: self.vendor,
+        }))
+    }
 }
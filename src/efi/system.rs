@@ -1,6 +1,42 @@
-use crate::efi::io::SimpleTextOutputProtocol;
-use crate::efi::{BootServices, Char16, EfiHandle, TableHeader};
+use crate::efi::io::{SimpleTextInputProtocol, SimpleTextOutputProtocol};
+use crate::efi::runtime::RuntimeServices;
+use crate::efi::{BootServices, Char16, EfiGuid, EfiHandle, TableHeader};
 use core::ffi::c_void;
+use core::slice;
+
+pub const ACPI_TABLE_GUID: EfiGuid = EfiGuid::new(
+    0xEB9D2D30,
+    0x2D88,
+    0x11D3,
+    [0x9A, 0x16, 0x0, 0x90, 0x27, 0x3F, 0xC1, 0x4D],
+);
+
+pub const ACPI_20_TABLE_GUID: EfiGuid = EfiGuid::new(
+    0x8868E871,
+    0xE4F1,
+    0x11D3,
+    [0xBC, 0x22, 0x0, 0x80, 0xC7, 0x3C, 0x88, 0x81],
+);
+
+pub const SMBIOS_TABLE_GUID: EfiGuid = EfiGuid::new(
+    0xEB9D2D31,
+    0x2D88,
+    0x11D3,
+    [0x9A, 0x16, 0x0, 0x90, 0x27, 0x3F, 0xC1, 0x4D],
+);
+
+pub const SMBIOS3_TABLE_GUID: EfiGuid = EfiGuid::new(
+    0xF2FD1544,
+    0x9794,
+    0x4A2C,
+    [0x99, 0x2E, 0xE5, 0xBB, 0xCF, 0x20, 0xE3, 0x94],
+);
+
+#[repr(C)]
+pub struct EfiConfigurationTable {
+    pub vendor_guid: EfiGuid,
+    pub vendor_table: *const c_void,
+}
 
 #[repr(C)]
 pub struct SystemTable {
@@ -10,7 +46,7 @@ pub struct SystemTable {
     firmware_revision: u32,
 
     console_in_handle: EfiHandle,
-    console_in: *const u64,
+    console_in: *mut SimpleTextInputProtocol,
 
     console_out_handle: EfiHandle,
     console_out: *mut SimpleTextOutputProtocol,
@@ -18,11 +54,11 @@ pub struct SystemTable {
     std_err_handle: EfiHandle,
     stderr: *const u64,
 
-    runtime_services: *const c_void,
+    runtime_services: *const RuntimeServices,
     boot_services: *const BootServices,
 
     table_size: u64,
-    config_table: *const u64,
+    config_table: *const EfiConfigurationTable,
 }
 
 #[allow(unsafe_code)]
@@ -35,7 +71,34 @@ impl SystemTable {
         unsafe { &*self.boot_services }
     }
 
-    pub fn stdout(&self) -> &mut SimpleTextOutputProtocol {
-        unsafe { &mut *self.console_out }
+    pub fn runtime_services(&self) -> &RuntimeServices {
+        unsafe { &*self.runtime_services }
+    }
+
+    pub fn stdout(&self) -> &SimpleTextOutputProtocol {
+        unsafe { &*self.console_out }
+    }
+
+    pub fn stdin(&self) -> &SimpleTextInputProtocol {
+        unsafe { &*self.console_in }
+    }
+
+    /// Tables the firmware publishes for the operating system, e.g. ACPI and SMBIOS
+    pub fn configuration_tables(&self) -> &[EfiConfigurationTable] {
+        if self.config_table.is_null() {
+            return &[];
+        }
+
+        unsafe { slice::from_raw_parts(self.config_table, self.table_size as usize) }
+    }
+
+    /// Address of the first configuration table with one of the `guids`, in order of preference
+    pub fn configuration_table(&self, guids: &[EfiGuid]) -> Option<u64> {
+        guids.iter().find_map(|guid| {
+            self.configuration_tables()
+                .iter()
+                .find(|t| t.vendor_guid == *guid)
+                .map(|t| t.vendor_table as u64)
+        })
     }
 }
@@ -0,0 +1,707 @@
+use crate::common::bytes::{u16_at, u32_at, u64_at};
+use crate::efi::simple_fs::{Read, Seek, SeekFrom};
+use crate::efi::{efi_status_name, EfiStatus, EFI_END_OF_FILE};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use core::ops::Range;
+
+pub const ELF_CLASS_32: u8 = 1;
+pub const ELF_CLASS_64: u8 = 2;
+pub const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+pub const EM_386: u16 = 3;
+pub const EM_X86_64: u16 = 62;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+
+pub const SHT_PROGBITS: u32 = 1;
+
+pub const SHF_WRITE: u64 = 0x1;
+pub const SHF_ALLOC: u64 = 0x2;
+
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// Why a kernel can not be loaded. Program headers are named by their index in the program
+/// header table, relocations by their index in the relocation table.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ElfError {
+    /// Reading the file failed
+    Io(EfiStatus),
+    NotElf,
+    UnsupportedClass(u8),
+    UnsupportedEndianness(u8),
+    UnsupportedMachine(u16),
+    /// The machine is supported, but not in this class, e.g. 32-bit x86_64
+    ClassMismatch {
+        class: u8,
+        machine: u16,
+    },
+    UnsupportedType(u16),
+    InvalidProgramHeaderSize(u16),
+    /// The program or section header table reaches past the end of the file
+    HeaderTablePastEndOfFile {
+        end: u64,
+        file_len: u64,
+    },
+    NoLoadableSegments,
+    /// The file bytes of a segment reach past the end of the file
+    SegmentPastEndOfFile {
+        index: usize,
+        end: u64,
+        file_len: u64,
+    },
+    /// A segment has more bytes in the file than in memory
+    SegmentFileSizeTooLarge {
+        index: usize,
+    },
+    /// A segment wraps around the end of the address space
+    SegmentOutOfRange {
+        index: usize,
+    },
+    /// The alignment is not a power of two or the address and offset disagree on it
+    BadAlignment {
+        index: usize,
+        align: u64,
+    },
+    OverlappingSegments {
+        first: usize,
+        second: usize,
+    },
+    /// The entry point does not lie in an executable segment
+    EntryPointNotExecutable(u64),
+    /// The dynamic section or its relocation table lies outside the loaded segments
+    DynamicOutOfBounds {
+        index: usize,
+    },
+    RelocationOutOfBounds {
+        index: u64,
+        offset: u64,
+    },
+    UnsupportedRelocation {
+        index: u64,
+        kind: u32,
+    },
+}
+
+impl From<EfiStatus> for ElfError {
+    fn from(value: EfiStatus) -> Self {
+        ElfError::Io(value)
+    }
+}
+
+impl Display for ElfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ElfError::Io(status) => write!(f, "read failed: {}", efi_status_name(*status)),
+            ElfError::NotElf => write!(f, "not an ELF file, the magic number is missing"),
+            ElfError::UnsupportedClass(ELF_CLASS_32) => write!(f, "32-bit ELF is not supported"),
+            ElfError::UnsupportedClass(ELF_CLASS_64) => write!(f, "64-bit ELF is not supported"),
+            ElfError::UnsupportedClass(class) => write!(f, "ELF class {} is invalid", class),
+            ElfError::UnsupportedEndianness(data) => write!(
+                f,
+                "ELF data encoding {} is not supported, expected little endian",
+                data
+            ),
+            ElfError::UnsupportedMachine(EM_386) => write!(f, "machine i386 is not supported"),
+            ElfError::UnsupportedMachine(EM_X86_64) => write!(f, "machine x86_64 is not supported"),
+            ElfError::UnsupportedMachine(machine) => {
+                write!(f, "machine {} is not supported", machine)
+            }
+            ElfError::ClassMismatch { class, machine } => write!(
+                f,
+                "{}-bit ELF is not supported for machine {}",
+                if *class == ELF_CLASS_32 { 32 } else { 64 },
+                match *machine {
+                    EM_386 => "i386",
+                    _ => "x86_64",
+                }
+            ),
+            ElfError::UnsupportedType(elf_type) => write!(
+                f,
+                "ELF type {} is not supported, expected EXEC or DYN",
+                elf_type
+            ),
+            ElfError::InvalidProgramHeaderSize(size) => {
+                write!(
+                    f,
+                    "program header size {} does not match the ELF class",
+                    size
+                )
+            }
+            ElfError::HeaderTablePastEndOfFile { end, file_len } => write!(
+                f,
+                "header table ends at {:#X}, past the end of the file at {:#X}",
+                end, file_len
+            ),
+            ElfError::NoLoadableSegments => write!(f, "there are no PT_LOAD program headers"),
+            ElfError::SegmentPastEndOfFile {
+                index,
+                end,
+                file_len,
+            } => write!(
+                f,
+                "program header {}: segment ends at {:#X}, past the end of the file at {:#X}",
+                index, end, file_len
+            ),
+            ElfError::SegmentFileSizeTooLarge { index } => write!(
+                f,
+                "program header {}: file size is larger than memory size",
+                index
+            ),
+            ElfError::SegmentOutOfRange { index } => write!(
+                f,
+                "program header {}: segment exceeds the address space",
+                index
+            ),
+            ElfError::BadAlignment { index, align } => write!(
+                f,
+                "program header {}: address and offset are not aligned to {:#X}",
+                index, align
+            ),
+            ElfError::OverlappingSegments { first, second } => write!(
+                f,
+                "program headers {} and {}: segments overlap",
+                first, second
+            ),
+            ElfError::EntryPointNotExecutable(entry) => write!(
+                f,
+                "entry point {:#X} is not inside an executable segment",
+                entry
+            ),
+            ElfError::DynamicOutOfBounds { index } => write!(
+                f,
+                "program header {}: dynamic section lies outside the loaded segments",
+                index
+            ),
+            ElfError::RelocationOutOfBounds { index, offset } => write!(
+                f,
+                "relocation {}: target {:#X} lies outside the loaded segments",
+                index, offset
+            ),
+            ElfError::UnsupportedRelocation { index, kind } => {
+                write!(f, "relocation {}: type {} is not supported", index, kind)
+            }
+        }
+    }
+}
+
+/// A class and machine combination the caller is able to run
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ElfTarget {
+    pub class: u8,
+    pub machine: u16,
+}
+
+impl ElfTarget {
+    pub const X86_64: ElfTarget = ElfTarget {
+        class: ELF_CLASS_64,
+        machine: EM_X86_64,
+    };
+    pub const I386: ElfTarget = ElfTarget {
+        class: ELF_CLASS_32,
+        machine: EM_386,
+    };
+}
+
+/// The fields of the ELF file header the loader needs
+#[derive(Copy, Clone, Debug)]
+pub struct ElfHeader {
+    pub class: u8,
+    pub data: u8,
+    pub elf_type: u16,
+    pub machine: u16,
+    pub entry: u64,
+    pub program_header_offset: u64,
+    pub program_header_size: u16,
+    pub program_header_count: u16,
+    pub section_header_offset: u64,
+    pub section_header_size: u16,
+    pub section_header_count: u16,
+}
+
+impl ElfHeader {
+    pub const SIZE: usize = 64;
+
+    /// Parses the 32 or 64-bit header, which is at most [`ElfHeader::SIZE`] bytes
+    pub fn parse(data: &[u8; Self::SIZE]) -> Result<ElfHeader, ElfError> {
+        if data[0..4] != *b"\x7FELF" {
+            return Err(ElfError::NotElf);
+        }
+
+        let (entry, program_header_offset, section_header_offset, program_headers, sections) =
+            if data[4] == ELF_CLASS_32 {
+                (
+                    u32_at(data, 24) as u64,
+                    u32_at(data, 28) as u64,
+                    u32_at(data, 32) as u64,
+                    42,
+                    46,
+                )
+            } else {
+                (u64_at(data, 24), u64_at(data, 32), u64_at(data, 40), 54, 58)
+            };
+
+        Ok(ElfHeader {
+            class: data[4],
+            data: data[5],
+            elf_type: u16_at(data, 16),
+            machine: u16_at(data, 18),
+            entry,
+            program_header_offset,
+            program_header_size: u16_at(data, program_headers),
+            program_header_count: u16_at(data, program_headers + 2),
+            section_header_offset,
+            section_header_size: u16_at(data, sections),
+            section_header_count: u16_at(data, sections + 2),
+        })
+    }
+
+    /// Checks that this is a little endian executable for one of the `targets`
+    pub fn validate(&self, targets: &[ElfTarget]) -> Result<(), ElfError> {
+        if !targets.iter().any(|t| t.class == self.class) {
+            return Err(ElfError::UnsupportedClass(self.class));
+        }
+        if self.data != ELF_DATA_LITTLE_ENDIAN {
+            return Err(ElfError::UnsupportedEndianness(self.data));
+        }
+        if !targets.contains(&ElfTarget {
+            class: self.class,
+            machine: self.machine,
+        }) {
+            if targets.iter().any(|t| t.machine == self.machine) {
+                return Err(ElfError::ClassMismatch {
+                    class: self.class,
+                    machine: self.machine,
+                });
+            }
+            return Err(ElfError::UnsupportedMachine(self.machine));
+        }
+        if self.elf_type != ET_EXEC && self.elf_type != ET_DYN {
+            return Err(ElfError::UnsupportedType(self.elf_type));
+        }
+        if self.program_header_size as usize != ProgramHeader::size(self.class) {
+            return Err(ElfError::InvalidProgramHeaderSize(self.program_header_size));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ProgramHeader {
+    pub header_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub v_addr: u64,
+    pub p_addr: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    /// Size of a program header in a file of the given class
+    pub fn size(class: u8) -> usize {
+        if class == ELF_CLASS_32 {
+            32
+        } else {
+            56
+        }
+    }
+
+    pub fn parse(class: u8, data: &[u8]) -> ProgramHeader {
+        if class == ELF_CLASS_32 {
+            return ProgramHeader {
+                header_type: u32_at(data, 0),
+                offset: u32_at(data, 4) as u64,
+                v_addr: u32_at(data, 8) as u64,
+                p_addr: u32_at(data, 12) as u64,
+                file_size: u32_at(data, 16) as u64,
+                memory_size: u32_at(data, 20) as u64,
+                flags: u32_at(data, 24),
+                align: u32_at(data, 28) as u64,
+            };
+        }
+
+        ProgramHeader {
+            header_type: u32_at(data, 0),
+            flags: u32_at(data, 4),
+            offset: u64_at(data, 8),
+            v_addr: u64_at(data, 16),
+            p_addr: u64_at(data, 24),
+            file_size: u64_at(data, 32),
+            memory_size: u64_at(data, 40),
+            align: u64_at(data, 48),
+        }
+    }
+
+    pub fn is_load(&self) -> bool {
+        self.header_type == PT_LOAD
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+
+    /// End of the segment in memory, only valid once the header was validated
+    pub fn end(&self) -> u64 {
+        self.v_addr + self.memory_size
+    }
+
+    /// Checks the segment of the program header at `index` against a file of `file_len` bytes
+    pub fn validate(&self, index: usize, file_len: u64) -> Result<(), ElfError> {
+        if self.file_size > self.memory_size {
+            return Err(ElfError::SegmentFileSizeTooLarge { index });
+        }
+
+        match self.offset.checked_add(self.file_size) {
+            Some(end) if end <= file_len => {}
+            end => {
+                return Err(ElfError::SegmentPastEndOfFile {
+                    index,
+                    end: end.unwrap_or(u64::MAX),
+                    file_len,
+                })
+            }
+        }
+
+        if self.v_addr.checked_add(self.memory_size).is_none() {
+            return Err(ElfError::SegmentOutOfRange { index });
+        }
+
+        //0 and 1 both mean the segment has no alignment requirement
+        if self.align > 1
+            && (!self.align.is_power_of_two()
+                || self.v_addr % self.align != self.offset % self.align)
+        {
+            return Err(ElfError::BadAlignment {
+                index,
+                align: self.align,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// The fields of a section header the loader needs
+#[derive(Copy, Clone, Debug)]
+pub struct SectionHeader {
+    pub section_type: u32,
+    pub flags: u64,
+    pub address: u64,
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl SectionHeader {
+    /// Size of a section header in a file of the given class
+    pub fn size(class: u8) -> usize {
+        if class == ELF_CLASS_32 {
+            40
+        } else {
+            64
+        }
+    }
+
+    pub fn parse(class: u8, data: &[u8]) -> SectionHeader {
+        if class == ELF_CLASS_32 {
+            return SectionHeader {
+                section_type: u32_at(data, 4),
+                flags: u32_at(data, 8) as u64,
+                address: u32_at(data, 12) as u64,
+                offset: u32_at(data, 16) as u64,
+                size: u32_at(data, 20) as u64,
+            };
+        }
+
+        SectionHeader {
+            section_type: u32_at(data, 4),
+            flags: u64_at(data, 8),
+            address: u64_at(data, 16),
+            offset: u64_at(data, 24),
+            size: u64_at(data, 32),
+        }
+    }
+
+    /// Initialized data the program may write to, as opposed to code, constants and bss
+    pub fn is_writable_data(&self) -> bool {
+        self.section_type == SHT_PROGBITS
+            && self.flags & (SHF_WRITE | SHF_ALLOC) == SHF_WRITE | SHF_ALLOC
+    }
+}
+
+/// Loads an ELF image from `R` without reading the whole file into memory. Only the headers are
+/// kept, every segment is read straight into its place in the destination.
+pub struct ElfLoader<R: Read + Seek> {
+    reader: R,
+    file_len: u64,
+    pub header: ElfHeader,
+    pub program_headers: Vec<ProgramHeader>,
+}
+
+impl<R: Read + Seek> ElfLoader<R> {
+    /// Reads the file and program headers and checks that the image can be loaded and runs on
+    /// one of the `targets`
+    pub fn new(mut reader: R, targets: &[ElfTarget]) -> Result<ElfLoader<R>, ElfError> {
+        let file_len = reader.seek(SeekFrom::End(0))?;
+
+        let mut header = [0u8; ElfHeader::SIZE];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut header)?;
+        let header = ElfHeader::parse(&header)?;
+        header.validate(targets)?;
+
+        //The size of an entry was validated, so only the count can make the table large
+        let entry_size = header.program_header_size as usize;
+        let len = entry_size * header.program_header_count as usize;
+        table_fits(header.program_header_offset, len, file_len)?;
+
+        let mut table = vec![0u8; len];
+        reader.seek(SeekFrom::Start(header.program_header_offset))?;
+        reader.read_exact(&mut table)?;
+
+        let program_headers = table
+            .chunks_exact(entry_size)
+            .map(|data| ProgramHeader::parse(header.class, data))
+            .collect();
+
+        let loader = ElfLoader {
+            reader,
+            file_len,
+            header,
+            program_headers,
+        };
+        loader.validate()?;
+
+        Ok(loader)
+    }
+
+    fn validate(&self) -> Result<(), ElfError> {
+        let segments = || {
+            self.program_headers
+                .iter()
+                .enumerate()
+                .filter(|(_, h)| h.is_load())
+        };
+
+        if segments().next().is_none() {
+            return Err(ElfError::NoLoadableSegments);
+        }
+
+        for (index, header) in segments() {
+            header.validate(index, self.file_len)?;
+        }
+
+        for (first, a) in segments() {
+            let overlapping = segments()
+                .filter(|(second, _)| *second > first)
+                .find(|(_, b)| a.v_addr < b.end() && b.v_addr < a.end());
+
+            if let Some((second, _)) = overlapping {
+                return Err(ElfError::OverlappingSegments { first, second });
+            }
+        }
+
+        let entry = self.header.entry;
+        if !segments().any(|(_, h)| h.is_executable() && h.v_addr <= entry && entry < h.end()) {
+            return Err(ElfError::EntryPointNotExecutable(entry));
+        }
+
+        Ok(())
+    }
+
+    /// Reads the section header table, which is empty if the file has been stripped of it
+    pub fn section_headers(&mut self) -> Result<Vec<SectionHeader>, ElfError> {
+        let entry_size = self.header.section_header_size as usize;
+        if self.header.section_header_offset == 0
+            || entry_size != SectionHeader::size(self.header.class)
+        {
+            return Ok(Vec::new());
+        }
+
+        let len = entry_size * self.header.section_header_count as usize;
+        table_fits(self.header.section_header_offset, len, self.file_len)?;
+
+        let mut table = vec![0u8; len];
+        self.read_at(self.header.section_header_offset, &mut table)?;
+
+        Ok(table
+            .chunks_exact(entry_size)
+            .map(|data| SectionHeader::parse(self.header.class, data))
+            .collect())
+    }
+
+    /// Reads `buffer.len()` bytes of the file at `offset`
+    pub fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), ElfError> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(buffer)?;
+
+        Ok(())
+    }
+
+    pub fn load_segments(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.program_headers.iter().filter(|h| h.is_load())
+    }
+
+    /// Lowest virtual address of a loaded segment, it ends up at the start of the image
+    pub fn base_address(&self) -> u64 {
+        self.load_segments().map(|h| h.v_addr).min().unwrap_or(0)
+    }
+
+    /// Number of bytes between the lowest and highest virtual address of the loaded segments
+    pub fn image_size(&self) -> usize {
+        let end = self.load_segments().map(|h| h.end()).max().unwrap_or(0);
+
+        end.saturating_sub(self.base_address()) as usize
+    }
+
+    /// Offset of the entry point from the start of the image
+    pub fn entry_point(&self) -> usize {
+        self.header.entry.wrapping_sub(self.base_address()) as usize
+    }
+
+    /// Reads every loadable segment into `image`, which must hold at least
+    /// [`ElfLoader::image_size`] bytes, and zeroes the part of a segment not backed by the file.
+    /// `progress` is called with the bytes read so far and the total after every chunk.
+    pub fn load(
+        &mut self,
+        image: &mut [u8],
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<(), ElfError> {
+        let base = self.base_address();
+        let total = self.load_segments().map(|h| h.file_size as usize).sum();
+        let mut read = 0;
+
+        progress(read, total);
+
+        for index in 0..self.program_headers.len() {
+            let header = self.program_headers[index];
+            if !header.is_load() {
+                continue;
+            }
+
+            //The headers were validated, so every segment fits into the image
+            let start = (header.v_addr - base) as usize;
+            let segment = &mut image[start..start + header.memory_size as usize];
+            let (data, tail) = segment.split_at_mut(header.file_size as usize);
+
+            self.reader.seek(SeekFrom::Start(header.offset))?;
+            let done = read;
+            let len = self
+                .reader
+                .read_chunked(data, |segment, _| progress(done + segment, total))?;
+            if len < data.len() {
+                return Err(EFI_END_OF_FILE.into());
+            }
+            tail.fill(0);
+
+            read += len;
+        }
+
+        Ok(())
+    }
+
+    /// Applies the dynamic relocations of a position independent image loaded at `image`
+    pub fn relocate(&self, image: &mut [u8]) -> Result<(), ElfError> {
+        self.relocate_to(image, image.as_ptr() as u64)
+    }
+
+    /// Applies the dynamic relocations for an image that runs at `load_address`, e.g. a virtual
+    /// address different from where `image` is in memory
+    pub fn relocate_to(&self, image: &mut [u8], load_address: u64) -> Result<(), ElfError> {
+        let base = self.base_address();
+
+        let (index, dynamic) = match self
+            .program_headers
+            .iter()
+            .enumerate()
+            .find(|(_, h)| h.header_type == PT_DYNAMIC)
+        {
+            Some(dynamic) => dynamic,
+            None => return Ok(()),
+        };
+        let out_of_bounds = ElfError::DynamicOutOfBounds { index };
+
+        //The dynamic section is part of a loaded segment, so it is read from the image
+        let entries = range(dynamic.v_addr.wrapping_sub(base), dynamic.memory_size)
+            .and_then(|range| image.get(range))
+            .ok_or(out_of_bounds.clone())?;
+
+        let (mut rela, mut rela_size, mut rela_entry) = (None, 0, 24);
+        for entry in entries.chunks_exact(16) {
+            match u64_at(entry, 0) {
+                DT_NULL => break,
+                DT_RELA => rela = Some(u64_at(entry, 8)),
+                DT_RELASZ => rela_size = u64_at(entry, 8),
+                DT_RELAENT => rela_entry = u64_at(entry, 8),
+                _ => {}
+            }
+        }
+
+        let rela = match rela {
+            Some(rela) => rela,
+            None => return Ok(()),
+        };
+        if rela_entry < 24 {
+            return Err(out_of_bounds);
+        }
+
+        for i in 0..rela_size / rela_entry {
+            let entry_start = rela.wrapping_sub(base).wrapping_add(i * rela_entry);
+            let entry = range(entry_start, 24)
+                .and_then(|range| image.get(range))
+                .ok_or(out_of_bounds.clone())?;
+
+            let offset = u64_at(entry, 0);
+            let kind = u64_at(entry, 8) as u32;
+            let addend = u64_at(entry, 16);
+
+            match kind {
+                R_X86_64_NONE => {}
+                R_X86_64_RELATIVE => {
+                    let value = load_address.wrapping_add(addend.wrapping_sub(base));
+
+                    range(offset.wrapping_sub(base), 8)
+                        .and_then(|range| image.get_mut(range))
+                        .ok_or(ElfError::RelocationOutOfBounds { index: i, offset })?
+                        .copy_from_slice(&value.to_le_bytes());
+                }
+                kind => return Err(ElfError::UnsupportedRelocation { index: i, kind }),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that a header table of `len` bytes at `offset` lies inside a file of `file_len` bytes,
+/// before anything is allocated for it
+fn table_fits(offset: u64, len: usize, file_len: u64) -> Result<(), ElfError> {
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= file_len => Ok(()),
+        end => Err(ElfError::HeaderTablePastEndOfFile {
+            end: end.unwrap_or(u64::MAX),
+            file_len,
+        }),
+    }
+}
+
+/// The range of `len` bytes at `offset` in the image
+fn range(offset: u64, len: u64) -> Option<Range<usize>> {
+    let end = offset.checked_add(len)?;
+
+    Some(offset as usize..end as usize)
+}
@@ -0,0 +1,933 @@
+//! Boots kernels written for the Limine boot protocol. The kernel declares what it needs with
+//! request structures in its data sections, the loader answers each request it knows with a
+//! response and starts the kernel in its own higher half address space.
+
+use crate::common::bytes::{u32_at, u64_at};
+use crate::common::gdt::GdtPointer;
+use crate::common::log::FrameBufferInfo;
+use crate::common::memory::{MemoryMapBuilder, MemoryRegionKind, PAGE_SIZE};
+use crate::common::paging::PageTableMapper;
+use crate::efi::graphics::PixelLayout;
+use crate::efi::mp_services::MpServices;
+use crate::efi::simple_fs::{to_uefi_path, EfiFile, FileError, FILE_MODE_READ};
+use crate::efi::{
+    efi_status_name, EfiHandle, EfiMemoryDescriptor, EfiMemoryType, EfiStatus, EventType,
+};
+use crate::efi::{SystemTable, ACPI_20_TABLE_GUID, ACPI_TABLE_GUID};
+use crate::elf::{ElfError, ElfLoader, ElfTarget, ET_DYN, PF_W};
+use crate::menu::BootEntry;
+use crate::{allocator, exit_boot_services, load_file, logger, memory_map_size, LOADER_POOL_SIZE};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::Infallible;
+use core::ffi::c_void;
+use core::fmt::{Display, Formatter, Write};
+use trampoline::{limine_enter, limine_park};
+
+const COMMON_MAGIC: [u64; 2] = [0xC7B1DD30DF4C8B88, 0x0A82E883A194F07B];
+const BASE_REVISION_MAGIC: [u64; 2] = [0xF9562B2D5C95A6C8, 0x6A7B384944536BDC];
+/// Highest base revision whose guarantees the loader meets. Up to revision 2 every address
+/// handed to the kernel is in the higher half direct map.
+const SUPPORTED_BASE_REVISION: u64 = 2;
+
+/// Start of the higher half direct map of all physical memory
+pub const HHDM_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+const DEFAULT_STACK_SIZE: u64 = 64 * 1024;
+/// The identity map and the direct map cover at least this much
+const MIN_MAPPED: u64 = 0x1_0000_0000;
+
+//Offsets in a request
+const REQUEST_RESPONSE: usize = 40;
+const REQUEST_DATA: usize = 48;
+
+/// The last two words of a request id, the first two are [`COMMON_MAGIC`]
+pub struct RequestId;
+
+impl RequestId {
+    pub const BOOTLOADER_INFO: [u64; 2] = [0xF55038D8E2A1202F, 0x279426FCF5F59740];
+    pub const STACK_SIZE: [u64; 2] = [0x224EF0460A8E8926, 0xE1CB0FC25F46EA3D];
+    pub const HHDM: [u64; 2] = [0x48DCF1CB8AD2B852, 0x63984E959A98244B];
+    pub const FRAMEBUFFER: [u64; 2] = [0x9D5827DCD881DD75, 0xA3148604F6FAB11B];
+    pub const MEMMAP: [u64; 2] = [0x67CF3D9D378A806F, 0xE304ACDFC50C3C62];
+    pub const ENTRY_POINT: [u64; 2] = [0x13D86C035A1CD3E1, 0x2B0CAA89D8F3026A];
+    pub const MODULE: [u64; 2] = [0x3E7E279702BE32AF, 0xCA1C4F3BD1280CEE];
+    pub const RSDP: [u64; 2] = [0xC5E77B6B397E7B43, 0x27637845ACCDCF3C];
+    pub const BOOT_TIME: [u64; 2] = [0x502746E184C088AA, 0xFBC5EC83E6327893];
+    pub const KERNEL_ADDRESS: [u64; 2] = [0x71BA76863CC55F63, 0xB2644A48C516A487];
+    pub const SMP: [u64; 2] = [0x95A67B819A1B857E, 0xA0B61B723B6A73E0];
+}
+
+/// Memory map entry types
+const MEMMAP_USABLE: u64 = 0;
+const MEMMAP_RESERVED: u64 = 1;
+const MEMMAP_ACPI_RECLAIMABLE: u64 = 2;
+const MEMMAP_ACPI_NVS: u64 = 3;
+const MEMMAP_BOOTLOADER_RECLAIMABLE: u64 = 5;
+const MEMMAP_KERNEL_AND_MODULES: u64 = 6;
+const MEMMAP_FRAMEBUFFER: u64 = 7;
+
+const FRAMEBUFFER_RGB: u8 = 1;
+
+#[derive(Debug)]
+pub enum LimineError {
+    File(FileError),
+    Efi(EfiStatus),
+    Elf(ElfError),
+    /// A request lies outside the loaded image
+    RequestOutOfBounds(u64),
+}
+
+impl From<EfiStatus> for LimineError {
+    fn from(status: EfiStatus) -> Self {
+        LimineError::Efi(status)
+    }
+}
+
+impl From<FileError> for LimineError {
+    fn from(error: FileError) -> Self {
+        LimineError::File(error)
+    }
+}
+
+impl From<ElfError> for LimineError {
+    fn from(error: ElfError) -> Self {
+        LimineError::Elf(error)
+    }
+}
+
+impl Display for LimineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LimineError::File(error) => write!(f, "{}", error),
+            LimineError::Efi(status) => write!(f, "firmware error: {}", efi_status_name(*status)),
+            LimineError::Elf(error) => write!(f, "{}", error),
+            LimineError::RequestOutOfBounds(address) => {
+                write!(f, "the request at {:#X} is not part of the image", address)
+            }
+        }
+    }
+}
+
+/// A request found in the kernel
+#[derive(Copy, Clone, Debug)]
+pub struct Request {
+    /// Virtual address of the request
+    pub address: u64,
+    pub id: [u64; 2],
+}
+
+/// The requests of a Limine kernel
+#[derive(Clone, Debug)]
+pub struct LimineKernel {
+    pub requests: Vec<Request>,
+    /// Virtual address and value of the base revision tag, if the kernel has one
+    pub base_revision: Option<(u64, u64)>,
+}
+
+impl LimineKernel {
+    /// Scans the writable data of the kernel for requests, returns `None` if there are none
+    pub fn find(loader: &mut ElfLoader<&mut EfiFile>) -> Result<Option<LimineKernel>, ElfError> {
+        //Requests are written by the loader, so they live in writable sections. Without section
+        //headers the writable segments are scanned instead.
+        let mut ranges: Vec<(u64, u64, u64)> = loader
+            .section_headers()?
+            .iter()
+            .filter(|s| s.is_writable_data())
+            .map(|s| (s.address, s.offset, s.size))
+            .collect();
+        if ranges.is_empty() {
+            ranges = loader
+                .load_segments()
+                .filter(|h| h.flags & PF_W != 0)
+                .map(|h| (h.v_addr, h.offset, h.file_size))
+                .collect();
+        }
+
+        let mut kernel = LimineKernel {
+            requests: Vec::new(),
+            base_revision: None,
+        };
+
+        for (address, offset, size) in ranges {
+            let mut data = vec![0u8; size as usize];
+            loader.read_at(offset, &mut data)?;
+
+            let start = address.next_multiple_of(8) - address;
+            for position in (start as usize..data.len().saturating_sub(23)).step_by(8) {
+                let words = [u64_at(&data, position), u64_at(&data, position + 8)];
+
+                if words == COMMON_MAGIC && position + REQUEST_DATA <= data.len() {
+                    kernel.requests.push(Request {
+                        address: address + position as u64,
+                        id: [u64_at(&data, position + 16), u64_at(&data, position + 24)],
+                    });
+                } else if words == BASE_REVISION_MAGIC {
+                    kernel.base_revision =
+                        Some((address + position as u64, u64_at(&data, position + 16)));
+                }
+            }
+        }
+
+        if kernel.requests.is_empty() && kernel.base_revision.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(kernel))
+    }
+}
+
+/// Checks the kernel at `path` for Limine requests. Files that are no 64-bit ELF are left to
+/// the other loaders.
+#[allow(unsafe_code)]
+pub fn detect(root: &mut EfiFile, path: &str) -> Result<Option<LimineKernel>, LimineError> {
+    let file = root.open_path(path, FILE_MODE_READ)?;
+    let result = match ElfLoader::new(unsafe { &mut *file }, &[ElfTarget::X86_64]) {
+        Ok(mut loader) => LimineKernel::find(&mut loader),
+        Err(ElfError::Io(status)) => Err(ElfError::Io(status)),
+        Err(_) => Ok(None),
+    };
+    unsafe { (*file).close() };
+
+    Ok(result?)
+}
+
+#[repr(C)]
+struct BootloaderInfoResponse {
+    revision: u64,
+    name: u64,
+    version: u64,
+}
+
+#[repr(C)]
+struct HhdmResponse {
+    revision: u64,
+    offset: u64,
+}
+
+#[repr(C)]
+struct FramebufferResponse {
+    revision: u64,
+    framebuffer_count: u64,
+    framebuffers: u64,
+}
+
+#[repr(C)]
+struct Framebuffer {
+    address: u64,
+    width: u64,
+    height: u64,
+    pitch: u64,
+    bpp: u16,
+    memory_model: u8,
+    red_mask_size: u8,
+    red_mask_shift: u8,
+    green_mask_size: u8,
+    green_mask_shift: u8,
+    blue_mask_size: u8,
+    blue_mask_shift: u8,
+    unused: [u8; 7],
+    edid_size: u64,
+    edid: u64,
+}
+
+#[repr(C)]
+struct MemmapResponse {
+    revision: u64,
+    entry_count: u64,
+    entries: u64,
+}
+
+#[repr(C)]
+struct MemmapEntry {
+    base: u64,
+    length: u64,
+    entry_type: u64,
+}
+
+#[repr(C)]
+struct ModuleResponse {
+    revision: u64,
+    module_count: u64,
+    modules: u64,
+}
+
+#[repr(C)]
+struct File {
+    revision: u64,
+    address: u64,
+    size: u64,
+    path: u64,
+    cmdline: u64,
+    media_type: u32,
+    unused: u32,
+    tftp_ip: u32,
+    tftp_port: u32,
+    partition_index: u32,
+    mbr_disk_id: u32,
+    gpt_disk_uuid: [u8; 16],
+    gpt_part_uuid: [u8; 16],
+    part_uuid: [u8; 16],
+}
+
+#[repr(C)]
+struct AddressResponse {
+    revision: u64,
+    address: u64,
+}
+
+#[repr(C)]
+struct BootTimeResponse {
+    revision: u64,
+    boot_time: i64,
+}
+
+#[repr(C)]
+struct KernelAddressResponse {
+    revision: u64,
+    physical_base: u64,
+    virtual_base: u64,
+}
+
+#[repr(C)]
+struct SmpResponse {
+    revision: u64,
+    flags: u32,
+    bsp_lapic_id: u32,
+    cpu_count: u64,
+    cpus: u64,
+}
+
+#[repr(C)]
+struct SmpInfo {
+    processor_id: u32,
+    lapic_id: u32,
+    reserved: u64,
+    /// Written by the kernel to start the processor there
+    goto_address: u64,
+    extra_argument: u64,
+}
+
+/// Where a parked application processor waits for the kernel, read by [`limine_park`]
+#[repr(C)]
+struct ParkInfo {
+    /// Its [`SmpInfo`] in the higher half direct map
+    info: u64,
+    stack: u64,
+    pml4: u64,
+    gdt: *const GdtPointer,
+}
+
+/// Responses that only carry their revision
+#[repr(C)]
+struct EmptyResponse {
+    revision: u64,
+}
+
+/// The address of `value` in the higher half direct map
+fn hhdm<T: ?Sized>(value: &T) -> u64 {
+    HHDM_OFFSET + value as *const T as *const u8 as u64
+}
+
+/// Moves `value` to boot information memory for good
+fn leak<T>(value: T) -> &'static mut T {
+    allocator().with_memory_type(EfiMemoryType::BOOT_INFO, || Box::leak(Box::new(value)))
+}
+
+/// A null terminated copy of `s` in boot information memory
+fn leak_str(s: &str) -> &'static [u8] {
+    allocator().with_memory_type(EfiMemoryType::BOOT_INFO, || {
+        let mut bytes = Vec::with_capacity(s.len() + 1);
+        bytes.extend_from_slice(s.as_bytes());
+        bytes.push(0);
+        &*bytes.leak()
+    })
+}
+
+/// A kernel loaded to physical memory with the responses written into its requests
+struct LoadedKernel {
+    image: &'static mut [u8],
+    virtual_base: u64,
+    answered: usize,
+}
+
+impl LoadedKernel {
+    fn request_offset(&self, request: &Request) -> Result<usize, LimineError> {
+        request
+            .address
+            .checked_sub(self.virtual_base)
+            .map(|offset| offset as usize)
+            .filter(|offset| offset + REQUEST_DATA + 8 <= self.image.len())
+            .ok_or(LimineError::RequestOutOfBounds(request.address))
+    }
+
+    /// The first request specific field
+    fn request_data(&self, request: &Request) -> Result<u64, LimineError> {
+        let offset = self.request_offset(request)?;
+
+        Ok(u64_at(self.image, offset + REQUEST_DATA))
+    }
+
+    fn respond<T>(&mut self, request: &Request, response: &T) -> Result<(), LimineError> {
+        let offset = self.request_offset(request)? + REQUEST_RESPONSE;
+        self.image[offset..offset + 8].copy_from_slice(&hhdm(response).to_le_bytes());
+        self.answered += 1;
+
+        Ok(())
+    }
+}
+
+/// Loads the kernel of `entry` with its modules, answers its requests and starts it
+#[allow(unsafe_code)]
+pub fn boot(
+    image_handle: EfiHandle,
+    st: &SystemTable,
+    root: &mut EfiFile,
+    entry: &BootEntry,
+    limine: &LimineKernel,
+    framebuffer: FrameBufferInfo,
+    pixels: PixelLayout,
+) -> Result<Infallible, LimineError> {
+    let logger = logger();
+
+    let file = root.open_path(&entry.kernel, FILE_MODE_READ)?;
+    let result = load_kernel(unsafe { &mut *file });
+    unsafe { (*file).close() };
+    let (mut kernel, mut kernel_entry) = result?;
+
+    writeln!(
+        logger,
+        "\rLimine kernel at {:#X}, virtual {:#X}, {} requests\r",
+        kernel.image.as_ptr() as u64,
+        kernel.virtual_base,
+        limine.requests.len()
+    )
+    .unwrap();
+
+    if let Some((address, revision)) = limine.base_revision {
+        if revision <= SUPPORTED_BASE_REVISION {
+            //Setting the revision to 0 tells the kernel it is supported
+            let request = Request {
+                address,
+                id: [0; 2],
+            };
+            let offset = kernel.request_offset(&request)?;
+            kernel.image[offset + 16..offset + 24].fill(0);
+        } else {
+            //Left as it is, the kernel sees that its revision is not supported
+            writeln!(
+                logger,
+                "Kernel asks for base revision {}, {} is the highest supported\r",
+                revision, SUPPORTED_BASE_REVISION
+            )
+            .unwrap();
+        }
+    }
+
+    //Identity map the loader, so it keeps running after switching to the kernel's tables, and
+    //map the RAM into the higher half
+    let top = physical_memory_top(st).max(MIN_MAPPED);
+    //A framebuffer above the RAM only gets its own range mapped
+    let framebuffer_start = (framebuffer.address as u64).max(top);
+    let framebuffer_end = (framebuffer.address + framebuffer.len) as u64;
+    let mut mapper = PageTableMapper::new()?;
+    for offset in [0, HHDM_OFFSET] {
+        mapper.map(offset, 0, top)?;
+        if framebuffer_end > framebuffer_start {
+            mapper.map(
+                offset + framebuffer_start,
+                framebuffer_start,
+                framebuffer_end - framebuffer_start,
+            )?;
+        }
+    }
+    mapper.map(
+        kernel.virtual_base,
+        kernel.image.as_ptr() as u64,
+        kernel.image.len() as u64,
+    )?;
+
+    let mut stack_size = DEFAULT_STACK_SIZE;
+    let mut memmap = None;
+    let mut smp = None;
+
+    for request in &limine.requests {
+        match request.id {
+            RequestId::BOOTLOADER_INFO => {
+                let response = leak(BootloaderInfoResponse {
+                    revision: 0,
+                    name: hhdm(leak_str(env!("CARGO_PKG_NAME"))),
+                    version: hhdm(leak_str(env!("CARGO_PKG_VERSION"))),
+                });
+                kernel.respond(request, response)?;
+            }
+            RequestId::STACK_SIZE => {
+                stack_size = stack_size.max(kernel.request_data(request)?);
+                kernel.respond(request, leak(EmptyResponse { revision: 0 }))?;
+            }
+            RequestId::HHDM => {
+                let response = leak(HhdmResponse {
+                    revision: 0,
+                    offset: HHDM_OFFSET,
+                });
+                kernel.respond(request, response)?;
+            }
+            RequestId::FRAMEBUFFER => {
+                let info = leak(Framebuffer {
+                    address: HHDM_OFFSET + framebuffer.address as u64,
+                    width: framebuffer.screen_width as u64,
+                    height: framebuffer.screen_height as u64,
+                    pitch: framebuffer.pixels_per_scan_line as u64 * 4,
+                    bpp: 32,
+                    memory_model: FRAMEBUFFER_RGB,
+                    red_mask_size: pixels.red.size,
+                    red_mask_shift: pixels.red.shift,
+                    green_mask_size: pixels.green.size,
+                    green_mask_shift: pixels.green.shift,
+                    blue_mask_size: pixels.blue.size,
+                    blue_mask_shift: pixels.blue.shift,
+                    unused: [0; 7],
+                    edid_size: 0,
+                    edid: 0,
+                });
+                let framebuffers = leak([hhdm(info)]);
+                let response = leak(FramebufferResponse {
+                    revision: 0,
+                    framebuffer_count: 1,
+                    framebuffers: hhdm(framebuffers),
+                });
+                kernel.respond(request, response)?;
+            }
+            RequestId::MEMMAP => {
+                //Filled in once the boot services are gone and the map is final
+                let response = leak(MemmapResponse {
+                    revision: 0,
+                    entry_count: 0,
+                    entries: 0,
+                });
+                kernel.respond(request, &*response)?;
+                memmap = Some(response);
+            }
+            RequestId::ENTRY_POINT => {
+                let entry_point = kernel.request_data(request)?;
+                if entry_point != 0 {
+                    kernel_entry = entry_point;
+                }
+                kernel.respond(request, leak(EmptyResponse { revision: 0 }))?;
+            }
+            RequestId::MODULE => {
+                let modules = load_modules(root, &entry.modules)?;
+                let response = leak(ModuleResponse {
+                    revision: 0,
+                    module_count: modules.len() as u64,
+                    modules: hhdm(modules),
+                });
+                kernel.respond(request, response)?;
+            }
+            RequestId::RSDP => {
+                if let Some(rsdp) = st.configuration_table(&[ACPI_20_TABLE_GUID, ACPI_TABLE_GUID]) {
+                    let response = leak(AddressResponse {
+                        revision: 0,
+                        address: HHDM_OFFSET + rsdp,
+                    });
+                    kernel.respond(request, response)?;
+                }
+            }
+            RequestId::BOOT_TIME => {
+                let boot_time = st
+                    .runtime_services()
+                    .get_time()
+                    .map_or(0, |t| t.unix_time());
+                let response = leak(BootTimeResponse {
+                    revision: 0,
+                    boot_time,
+                });
+                kernel.respond(request, response)?;
+            }
+            RequestId::KERNEL_ADDRESS => {
+                let response = leak(KernelAddressResponse {
+                    revision: 0,
+                    physical_base: kernel.image.as_ptr() as u64,
+                    virtual_base: kernel.virtual_base,
+                });
+                kernel.respond(request, response)?;
+            }
+            //Answered once the stack size is known, right before leaving the boot services
+            RequestId::SMP => smp = Some(*request),
+            //Unknown requests stay unanswered
+            _ => {}
+        }
+    }
+
+    let stack = allocator().allocate_pages_below(
+        u64::MAX,
+        EfiMemoryType::KERNEL_STACK,
+        stack_size as usize,
+    )?;
+    let stack_top = hhdm(stack) + stack.len() as u64;
+
+    allocator().reserve_pool(LOADER_POOL_SIZE)?;
+
+    let gdt_pointer: &'static GdtPointer = leak(GdtPointer::new(&LIMINE_GDT));
+
+    //Started last, a parked processor cannot be given back to the firmware
+    if let Some(request) = smp {
+        if let Some(response) = start_processors(st, stack_size, mapper.root(), gdt_pointer)? {
+            kernel.respond(&request, response)?;
+        }
+    }
+
+    writeln!(
+        logger,
+        "Answered {} of {} requests\r",
+        kernel.answered,
+        limine.requests.len()
+    )
+    .unwrap();
+
+    let mut memory_regions = MemoryMapBuilder::new();
+    memory_regions.overlay(
+        framebuffer.address as u64,
+        framebuffer.len as u64,
+        MemoryRegionKind::FRAMEBUFFER,
+    );
+
+    let memory_map = exit_boot_services(image_handle, st, &mut memory_regions, usize::MAX)?;
+    let memory_regions = memory_regions.build(&memory_map);
+
+    if let Some(memmap) = memmap {
+        //Allocated from the loader pool, which is bootloader reclaimable memory
+        let entries: &'static [MemmapEntry] = memory_regions
+            .iter()
+            .map(|region| MemmapEntry {
+                base: region.start,
+                length: region.len(),
+                entry_type: memmap_type(region.kind),
+            })
+            .collect::<Vec<_>>()
+            .leak();
+        let pointers: &'static [u64] = entries.iter().map(hhdm).collect::<Vec<_>>().leak();
+
+        memmap.entry_count = entries.len() as u64;
+        memmap.entries = hhdm(pointers);
+    }
+
+    writeln!(logger, "Calling Limine kernel at {:#X}\r", kernel_entry).unwrap();
+
+    unsafe { limine_enter(kernel_entry, stack_top, mapper.root(), gdt_pointer, 0) }
+}
+
+/// Starts the other processors with the MP services, each switches to the kernel's page tables
+/// and waits until the kernel writes its `goto_address`. `None` if the firmware has no MP
+/// services.
+#[allow(unsafe_code)]
+fn start_processors(
+    st: &SystemTable,
+    stack_size: u64,
+    pml4: u64,
+    gdt: &'static GdtPointer,
+) -> Result<Option<&'static SmpResponse>, LimineError> {
+    let logger = logger();
+    let boot_services = st.boot_services();
+
+    let mp = match boot_services.locate_protocol::<MpServices>() {
+        Ok(mp) => unsafe { &*mp },
+        Err(_) => return Ok(None),
+    };
+    let (processors, _) = mp.number_of_processors()?;
+
+    let mut bsp_lapic_id = 0;
+    //Never grows past its capacity, so it stays in boot information memory
+    let mut cpus =
+        allocator().with_memory_type(EfiMemoryType::BOOT_INFO, || Vec::with_capacity(processors));
+    for processor in 0..processors {
+        let processor_info = mp.processor_info(processor)?;
+        if !processor_info.is_enabled() {
+            continue;
+        }
+
+        let info = leak(SmpInfo {
+            processor_id: processor as u32,
+            lapic_id: processor_info.processor_id as u32,
+            reserved: 0,
+            goto_address: 0,
+            extra_argument: 0,
+        });
+
+        if processor_info.is_bsp() {
+            bsp_lapic_id = info.lapic_id;
+        } else {
+            let stack = allocator().allocate_pages_below(
+                u64::MAX,
+                EfiMemoryType::KERNEL_STACK,
+                stack_size as usize,
+            )?;
+            let park = leak(ParkInfo {
+                info: hhdm(info),
+                stack: hhdm(stack) + stack.len() as u64,
+                pml4,
+                gdt,
+            });
+
+            let event = boot_services.create_event(EventType::NONE)?;
+            let argument = park as *mut ParkInfo as *mut c_void;
+            if let Err(status) = mp.startup_this_ap(limine_park, processor, &event, argument) {
+                writeln!(
+                    logger,
+                    "Unable to start processor {}: {}\r",
+                    processor,
+                    efi_status_name(status)
+                )
+                .unwrap();
+                continue;
+            }
+            //Only signaled if the processor returned, which it never does
+            core::mem::forget(event);
+        }
+
+        cpus.push(hhdm(info));
+    }
+
+    writeln!(logger, "Started {} processors\r", cpus.len()).unwrap();
+
+    let cpus: &'static [u64] = cpus.leak();
+    Ok(Some(leak(SmpResponse {
+        revision: 0,
+        flags: 0,
+        bsp_lapic_id,
+        cpu_count: cpus.len() as u64,
+        cpus: hhdm(cpus),
+    })))
+}
+
+/// Loads the kernel into physical pages to be mapped at its linked address. Position
+/// independent kernels are relocated to that address as well.
+fn load_kernel(file: &mut EfiFile) -> Result<(LoadedKernel, u64), LimineError> {
+    let logger = logger();
+
+    let mut loader = ElfLoader::new(file, &[ElfTarget::X86_64])?;
+    for h in loader.load_segments() {
+        writeln!(
+            logger,
+            "Offset: {:#016X} - V: {:#016X} - {:8} Bytes\r",
+            h.offset, h.v_addr, h.memory_size
+        )
+        .unwrap();
+    }
+
+    //The image starts at the same offset into its first page as the virtual base
+    let virtual_base = loader.base_address();
+    let page_offset = (virtual_base % PAGE_SIZE) as usize;
+    let pages = allocator().allocate_pages_below(
+        u64::MAX,
+        EfiMemoryType::KERNEL,
+        page_offset + loader.image_size(),
+    )?;
+    let image = &mut pages[page_offset..];
+
+    loader.load(image, |done, total| logger.progress(done, total))?;
+    if loader.header.elf_type == ET_DYN {
+        loader.relocate_to(image, virtual_base)?;
+    }
+
+    let entry = loader.header.entry;
+
+    Ok((
+        LoadedKernel {
+            image,
+            virtual_base,
+            answered: 0,
+        },
+        entry,
+    ))
+}
+
+/// Loads the modules to page aligned memory and describes them as Limine files
+fn load_modules(
+    root: &mut EfiFile,
+    modules: &[alloc::string::String],
+) -> Result<&'static [u64], LimineError> {
+    let logger = logger();
+    let mut files = Vec::with_capacity(modules.len());
+
+    for module in modules {
+        let path = BootEntry::module_path(module);
+        writeln!(logger, "Loading module {}\r", to_uefi_path(path)).unwrap();
+
+        let data = load_file(
+            root,
+            path,
+            u64::MAX,
+            EfiMemoryType::MODULES,
+            |done, total| logger.progress(done, total),
+        )?;
+        writeln!(logger, "\r").unwrap();
+
+        let cmdline = module[module.find(path).unwrap_or(0) + path.len()..].trim();
+        let file = leak(File {
+            revision: 0,
+            address: hhdm(&*data),
+            size: data.len() as u64,
+            path: hhdm(leak_str(path)),
+            cmdline: hhdm(leak_str(cmdline)),
+            media_type: 0,
+            unused: 0,
+            tftp_ip: 0,
+            tftp_port: 0,
+            partition_index: 0,
+            mbr_disk_id: 0,
+            gpt_disk_uuid: [0; 16],
+            gpt_part_uuid: [0; 16],
+            part_uuid: [0; 16],
+        });
+        files.push(hhdm(file));
+    }
+
+    Ok(allocator().with_memory_type(EfiMemoryType::BOOT_INFO, || &*files.leak()))
+}
+
+fn memmap_type(kind: MemoryRegionKind) -> u64 {
+    match kind {
+        MemoryRegionKind::USABLE => MEMMAP_USABLE,
+        MemoryRegionKind::ACPI_RECLAIMABLE => MEMMAP_ACPI_RECLAIMABLE,
+        MemoryRegionKind::ACPI_NVS => MEMMAP_ACPI_NVS,
+        MemoryRegionKind::BOOTLOADER_RECLAIMABLE => MEMMAP_BOOTLOADER_RECLAIMABLE,
+        MemoryRegionKind::KERNEL_AND_MODULES => MEMMAP_KERNEL_AND_MODULES,
+        MemoryRegionKind::FRAMEBUFFER => MEMMAP_FRAMEBUFFER,
+        _ => MEMMAP_RESERVED,
+    }
+}
+
+/// End of the highest RAM in the firmware memory map. MMIO and reserved ranges are left out,
+/// firmware often places them right below 4 GiB or far above the RAM, where mapping them would
+/// cost a lot of page tables.
+fn physical_memory_top(st: &SystemTable) -> u64 {
+    let (descriptors, desc_size) = memory_map_size(st);
+    //Room for the descriptors of the buffer allocation itself
+    let mut bytes = vec![0u8; (descriptors + 4) * desc_size];
+    let mut map_size = bytes.len() as u64;
+    let mut map_key = 0u64;
+    let mut desc_size = desc_size as u64;
+    let mut desc_version = 0u32;
+
+    let status = st.boot_services().get_memory_map(
+        &mut map_size,
+        bytes.as_mut_ptr() as *mut EfiMemoryDescriptor,
+        &mut map_key,
+        &mut desc_size,
+        &mut desc_version,
+    );
+    if status != 0 {
+        return 0;
+    }
+
+    bytes[..map_size as usize]
+        .chunks_exact(desc_size as usize)
+        .filter(|d| {
+            !matches!(
+                EfiMemoryType(u32_at(d, 0)),
+                EfiMemoryType::EFI_RESERVED_MEMORY_TYPE
+                    | EfiMemoryType::EFI_MEMORY_MAPPED_IO
+                    | EfiMemoryType::EFI_MEMORY_MAPPED_IOPORT_SPACE
+            )
+        })
+        .map(|d| u64_at(d, 8) + u64_at(d, 24) * PAGE_SIZE)
+        .max()
+        .unwrap_or(0)
+}
+
+/// The GDT Limine kernels start with: null, 16, 32 and 64-bit code and data. The kernel runs
+/// with code at 0x28 and data at 0x30.
+static LIMINE_GDT: [u64; 7] = [
+    0,
+    0x00009A000000FFFF,
+    0x000092000000FFFF,
+    0x00CF9A000000FFFF,
+    0x00CF92000000FFFF,
+    0x00AF9A000000FFFF,
+    0x00CF92000000FFFF,
+];
+
+#[allow(unsafe_code)]
+mod trampoline {
+    use crate::common::gdt::GdtPointer;
+    use core::arch::global_asm;
+    use core::ffi::c_void;
+
+    extern "sysv64" {
+        /// Switches to the kernel's page tables and stack and jumps to `entry` with a return
+        /// address of 0 on the stack and all general purpose registers but `rdi` cleared, it
+        /// holds `argument`
+        pub fn limine_enter(
+            entry: u64,
+            stack: u64,
+            pml4: u64,
+            gdt: *const GdtPointer,
+            argument: u64,
+        ) -> !;
+    }
+
+    extern "efiapi" {
+        /// Run by the MP services on an application processor with its
+        /// [`ParkInfo`](super::ParkInfo). Enters the kernel's address space like
+        /// [`limine_enter`] and spins until the kernel writes the `goto_address` of the
+        /// processor, then jumps there with `rdi` pointing to its `SmpInfo`.
+        pub fn limine_park(park: *mut c_void);
+    }
+
+    global_asm!(
+        ".global limine_enter",
+        "limine_enter:",
+        "cli",
+        "cld",
+        "lgdt [rcx]",
+        "mov cr3, rdx",
+        "mov ax, 0x30",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov fs, ax",
+        "mov gs, ax",
+        "mov ss, ax",
+        "mov rsp, rsi",
+        "push 0x28",
+        "lea rax, [rip + 2f]",
+        "push rax",
+        "retfq",
+        "2:",
+        "push 0",
+        "push rdi",
+        "mov rdi, r8",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "ret",
+        ".global limine_park",
+        "limine_park:",
+        "mov r8, [rcx]",
+        "mov rsi, [rcx + 8]",
+        "mov rdx, [rcx + 16]",
+        "mov rcx, [rcx + 24]",
+        "lea rdi, [rip + 3f]",
+        "jmp limine_enter",
+        //Entered with the SmpInfo in rdi, spin on its goto_address
+        "3:",
+        "pause",
+        "mov rax, [rdi + 16]",
+        "test rax, rax",
+        "jz 3b",
+        "push rax",
+        "xor eax, eax",
+        "ret",
+    );
+}
@@ -0,0 +1,562 @@
+//! Boots Linux bzImage files through the x86 boot protocol. The kernel is entered through the EFI
+//! handover protocol if it advertises it, otherwise through its 64-bit entry point after the boot
+//! services have been exited.
+
+use crate::common::bytes::{set_u16, set_u32, set_u64, u16_at, u32_at, u64_at};
+use crate::common::gdt::GdtPointer;
+use crate::common::log::FrameBufferInfo;
+use crate::common::memory::{MemoryMapBuilder, MemoryRegion, MemoryRegionKind, PAGE_SIZE};
+use crate::efi::graphics::PixelLayout;
+use crate::efi::simple_fs::FILE_MODE_READ;
+use crate::efi::simple_fs::{to_uefi_path, EfiFile, FileError, Read, Seek, SeekFrom};
+use crate::efi::{efi_status_name, EfiHandle, EfiMemoryDescriptor, EfiMemoryType, EfiStatus};
+use crate::efi::{SystemTable, ACPI_20_TABLE_GUID, ACPI_TABLE_GUID, EFI_END_OF_FILE};
+use crate::menu::BootEntry;
+use crate::{allocator, exit_boot_services, logger, LOADER_POOL_SIZE};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::Infallible;
+use core::fmt::{Display, Formatter, Write};
+use core::mem::{self, size_of};
+use trampoline::linux_enter_64;
+
+/// Size of `struct boot_params`, the "zero page"
+const BOOT_PARAMS_SIZE: usize = 4096;
+/// The boot sector and the setup header fit into the first two sectors
+const BOOT_SECTOR_LEN: usize = 1024;
+const SECTOR_SIZE: u64 = 512;
+
+const BOOT_FLAG: u16 = 0xAA55;
+const HEADER_MAGIC: u32 = u32::from_le_bytes(*b"HdrS");
+/// 2.12 added `xloadflags` and with it the 64-bit entry point
+const MIN_VERSION: u16 = 0x020C;
+/// 2.14 added `acpi_rsdp_addr`
+const VERSION_ACPI_RSDP: u16 = 0x020E;
+
+/// The 64-bit entry point is this far behind the start of the protected mode kernel
+const STARTUP_64_OFFSET: usize = 0x200;
+/// Loaders without an id of their own
+const LOADER_TYPE_UNDEFINED: u8 = 0xFF;
+const MAX_ADDRESS: u64 = 0xFFFF_FFFF;
+
+//Offsets in `struct boot_params`
+const SCREEN_INFO: usize = 0x000;
+const ACPI_RSDP_ADDR: usize = 0x070;
+const EFI_INFO: usize = 0x1C0;
+const E820_ENTRIES: usize = 0x1E8;
+const SETUP_HEADER: usize = 0x1F1;
+const E820_TABLE: usize = 0x2D0;
+
+//Offsets of the setup header fields, relative to the start of `struct boot_params`
+const SETUP_SECTS: usize = 0x1F1;
+const BOOT_FLAG_OFFSET: usize = 0x1FE;
+const JUMP: usize = 0x200;
+const HEADER: usize = 0x202;
+const VERSION: usize = 0x206;
+const TYPE_OF_LOADER: usize = 0x210;
+const LOADFLAGS: usize = 0x211;
+const CODE32_START: usize = 0x214;
+const RAMDISK_IMAGE: usize = 0x218;
+const RAMDISK_SIZE: usize = 0x21C;
+const HEAP_END_PTR: usize = 0x224;
+const CMD_LINE_PTR: usize = 0x228;
+const INITRD_ADDR_MAX: usize = 0x22C;
+const KERNEL_ALIGNMENT: usize = 0x230;
+const RELOCATABLE_KERNEL: usize = 0x234;
+const XLOADFLAGS: usize = 0x236;
+const CMDLINE_SIZE: usize = 0x238;
+const PREF_ADDRESS: usize = 0x258;
+const INIT_SIZE: usize = 0x260;
+const HANDOVER_OFFSET: usize = 0x264;
+
+const LOADED_HIGH: u8 = 1 << 0;
+const CAN_USE_HEAP: u8 = 1 << 7;
+
+const XLF_KERNEL_64: u16 = 1 << 0;
+const XLF_CAN_BE_LOADED_ABOVE_4G: u16 = 1 << 1;
+const XLF_EFI_HANDOVER_64: u16 = 1 << 3;
+
+const VIDEO_TYPE_EFI: u8 = 0x70;
+const VIDEO_CAPABILITY_64BIT_BASE: u32 = 1 << 1;
+
+const E820_MAX_ENTRIES: usize = 128;
+const E820_ENTRY_SIZE: usize = 20;
+const E820_RAM: u32 = 1;
+const E820_RESERVED: u32 = 2;
+const E820_ACPI: u32 = 3;
+const E820_NVS: u32 = 4;
+
+const EFI_LOADER_SIGNATURE: u32 = u32::from_le_bytes(*b"EL64");
+const EFI_MEMORY_DESCRIPTOR_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum LinuxError {
+    File(FileError),
+    Io(EfiStatus),
+    /// Boot protocol older than 2.12
+    UnsupportedVersion(u16),
+    /// The kernel has no 64-bit entry point
+    Not64Bit,
+    CmdlineTooLong {
+        len: usize,
+        max: usize,
+    },
+    Allocation {
+        address: u64,
+        status: EfiStatus,
+    },
+}
+
+impl From<EfiStatus> for LinuxError {
+    fn from(status: EfiStatus) -> Self {
+        LinuxError::Io(status)
+    }
+}
+
+impl From<FileError> for LinuxError {
+    fn from(error: FileError) -> Self {
+        LinuxError::File(error)
+    }
+}
+
+impl Display for LinuxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LinuxError::File(error) => write!(f, "{}", error),
+            LinuxError::Io(status) => write!(f, "read failed: {}", efi_status_name(*status)),
+            LinuxError::UnsupportedVersion(version) => write!(
+                f,
+                "boot protocol {}.{:02} is too old, 2.12 is required",
+                version >> 8,
+                version & 0xFF
+            ),
+            LinuxError::Not64Bit => write!(f, "the kernel has no 64-bit entry point"),
+            LinuxError::CmdlineTooLong { len, max } => write!(
+                f,
+                "the command line has {} bytes, the kernel accepts {}",
+                len, max
+            ),
+            LinuxError::Allocation { address, status } => write!(
+                f,
+                "can not allocate memory at {:#X}: {}",
+                address,
+                efi_status_name(*status)
+            ),
+        }
+    }
+}
+
+/// The setup header of a bzImage
+pub struct BzImage {
+    /// First two sectors of the file, the setup header is copied from here into `boot_params`
+    boot_sector: [u8; BOOT_SECTOR_LEN],
+    pub version: u16,
+    /// Offset of the protected mode kernel in the file
+    pub kernel_offset: u64,
+    pub xloadflags: u16,
+    pub pref_address: u64,
+    /// Memory the kernel needs to decompress itself, starting at its load address
+    pub init_size: u64,
+    pub kernel_alignment: u64,
+    pub relocatable: bool,
+    pub handover_offset: u32,
+    pub cmdline_size: u32,
+    pub initrd_addr_max: u32,
+}
+
+impl BzImage {
+    /// Looks for the setup header at the start of `reader`, returns `None` for other kernels
+    pub fn find<R: Read + Seek>(reader: &mut R) -> Result<Option<BzImage>, LinuxError> {
+        let mut boot_sector = [0u8; BOOT_SECTOR_LEN];
+        reader.seek(SeekFrom::Start(0))?;
+        match reader.read_exact(&mut boot_sector) {
+            Ok(()) => {}
+            Err(EFI_END_OF_FILE) => return Ok(None),
+            Err(status) => return Err(status.into()),
+        }
+
+        let data = &boot_sector;
+        if u16_at(data, BOOT_FLAG_OFFSET) != BOOT_FLAG || u32_at(data, HEADER) != HEADER_MAGIC {
+            return Ok(None);
+        }
+
+        let version = u16_at(data, VERSION);
+        if version < MIN_VERSION {
+            return Err(LinuxError::UnsupportedVersion(version));
+        }
+
+        let xloadflags = u16_at(data, XLOADFLAGS);
+        if xloadflags & XLF_KERNEL_64 == 0 {
+            return Err(LinuxError::Not64Bit);
+        }
+
+        //Old kernels leave the number of setup sectors at 0, which means 4
+        let setup_sects = match data[SETUP_SECTS] {
+            0 => 4,
+            sects => sects as u64,
+        };
+
+        Ok(Some(BzImage {
+            boot_sector,
+            version,
+            kernel_offset: (setup_sects + 1) * SECTOR_SIZE,
+            xloadflags,
+            pref_address: u64_at(data, PREF_ADDRESS),
+            init_size: u32_at(data, INIT_SIZE) as u64,
+            kernel_alignment: u32_at(data, KERNEL_ALIGNMENT) as u64,
+            relocatable: data[RELOCATABLE_KERNEL] != 0,
+            handover_offset: u32_at(data, HANDOVER_OFFSET),
+            cmdline_size: u32_at(data, CMDLINE_SIZE),
+            initrd_addr_max: u32_at(data, INITRD_ADDR_MAX),
+        }))
+    }
+
+    /// The setup header as stored in the file, it ends with the jump at 0x200 and whatever that
+    /// jump skips
+    fn setup_header(&self) -> &[u8] {
+        let end = (JUMP + 2 + self.boot_sector[JUMP + 1] as usize).min(BOOT_SECTOR_LEN);
+
+        &self.boot_sector[SETUP_HEADER..end]
+    }
+
+    pub fn has_efi_handover(&self) -> bool {
+        self.xloadflags & XLF_EFI_HANDOVER_64 != 0 && self.handover_offset != 0
+    }
+}
+
+/// Checks whether the kernel at `path` is a bzImage
+#[allow(unsafe_code)]
+pub fn detect(root: &mut EfiFile, path: &str) -> Result<Option<BzImage>, LinuxError> {
+    let file = root.open_path(path, FILE_MODE_READ)?;
+    let result = BzImage::find(unsafe { &mut *file });
+    unsafe { (*file).close() };
+
+    result
+}
+
+/// Loads the kernel of `entry` with its initrd and starts it
+#[allow(unsafe_code)]
+pub fn boot(
+    image_handle: EfiHandle,
+    st: &SystemTable,
+    root: &mut EfiFile,
+    entry: &BootEntry,
+    image: &BzImage,
+    framebuffer: FrameBufferInfo,
+    pixels: PixelLayout,
+) -> Result<Infallible, LinuxError> {
+    let logger = logger();
+
+    let file = root.open_path(&entry.kernel, FILE_MODE_READ)?;
+    let result = load_kernel(unsafe { &mut *file }, image);
+    unsafe { (*file).close() };
+    let kernel = result?;
+
+    writeln!(
+        logger,
+        "\rLinux boot protocol {}.{:02}, kernel at {:#X}\r",
+        image.version >> 8,
+        image.version & 0xFF,
+        kernel.as_ptr() as u64
+    )
+    .unwrap();
+
+    let params = allocator().allocate_pages_below(
+        MAX_ADDRESS,
+        EfiMemoryType::BOOT_INFO,
+        BOOT_PARAMS_SIZE,
+    )?;
+    let header = image.setup_header();
+    params[SETUP_HEADER..SETUP_HEADER + header.len()].copy_from_slice(header);
+
+    params[TYPE_OF_LOADER] = LOADER_TYPE_UNDEFINED;
+    params[LOADFLAGS] |= LOADED_HIGH | CAN_USE_HEAP;
+    set_u16(params, HEAP_END_PTR, 0xFE00);
+    set_u32(params, CODE32_START, kernel.as_ptr() as u32);
+
+    //The kernel copies the command line early, before it touches any memory
+    let cmdline = entry.cmdline.as_bytes();
+    if cmdline.len() > image.cmdline_size as usize {
+        return Err(LinuxError::CmdlineTooLong {
+            len: cmdline.len(),
+            max: image.cmdline_size as usize,
+        });
+    }
+    let cmdline_buffer = allocator().allocate_pages_below(
+        MAX_ADDRESS,
+        EfiMemoryType::BOOT_INFO,
+        cmdline.len() + 1,
+    )?;
+    cmdline_buffer[..cmdline.len()].copy_from_slice(cmdline);
+    set_u32(params, CMD_LINE_PTR, cmdline_buffer.as_ptr() as u32);
+
+    if !entry.modules.is_empty() {
+        let initrd = load_initrd(root, &entry.modules, image.initrd_addr_max as u64)?;
+        set_u32(params, RAMDISK_IMAGE, initrd.as_ptr() as u32);
+        set_u32(params, RAMDISK_SIZE, initrd.len() as u32);
+    }
+
+    screen_info(params, &framebuffer, &pixels);
+
+    if image.version >= VERSION_ACPI_RSDP {
+        let rsdp = st.configuration_table(&[ACPI_20_TABLE_GUID, ACPI_TABLE_GUID]);
+        set_u64(params, ACPI_RSDP_ADDR, rsdp.unwrap_or(0));
+    }
+
+    let startup_64 = kernel.as_ptr() as usize + STARTUP_64_OFFSET;
+
+    //The EFI stub of the kernel sets up the rest and exits the boot services on its own
+    if image.has_efi_handover() {
+        writeln!(logger, "Calling Linux through the EFI handover protocol\r").unwrap();
+
+        let handover: unsafe extern "sysv64" fn(EfiHandle, *const SystemTable, *mut u8) -> ! =
+            unsafe { mem::transmute(startup_64 + image.handover_offset as usize) };
+        unsafe { handover(image_handle, st, params.as_mut_ptr()) }
+    }
+
+    allocator().reserve_pool(LOADER_POOL_SIZE)?;
+
+    let mut memory_regions = MemoryMapBuilder::new();
+    memory_regions.overlay(
+        framebuffer.address as u64,
+        framebuffer.len as u64,
+        MemoryRegionKind::FRAMEBUFFER,
+    );
+
+    let mut memory_map = exit_boot_services(image_handle, st, &mut memory_regions, usize::MAX)?;
+    let memory_regions = memory_regions.build(&memory_map);
+
+    e820_table(params, &memory_regions);
+    efi_info(params, st, &mut memory_map);
+
+    writeln!(logger, "Calling Linux\r").unwrap();
+
+    let gdt_pointer = GdtPointer::new(&LONG_MODE_GDT);
+    unsafe { linux_enter_64(startup_64 as u64, params.as_ptr() as u64, &gdt_pointer) }
+}
+
+/// Reads the protected mode kernel to its preferred address, or anywhere suitably aligned if it
+/// is relocatable. The returned slice covers `init_size` bytes.
+fn load_kernel(file: &mut EfiFile, image: &BzImage) -> Result<&'static mut [u8], LinuxError> {
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let kernel_len = file_len.saturating_sub(image.kernel_offset);
+    let size = image.init_size.max(kernel_len) as usize;
+
+    let kernel =
+        match allocator().allocate_pages_at(image.pref_address, EfiMemoryType::KERNEL, size) {
+            Ok(kernel) => kernel,
+            Err(status) if !image.relocatable => {
+                return Err(LinuxError::Allocation {
+                    address: image.pref_address,
+                    status,
+                })
+            }
+            Err(_) => {
+                //CODE32_START only holds 32 bits unless the kernel says it can live above 4 GiB
+                let max_address = if image.xloadflags & XLF_CAN_BE_LOADED_ABOVE_4G != 0 {
+                    u64::MAX
+                } else {
+                    MAX_ADDRESS
+                };
+
+                //Over-allocate to be able to align the start, the rest stays reserved
+                let align = image.kernel_alignment.max(PAGE_SIZE);
+                let pages = allocator()
+                    .allocate_pages_below(max_address, EfiMemoryType::KERNEL, size + align as usize)
+                    .map_err(|status| LinuxError::Allocation { address: 0, status })?;
+                let start = (pages.as_ptr() as u64).next_multiple_of(align) - pages.as_ptr() as u64;
+
+                &mut pages[start as usize..start as usize + size]
+            }
+        };
+
+    file.seek(SeekFrom::Start(image.kernel_offset))?;
+    let logger = logger();
+    let (data, _) = kernel.split_at_mut(kernel_len as usize);
+    read_with_progress(file, data, &mut |done, total| logger.progress(done, total))?;
+
+    Ok(kernel)
+}
+
+/// Concatenates the modules into a single initrd below `max_address`. Every module starts
+/// 4 byte aligned, so the kernel can unpack concatenated cpio archives.
+#[allow(unsafe_code)]
+fn load_initrd(
+    root: &mut EfiFile,
+    modules: &[String],
+    max_address: u64,
+) -> Result<&'static mut [u8], LinuxError> {
+    let logger = logger();
+
+    let mut files = Vec::with_capacity(modules.len());
+    let result = (|| -> Result<&'static mut [u8], LinuxError> {
+        let mut offsets = Vec::with_capacity(modules.len());
+        let mut total = 0usize;
+
+        for module in modules {
+            let path = BootEntry::module_path(module);
+            let file = root.open_path(path, FILE_MODE_READ)?;
+            files.push(file);
+
+            let size = unsafe { (*file).file_size() }
+                .map_err(|status| FileError::new(&to_uefi_path(path), status))?;
+            offsets.push((total, size as usize));
+            total = (total + size as usize).next_multiple_of(4);
+        }
+
+        let initrd = allocator()
+            .allocate_pages_below(max_address, EfiMemoryType::MODULES, total.max(1))
+            .map_err(|status| LinuxError::Allocation { address: 0, status })?;
+
+        for (index, (offset, size)) in offsets.into_iter().enumerate() {
+            writeln!(
+                logger,
+                "Loading initrd {}\r",
+                to_uefi_path(BootEntry::module_path(&modules[index]))
+            )
+            .unwrap();
+
+            let file = unsafe { &mut *files[index] };
+            read_with_progress(
+                file,
+                &mut initrd[offset..offset + size],
+                &mut |done, total| logger.progress(done, total),
+            )?;
+            writeln!(logger, "\r").unwrap();
+        }
+
+        Ok(&mut initrd[..total])
+    })();
+
+    for file in files {
+        unsafe { (*file).close() };
+    }
+
+    result
+}
+
+fn read_with_progress(
+    file: &mut EfiFile,
+    buffer: &mut [u8],
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<(), EfiStatus> {
+    match file.read_chunked(buffer, progress)? {
+        read if read == buffer.len() => Ok(()),
+        _ => Err(EFI_END_OF_FILE),
+    }
+}
+
+/// Describes the GOP framebuffer as an EFI framebuffer
+fn screen_info(params: &mut [u8], framebuffer: &FrameBufferInfo, pixels: &PixelLayout) {
+    let screen = &mut params[SCREEN_INFO..SCREEN_INFO + 0x40];
+    let address = framebuffer.address as u64;
+
+    screen[0x0F] = VIDEO_TYPE_EFI;
+    set_u16(screen, 0x12, framebuffer.screen_width as u16);
+    set_u16(screen, 0x14, framebuffer.screen_height as u16);
+    set_u16(screen, 0x16, 32);
+    set_u32(screen, 0x18, address as u32);
+    set_u32(screen, 0x1C, framebuffer.len as u32);
+    set_u16(screen, 0x24, (framebuffer.pixels_per_scan_line * 4) as u16);
+    //Size and position of red, green, blue and reserved
+    screen[0x26..0x2E].copy_from_slice(&[
+        pixels.red.size,
+        pixels.red.shift,
+        pixels.green.size,
+        pixels.green.shift,
+        pixels.blue.size,
+        pixels.blue.shift,
+        pixels.reserved.size,
+        pixels.reserved.shift,
+    ]);
+    set_u32(screen, 0x36, VIDEO_CAPABILITY_64BIT_BASE);
+    set_u32(screen, 0x3A, (address >> 32) as u32);
+}
+
+fn e820_table(params: &mut [u8], regions: &[MemoryRegion]) {
+    if regions.len() > E820_MAX_ENTRIES {
+        writeln!(
+            logger(),
+            "Memory map has {} regions, only {} are passed on\r",
+            regions.len(),
+            E820_MAX_ENTRIES
+        )
+        .unwrap();
+    }
+
+    let regions = &regions[..regions.len().min(E820_MAX_ENTRIES)];
+    for (index, region) in regions.iter().enumerate() {
+        let entry = E820_TABLE + index * E820_ENTRY_SIZE;
+        set_u64(params, entry, region.start);
+        set_u64(params, entry + 8, region.len());
+        set_u32(params, entry + 16, e820_type(region.kind));
+    }
+    params[E820_ENTRIES] = regions.len() as u8;
+}
+
+fn e820_type(kind: MemoryRegionKind) -> u32 {
+    match kind {
+        //The kernel reserves its image, the initrd and the boot data itself
+        MemoryRegionKind::USABLE
+        | MemoryRegionKind::BOOTLOADER_RECLAIMABLE
+        | MemoryRegionKind::KERNEL_AND_MODULES => E820_RAM,
+        MemoryRegionKind::ACPI_RECLAIMABLE => E820_ACPI,
+        MemoryRegionKind::ACPI_NVS => E820_NVS,
+        _ => E820_RESERVED,
+    }
+}
+
+/// Hands the system table and memory map to the kernel, so it can use the runtime services
+fn efi_info(params: &mut [u8], st: &SystemTable, memory_map: &mut [EfiMemoryDescriptor]) {
+    //The loader's own memory types mean nothing to the kernel
+    for descriptor in memory_map.iter_mut() {
+        if descriptor.memory_type.0 >= EfiMemoryType::KERNEL.0 {
+            descriptor.memory_type = EfiMemoryType::EFI_LOADER_DATA;
+        }
+    }
+
+    let system_table = st as *const SystemTable as u64;
+    let map = memory_map.as_ptr() as u64;
+    let info = &mut params[EFI_INFO..EFI_INFO + 0x20];
+
+    set_u32(info, 0x00, EFI_LOADER_SIGNATURE);
+    set_u32(info, 0x04, system_table as u32);
+    set_u32(info, 0x08, size_of::<EfiMemoryDescriptor>() as u32);
+    set_u32(info, 0x0C, EFI_MEMORY_DESCRIPTOR_VERSION);
+    set_u32(info, 0x10, map as u32);
+    set_u32(info, 0x14, mem::size_of_val(memory_map) as u32);
+    set_u32(info, 0x18, (system_table >> 32) as u32);
+    set_u32(info, 0x1C, (map >> 32) as u32);
+}
+
+/// Null, unused, 64-bit code at `__BOOT_CS` (0x10) and data at `__BOOT_DS` (0x18)
+static LONG_MODE_GDT: [u64; 4] = [0, 0, 0x00AF9A000000FFFF, 0x00CF92000000FFFF];
+
+#[allow(unsafe_code)]
+mod trampoline {
+    use crate::common::gdt::GdtPointer;
+    use core::arch::global_asm;
+
+    extern "sysv64" {
+        /// Loads the segments the 64-bit boot protocol asks for and jumps to `entry` with
+        /// `boot_params` in `rsi`
+        pub fn linux_enter_64(entry: u64, boot_params: u64, gdt: *const GdtPointer) -> !;
+    }
+
+    global_asm!(
+        ".global linux_enter_64",
+        "linux_enter_64:",
+        "cli",
+        "lgdt [rdx]",
+        "mov ax, 0x18",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov ss, ax",
+        "push 0x10",
+        "lea rax, [rip + 2f]",
+        "push rax",
+        "retfq",
+        "2:",
+        "jmp rdi",
+    );
+}
@@ -1,42 +1,78 @@
-#![no_std]
-#![no_main]
+//Unit tests run on the host with std and its test harness
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![deny(unsafe_code)]
 
 extern crate alloc;
 
 use crate::common::log::{FrameBuffer, FrameBufferInfo, Logger};
-use crate::common::paging::{PageMapTable, PageMapTableBuilder};
+use crate::common::memory::{MemoryMapBuilder, MemoryRegionKind};
+use crate::common::paging::{PageMapTableBuilder, PageTable};
+use crate::config::Config;
 use crate::efi::alloc::EfiAllocator;
-use crate::efi::graphics::{GraphicsOutput, GRAPHICS_OUTPUT_GUID};
-use crate::efi::loaded_image::{LoadedImage, LOADED_IMAGE_GUID};
+use crate::efi::graphics::{GraphicsOutput, PixelLayout};
+use crate::efi::loaded_image::LoadedImage;
 use crate::efi::logger::EfiLogger;
-use crate::efi::simple_fs::{SimpleFileSystem, SIMPLE_FILE_SYSTEM_GUID};
-use crate::efi::SystemTable;
-use crate::efi::{format_efi_status, EfiHandle, EfiMemoryDescriptor, EfiStatus};
+use crate::efi::simple_fs::{to_uefi_path, EfiFile, FileError, Read, FILE_MODE_READ};
+use crate::efi::{efi_status_name, format_efi_status, EfiHandle, EfiMemoryDescriptor};
+use crate::efi::{EfiMemoryType, EfiStatus};
+use crate::efi::{OpenProtocolAttributes, SystemTable, EFI_BUFFER_TOO_SMALL, EFI_NOT_FOUND};
+use crate::efi::{ACPI_20_TABLE_GUID, ACPI_TABLE_GUID, SMBIOS3_TABLE_GUID, SMBIOS_TABLE_GUID};
+use crate::elf::{ElfError, ElfLoader, ElfTarget};
+use crate::menu::{BootEntry, BootEntryKind, BootMenu, MenuChoice};
+use crate::volume::{find_volume, volumes, VolumeSelector};
 use alloc::boxed::Box;
-use alloc::string::ToString;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
 use alloc::vec::Vec;
+use bootinfo::tags::{tag_size, ModuleTag, TagWriter, END_TAG_SIZE};
+use bootinfo::{KernelArgs, MemoryMapType, MemoryRegion, Module};
 use core::arch::asm;
-use core::fmt::{Debug, Write};
+use core::fmt::{Debug, Display, Formatter, Write};
+use core::mem;
 use core::ops::Add;
-use core::panic::PanicInfo;
-use core::ptr::null;
-use core::{mem, slice};
-use elf_loader::{ElfFile, ProgramHeader, RelocationSection};
+use core::ptr::{addr_of, null, null_mut};
+use core::slice;
 
+mod bls;
+mod chainload;
 mod common;
+mod config;
 mod efi;
-
-#[global_allocator]
+mod elf;
+mod limine;
+mod linux;
+mod menu;
+mod multiboot2;
+mod shell;
+mod variables;
+mod volume;
+
+#[cfg_attr(not(test), global_allocator)]
 static mut ALLOC: EfiAllocator = EfiAllocator::new(null());
 
 static mut LOGGER: Option<*mut EfiLogger> = None;
 
+/// Size of the pool that serves allocations after the boot services have been exited
+const LOADER_POOL_SIZE: usize = 1024 * 1024;
+
+/// Descriptors the memory map buffer holds on top of the ones counted when it was sized
+const MEMORY_MAP_SLACK: u64 = 8;
+
+/// How often a changed memory map is read again before `ExitBootServices` is given up
+const EXIT_BOOT_SERVICES_ATTEMPTS: u32 = 4;
+
 #[allow(unsafe_code)]
 fn logger() -> &'static mut EfiLogger {
     unsafe { &mut *LOGGER.unwrap() }
 }
 
+#[allow(unsafe_code)]
+fn allocator() -> &'static EfiAllocator {
+    unsafe { &*addr_of!(ALLOC) }
+}
+
 #[allow(unsafe_code)]
 #[export_name = "efi_main"]
 fn main(handle: EfiHandle, system_table: *const SystemTable) -> u64 {
@@ -50,53 +86,56 @@ fn main(handle: EfiHandle, system_table: *const SystemTable) -> u64 {
     ////////////////////////////////////////////////////////////////////////////////////////////////
     // Step 1: Prepare logger                                                                     //
     ////////////////////////////////////////////////////////////////////////////////////////////////
-    let (framebuffer, mut logger) = {
+    let (mut framebuffer, mut pixels, mut logger, mut graphics) = {
         //Get the handles for the Graphics Output Protocol for the logger
-        let graphics_handle_result = st
+        let graphics_handles = match st
             .boot_services()
-            .locate_handle_for_protocol(&GRAPHICS_OUTPUT_GUID);
-        if graphics_handle_result.is_err() {
-            let _ = writeln!(
-                st.stdout(),
-                "Unable to locate graphics handle: {}",
-                format_efi_status(graphics_handle_result.unwrap_err())
-            );
-            panic!();
-        }
-
-        let graphics_handle = graphics_handle_result.unwrap();
+            .locate_handles_for_protocol::<GraphicsOutput>()
+        {
+            Ok(graphics_handles) => graphics_handles,
+            Err(status) => {
+                let _ = writeln!(
+                    st.stdout(),
+                    "Unable to locate graphics handle: {}",
+                    format_efi_status(status)
+                );
+                panic!();
+            }
+        };
 
-        let g_result = st.boot_services().open_protocol::<GraphicsOutput>(
-            graphics_handle,
-            GRAPHICS_OUTPUT_GUID,
-            handle,
-        );
-        if g_result.is_err() {
-            let _ = writeln!(
-                st.stdout(),
-                "Unable to load graphics protocol: {}",
-                format_efi_status(g_result.unwrap_err())
+        //Use the first output we can get, open it exclusively so the firmware console stops
+        //drawing over the logger
+        let mut g_result = Err(EFI_NOT_FOUND);
+        for graphics_handle in graphics_handles.iter() {
+            g_result = st.boot_services().open_protocol::<GraphicsOutput>(
+                *graphics_handle,
+                handle,
+                OpenProtocolAttributes::EXCLUSIVE,
             );
-            panic!();
-        }
 
-        let g = unsafe { &*g_result.unwrap() };
-        let mode = g.mode();
-        let mode_info = mode.current_mode();
+            if g_result.is_ok() {
+                break;
+            }
+        }
+        let g = match g_result {
+            Ok(g) => g,
+            Err(status) => {
+                let _ = writeln!(
+                    st.stdout(),
+                    "Unable to load graphics protocol: {}",
+                    format_efi_status(status)
+                );
+                panic!();
+            }
+        };
 
         //Save the framebuffer data so we can use it in kernel later and access logger
-        let framebuffer = FrameBufferInfo {
-            address: mode.framebuffer as usize,
-            len: mode.framebuffer_len as usize,
-            screen_width: mode_info.horizontal_resolution,
-            screen_height: mode_info.vertical_resolution,
-            pixels_per_scan_line: mode_info.pixels_per_scan_line,
-        };
+        let (framebuffer, pixels) = framebuffer_info(&g);
 
         let mut fb = FrameBuffer::new(framebuffer.clone());
         let mut logger = EfiLogger::new(fb.clone());
 
-        (framebuffer, logger)
+        (framebuffer, pixels, logger, g)
     };
 
     unsafe {
@@ -111,60 +150,177 @@ fn main(handle: EfiHandle, system_table: *const SystemTable) -> u64 {
     .unwrap();
 
     ////////////////////////////////////////////////////////////////////////////////////////////////
-    // Step 2: Load kernel into memory                                                            //
+    // Step 2: Select the entry to boot and load its kernel into memory                           //
     ////////////////////////////////////////////////////////////////////////////////////////////////
-    let mut kernel_data = load_kernel(handle, st).expect("Unable to load kernel");
-    let kernel_file = ElfFile::read(kernel_data.as_mut_slice());
+    let boot_device = boot_device(handle, st);
+    let volumes = volumes(st.boot_services(), handle);
+    let boot_volume = volumes
+        .iter()
+        .find(|v| v.handle == boot_device)
+        .expect("Unable to open the boot volume");
 
-    if !kernel_file.is_valid() {
-        panic!(
-            "Can not load kernel: File is corrupted, it is not of type ELF\r\nKernel Length: {:?}",
-            kernel_file.data().len()
-        )
-    }
+    let config = match read_file(unsafe { &mut *boot_volume.root }, Config::PATH, |_, _| {}) {
+        Ok(data) => Config::parse(&String::from_utf8_lossy(&data)),
+        Err(_) => Config::default(),
+    };
 
-    kernel_file
-        .program_headers()
-        .iter()
-        .filter(|h| h.header_type == 0x1)
-        .for_each(|h| {
-            let offset = h.offset;
-            let v_addr = h.v_addr;
-            let mem_size = h.memory_size;
-            writeln!(
-                logger,
-                "Abs: {:#016X} - V: {:#016X} - {:8} Bytes\r",
-                kernel_file.data().as_ptr() as u64 + offset,
-                v_addr,
-                mem_size
+    let mut entries = vec![BootEntry {
+        id: "nightos".to_string(),
+        title: "NightOS".to_string(),
+        kind: BootEntryKind::Kernel,
+        volume: None,
+        kernel: config.kernel.clone(),
+        cmdline: config.cmdline.clone(),
+        modules: config.modules.clone(),
+        tries_left: None,
+    }];
+    entries.extend(bls::discover(&volumes));
+    entries.extend(config.chainload.iter().cloned());
+
+    //Entries read from a volume keep their files next to them
+    let entry_volume = |entry: &BootEntry, selector: Option<&VolumeSelector>| match entry.volume {
+        Some(volume) => volumes.iter().find(|v| v.handle == volume),
+        None => find_volume(&volumes, selector, boot_device, &entry.kernel),
+    };
+
+    let rt = st.runtime_services();
+    let default_id = variables::default_entry(rt, config.default.as_deref());
+    let default = BootEntry::default_index(&entries, default_id.as_deref());
+
+    //Chainloaded applications may return and kernels may fail to load, the menu is shown again
+    //then
+    let mut menu = BootMenu::new(&entries, default, Some(config.timeout));
+    let (entry, kernel) = loop {
+        let entry = match menu.run(st, &mut logger) {
+            MenuChoice::Boot(entry) => entry,
+            MenuChoice::Shell => {
+                shell::run(st, &mut logger, &volumes);
+                menu = BootMenu::new(&entries, default, None);
+                continue;
+            }
+        };
+
+        //Give the rest of the boot a deadline, so a hung loader still resets the machine
+        st.boot_services().set_watchdog_timer(config.watchdog);
+
+        if config.default.as_deref() == Some(variables::SAVED_DEFAULT) {
+            if let Err(status) = variables::set_entry(rt, variables::ENTRY_LAST_BOOTED, &entry.id) {
+                writeln!(
+                    logger,
+                    "Unable to save the chosen entry: {}\r",
+                    efi_status_name(status)
+                )
+                .unwrap();
+            }
+        }
+
+        //Counted before anything can fail, an entry that never gets marked as good runs out of
+        //tries and the next good one becomes the default
+        if entry.tries_left.is_some() {
+            let result = entry_volume(entry, None).map_or(Err(EFI_NOT_FOUND), |volume| {
+                bls::count_attempt(volume, &entry.id)
+            });
+            if let Err(status) = result {
+                writeln!(
+                    logger,
+                    "Unable to count the boot attempt of {}: {}\r",
+                    entry.title,
+                    efi_status_name(status)
+                )
+                .unwrap();
+            }
+        }
+
+        if entry.kind == BootEntryKind::Kernel {
+            let result = match entry_volume(entry, config.volume.as_ref()) {
+                Some(volume) => load_entry(
+                    handle,
+                    st,
+                    unsafe { &mut *volume.root },
+                    entry,
+                    &graphics,
+                    (&mut framebuffer, &mut pixels),
+                    &mut logger,
+                ),
+                None => Err(format!("{} not found", to_uefi_path(&entry.kernel))),
+            };
+
+            let error = match result {
+                Ok(kernel) => break (entry, kernel),
+                Err(error) => error,
+            };
+
+            //Unattended machines move on to the next good entry, a failing entry that counts its
+            //tries soon is bad itself
+            let index = entries
+                .iter()
+                .position(|e| core::ptr::eq(e, entry))
+                .unwrap_or(0);
+            let next = BootEntry::next_good_index(&entries, index);
+            let timeout = (next != index).then_some(config.timeout);
+            menu = BootMenu::new(&entries, next, timeout)
+                .with_message(format!("Unable to boot {}: {}", entry.title, error));
+            continue;
+        }
+
+        //The application draws through the firmware console, which only comes back once the
+        //loader gives up its exclusive open of the GOP
+        let graphics_handle = graphics.handle();
+        drop(graphics);
+        let _ = st.boot_services().connect_controller(graphics_handle);
+
+        let result = chainload::boot(handle, st, entry_volume(entry, None), entry);
+        st.boot_services().set_watchdog_timer(0);
+
+        graphics = st
+            .boot_services()
+            .open_protocol::<GraphicsOutput>(
+                graphics_handle,
+                handle,
+                OpenProtocolAttributes::EXCLUSIVE,
             )
-            .unwrap();
-        });
-    let kernel_len = kernel_file.load_segments_len();
+            .expect("Unable to reopen the graphics protocol");
+        (framebuffer, pixels) = framebuffer_info(&graphics);
+        logger = EfiLogger::new(FrameBuffer::new(framebuffer));
+
+        let message = match result {
+            Ok(exit) => format!("{} returned {}", entry.title, exit),
+            Err(error) => format!("Unable to start {}: {}", entry.title, error),
+        };
+
+        let selected = entries.iter().position(|e| core::ptr::eq(e, entry));
+        menu = BootMenu::new(&entries, selected.unwrap_or(0), None).with_message(message);
+    };
+
+    let (kernel_image, kernel_entry, modules) = (kernel.image, kernel.entry, kernel.modules);
+    let kernel_len = kernel_image.len();
 
     //Prepare page table
     //let page_table = init_page_table(&kernel_file);
 
-    //Retrieve RSDP
-    //TODO: Read RSDP
-
-    let mut kargs = Box::new(KernelArgs {
-        kernel_ptr: 0x100000 as *const u8,
-        kernel_len: 0,
-        rsd_ptr: &0u8,
-        memory_map: &0u8,
-        memory_map_size: 0,
-        memory_map_type: 0,
-        framebuffer,
+    //Retrieve RSDP, preferring the ACPI 2.0 one with the XSDT
+    let rsdp = st.configuration_table(&[ACPI_20_TABLE_GUID, ACPI_TABLE_GUID]);
+    let smbios = st.configuration_table(&[SMBIOS3_TABLE_GUID, SMBIOS_TABLE_GUID]);
+
+    let mut kargs = allocator().with_memory_type(EfiMemoryType::BOOT_INFO, || {
+        Box::new(KernelArgs {
+            kernel_ptr: kernel_image.as_ptr(),
+            kernel_len: kernel_len as u64,
+            rsd_ptr: rsdp.unwrap_or(0) as *const u8,
+            modules: modules.as_ptr(),
+            modules_len: modules.len() as u64,
+            ..KernelArgs::new(framebuffer)
+        })
     });
 
     ////////////////////////////////////////////////////////////////////////////////////////////////
     // Step 3: Prepare stack                                                                      //
     ////////////////////////////////////////////////////////////////////////////////////////////////
     let stack_size: usize = 128 * 1024;
-    let mut stack: Vec<u8> = Vec::new();
-    stack.resize(stack_size, 0);
-    let stack_ptr = stack.as_ptr() as usize + stack_size;
+    let stack: Vec<u8> =
+        allocator().with_memory_type(EfiMemoryType::KERNEL_STACK, || vec![0; stack_size]);
+    //The System V ABI expects a 16 byte aligned stack
+    let stack_ptr = (stack.as_ptr() as usize + stack_size) & !0xF;
 
     //Prepare identity paging
     //let page_tables = identity_paging();
@@ -172,11 +328,41 @@ fn main(handle: EfiHandle, system_table: *const SystemTable) -> u64 {
     ////////////////////////////////////////////////////////////////////////////////////////////////
     // Step 4: Exit the boot services and get memory map                                          //
     ////////////////////////////////////////////////////////////////////////////////////////////////
-    let memory_map = exit_boot_services(handle, st).unwrap();
+    allocator()
+        .reserve_pool(LOADER_POOL_SIZE)
+        .expect("Unable to reserve the loader pool");
+
+    let mut memory_regions = MemoryMapBuilder::new();
+    memory_regions.overlay(
+        framebuffer.address as u64,
+        framebuffer.len as u64,
+        MemoryRegionKind::FRAMEBUFFER,
+    );
+
+    let memory_map = exit_boot_services(handle, st, &mut memory_regions, usize::MAX).unwrap();
+    let memory_regions = memory_regions.build(&memory_map);
 
     kargs.memory_map = memory_map.as_ptr() as *const u8;
     kargs.memory_map_size = memory_map.len() as u64;
     kargs.memory_map_type = MemoryMapType::UEFI;
+    kargs.memory_regions = memory_regions.as_ptr();
+    kargs.memory_regions_len = memory_regions.len() as u64;
+
+    //Allocated from the loader pool, which the kernel can reclaim with the rest of the boot data
+    let tags = boot_info_tags(
+        &memory_regions,
+        &framebuffer,
+        rsdp,
+        smbios,
+        &entry.cmdline,
+        &modules,
+    );
+    kargs.tags = tags.as_ptr() as *const u8;
+    kargs.tags_len = (tags.len() * mem::size_of::<u64>()) as u64;
+
+    let (pool_start, pool_len) = allocator().pool();
+    kargs.loader_pool = pool_start as *const u8;
+    kargs.loader_pool_len = pool_len as u64;
 
     writeln!(
         logger,
@@ -202,21 +388,12 @@ fn main(handle: EfiHandle, system_table: *const SystemTable) -> u64 {
     )
     .unwrap();
 
-    ////////////////////////////////////////////////////////////////////////////////////////////////
-    // Step 5: Copy the kernel to 0x100000 static location and apply relocations                  //
-    ////////////////////////////////////////////////////////////////////////////////////////////////
-    writeln!(logger, "Allocating kernel: {}\r", kernel_len).unwrap();
-    //Write the kernel to 0x100000 in physical memory
-    let memory_location = unsafe { slice::from_raw_parts_mut(0x100000 as *mut u8, kernel_len) };
-    kernel_file.load(memory_location);
-    kernel_file.relocate(memory_location);
-
     ////////////////////////////////////////////////////////////////////////////////////////////////
     // Step 1: Call the kernel                                                                    //
     ////////////////////////////////////////////////////////////////////////////////////////////////
     //Load the main function into variable
     let kernel_main: unsafe extern "sysv64" fn(*const KernelArgs) -> ! =
-        unsafe { mem::transmute(memory_location.as_ptr().add(kernel_file.entrypoint())) };
+        unsafe { mem::transmute(kernel_image.as_ptr().add(kernel_entry)) };
 
     unsafe {
         writeln!(logger, "Calling kernel\r").unwrap();
@@ -240,15 +417,13 @@ pub unsafe fn call_kernel(
     kmain(args);
 }
 
-#[allow(unsafe_code)]
-pub fn load_kernel(handle: EfiHandle, st: &SystemTable) -> Result<Vec<u8>, EfiStatus> {
-    let mut logger = unsafe { &mut *LOGGER.unwrap() };
-    //logger.log("NightOS Bootloader (v0.0.1)\r\n");
-
-    //logger.log("Loading kernel from /kernel\r\n");
-    let loaded_image_result =
-        st.boot_services()
-            .open_protocol::<LoadedImage>(handle, LOADED_IMAGE_GUID, handle);
+/// Returns the handle of the device the loader was started from
+pub fn boot_device(handle: EfiHandle, st: &SystemTable) -> EfiHandle {
+    let loaded_image_result = st.boot_services().open_protocol::<LoadedImage>(
+        handle,
+        handle,
+        OpenProtocolAttributes::GET_PROTOCOL,
+    );
     if loaded_image_result.is_err() {
         panic!(
             "Unable to open LoadedImage protocol: {}",
@@ -257,49 +432,309 @@ pub fn load_kernel(handle: EfiHandle, st: &SystemTable) -> Result<Vec<u8>, EfiSt
     }
     let loaded_image = loaded_image_result.unwrap();
 
-    let sfp_result = unsafe {
-        st.boot_services().open_protocol::<SimpleFileSystem>(
-            (*loaded_image).device_handle,
-            SIMPLE_FILE_SYSTEM_GUID,
-            (*loaded_image).device_handle,
-        )
+    loaded_image.device_handle
+}
+
+/// The framebuffer of the current mode of `graphics` and the layout of its pixels
+fn framebuffer_info(graphics: &GraphicsOutput) -> (FrameBufferInfo, PixelLayout) {
+    let mode = graphics.mode();
+    let mode_info = mode.current_mode();
+
+    let framebuffer = FrameBufferInfo {
+        address: mode.framebuffer as usize,
+        len: mode.framebuffer_len as usize,
+        screen_width: mode_info.horizontal_resolution,
+        screen_height: mode_info.vertical_resolution,
+        pixels_per_scan_line: mode_info.pixels_per_scan_line,
     };
-    if sfp_result.is_err() {
-        panic!(
-            "Unable to open SimpleFileSystem protocol: {}",
-            sfp_result.unwrap_err()
-        );
+    //Without a framebuffer the logger has nothing to draw to either
+    let pixels = mode_info.pixel_layout().unwrap_or(PixelLayout::BGR);
+
+    (framebuffer, pixels)
+}
+
+/// A native kernel and its modules in memory, ready to be started
+pub struct LoadedKernel {
+    pub image: &'static mut [u8],
+    /// Offset of the entry point in `image`
+    pub entry: usize,
+    pub modules: Vec<Module>,
+}
+
+/// Starts the kernel of `entry` if it brings its own boot protocol, otherwise loads it and its
+/// modules for the native one. Errors are returned while the boot services are still there, so
+/// another entry can be booted instead.
+#[allow(unsafe_code)]
+fn load_entry(
+    handle: EfiHandle,
+    st: &SystemTable,
+    root: &mut EfiFile,
+    entry: &BootEntry,
+    graphics: &GraphicsOutput,
+    (framebuffer, pixels): (&mut FrameBufferInfo, &mut PixelLayout),
+    logger: &mut EfiLogger,
+) -> Result<LoadedKernel, String> {
+    writeln!(logger, "Loading {}\r", to_uefi_path(&entry.kernel)).unwrap();
+
+    //Kernels written for GRUB, Limine or stock Linux kernels bring their own protocol
+    if let Some(header) = multiboot2::detect(root, &entry.kernel).map_err(|e| e.to_string())? {
+        //The kernel may ask for a resolution, the logger follows the switch
+        if let Some(request) = header.framebuffer.filter(|r| !r.matches(framebuffer)) {
+            let mode = graphics.find_mode(st.boot_services(), request.width, request.height);
+            if let Some(Ok(())) = mode.map(|mode| graphics.set_mode(mode)) {
+                (*framebuffer, *pixels) = framebuffer_info(graphics);
+                *logger = EfiLogger::new(FrameBuffer::new(*framebuffer));
+            }
+        }
+
+        return match multiboot2::boot(handle, st, root, entry, &header, *framebuffer, *pixels) {
+            Ok(never) => match never {},
+            Err(error) => Err(error.to_string()),
+        };
     }
-    let sfp = sfp_result.unwrap();
 
-    let root = unsafe {
-        (*sfp)
-            .open_volume()
-            .expect("Unable to open volume due to error")
-    };
+    if let Some(image) = linux::detect(root, &entry.kernel).map_err(|e| e.to_string())? {
+        return match linux::boot(handle, st, root, entry, &image, *framebuffer, *pixels) {
+            Ok(never) => match never {},
+            Err(error) => Err(error.to_string()),
+        };
+    }
+
+    if let Some(kernel) = limine::detect(root, &entry.kernel).map_err(|e| e.to_string())? {
+        return match limine::boot(handle, st, root, entry, &kernel, *framebuffer, *pixels) {
+            Ok(never) => match never {},
+            Err(error) => Err(error.to_string()),
+        };
+    }
+
+    let (image, kernel_entry) = load_kernel(root, &entry.kernel).map_err(|e| e.to_string())?;
+    //Move below the progress bar
+    writeln!(logger, "\r").unwrap();
+
+    match load_modules(root, &entry.modules) {
+        Ok(modules) => Ok(LoadedKernel {
+            image,
+            entry: kernel_entry,
+            modules,
+        }),
+        Err(error) => {
+            allocator().free_pages(image);
+            Err(format!("module {}", error))
+        }
+    }
+}
+
+/// Physical address the kernel image is loaded to
+const KERNEL_ADDRESS: u64 = 0x100000;
+
+/// Why a native kernel can not be loaded
+#[derive(Debug)]
+pub enum KernelError {
+    File(FileError),
+    Efi(EfiStatus),
+    Elf(ElfError),
+}
+
+impl From<FileError> for KernelError {
+    fn from(error: FileError) -> Self {
+        KernelError::File(error)
+    }
+}
+
+impl From<EfiStatus> for KernelError {
+    fn from(status: EfiStatus) -> Self {
+        KernelError::Efi(status)
+    }
+}
+
+impl From<ElfError> for KernelError {
+    fn from(error: ElfError) -> Self {
+        KernelError::Elf(error)
+    }
+}
+
+impl Display for KernelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            KernelError::File(error) => write!(f, "{}", error),
+            KernelError::Efi(status) => write!(f, "firmware error: {}", efi_status_name(*status)),
+            KernelError::Elf(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Streams the loadable segments of the kernel at `path` into pages at [`KERNEL_ADDRESS`] and
+/// relocates them. Returns the image and the offset of its entry point.
+#[allow(unsafe_code)]
+pub fn load_kernel(
+    root: &mut EfiFile,
+    path: &str,
+) -> Result<(&'static mut [u8], usize), KernelError> {
+    let file = root.open_path(&to_uefi_path(path), FILE_MODE_READ)?;
+    let result = load_elf(unsafe { &mut *file });
+    unsafe { (*file).close() };
+
+    result
+}
+
+fn load_elf(file: &mut EfiFile) -> Result<(&'static mut [u8], usize), KernelError> {
+    let logger = logger();
+
+    //The headers are the only heap allocation, they are freed again when the loader is dropped
+    let mut loader = ElfLoader::new(file, &[ElfTarget::X86_64])?;
 
-    let file = unsafe { (*root).open("kernel", 1, 0).expect("Unable to open file") };
-    let file_size = unsafe { (*file).file_size() };
+    for h in loader.load_segments() {
+        writeln!(
+            logger,
+            "Offset: {:#016X} - V: {:#016X} - {:8} Bytes\r",
+            h.offset, h.v_addr, h.memory_size
+        )
+        .unwrap();
+    }
+
+    //Reserve the kernel's final location, so it is tagged in the memory map and not handed out to
+    //anyone else
+    let image = allocator().allocate_pages_at(
+        KERNEL_ADDRESS,
+        EfiMemoryType::KERNEL,
+        loader.image_size(),
+    )?;
+
+    let result = loader
+        .load(image, |done, total| logger.progress(done, total))
+        .and_then(|_| loader.relocate(image));
+    if let Err(error) = result {
+        allocator().free_pages(image);
+        return Err(error.into());
+    }
 
-    let mut buffer: Vec<u8> = Vec::new();
-    buffer.resize(file_size as usize, 0);
-    let mut buffer_size = buffer.capacity();
+    Ok((image, loader.entry_point()))
+}
+
+#[allow(unsafe_code)]
+pub fn read_file(
+    root: &mut EfiFile,
+    path: &str,
+    progress: impl FnMut(usize, usize),
+) -> Result<Vec<u8>, FileError> {
+    let file = root.open_path(path, FILE_MODE_READ)?;
+    let file_size = match unsafe { (*file).file_size() } {
+        Ok(size) => size,
+        Err(status) => {
+            unsafe { (*file).close() };
+            return Err(FileError::new(&to_uefi_path(path), status));
+        }
+    };
+
+    let mut buffer: Vec<u8> = vec![0; file_size as usize];
 
     unsafe {
-        let status = (*file).read_chunked(256, &mut buffer);
+        let result = (*file).read_chunked(&mut buffer, progress);
+        (*file).close();
+
+        match result {
+            //The file may have shrunk since its size was queried
+            Ok(read) => {
+                buffer.truncate(read);
+                Ok(buffer)
+            }
+            Err(status) => Err(FileError::new(&to_uefi_path(path), status)),
+        }
+    }
+}
 
-        if status == 0 {
-            Ok(buffer)
-        } else {
-            Err(status)
+/// Reads the file at `path` into zeroed pages of `memory_type` that end at or below
+/// `max_address`, so they show up in the memory map and need no copy
+#[allow(unsafe_code)]
+pub fn load_file(
+    root: &mut EfiFile,
+    path: &str,
+    max_address: u64,
+    memory_type: EfiMemoryType,
+    progress: impl FnMut(usize, usize),
+) -> Result<&'static mut [u8], FileError> {
+    let uefi_path = to_uefi_path(path);
+    let file = root.open_path(path, FILE_MODE_READ)?;
+
+    let result = unsafe { (*file).file_size() }.and_then(|size| {
+        //Empty files still get a page, so they have an address
+        let pages =
+            allocator().allocate_pages_below(max_address, memory_type, (size as usize).max(1))?;
+        let data = &mut pages[..size as usize];
+
+        let read = unsafe { (*file).read_chunked(data, progress)? };
+        Ok(&mut data[..read])
+    });
+    unsafe { (*file).close() };
+
+    result.map_err(|status| FileError::new(&uefi_path, status))
+}
+
+/// Loads the modules of an entry to pages of their own and describes them for the kernel, the
+/// list itself is boot information
+pub fn load_modules(root: &mut EfiFile, modules: &[String]) -> Result<Vec<Module>, FileError> {
+    let logger = logger();
+    let mut loaded = allocator().with_memory_type(EfiMemoryType::BOOT_INFO, || {
+        Vec::with_capacity(modules.len())
+    });
+
+    for module in modules {
+        let path = BootEntry::module_path(module);
+        writeln!(logger, "Loading module {}\r", to_uefi_path(path)).unwrap();
+
+        let data = load_file(
+            root,
+            path,
+            u64::MAX,
+            EfiMemoryType::MODULES,
+            |done, total| logger.progress(done, total),
+        )?;
+        writeln!(logger, "\r").unwrap();
+
+        //The name is cut at a character boundary, leaving room for the terminator
+        let mut len = path.len().min(Module::NAME_LEN - 1);
+        while !path.is_char_boundary(len) {
+            len -= 1;
         }
+        let mut name = [0u8; Module::NAME_LEN];
+        name[..len].copy_from_slice(&path.as_bytes()[..len]);
+
+        loaded.push(Module {
+            start: data.as_ptr() as u64,
+            len: data.len() as u64,
+            name,
+        });
     }
+
+    Ok(loaded)
+}
+
+/// Number of descriptors in the current memory map and the size of a descriptor
+#[allow(unsafe_code)]
+pub fn memory_map_size(st: &SystemTable) -> (usize, usize) {
+    let mut map_size_bytes = 0u64;
+    let mut map_key = 0u64;
+    let mut desc_size = 0u64;
+    let mut desc_version = 0u32;
+
+    st.boot_services().get_memory_map(
+        &mut map_size_bytes,
+        null_mut(),
+        &mut map_key,
+        &mut desc_size,
+        &mut desc_version,
+    );
+
+    let desc_size = desc_size.max(1) as usize;
+    (map_size_bytes as usize / desc_size, desc_size)
 }
 
 #[allow(unsafe_code)]
 pub fn exit_boot_services(
     handle: EfiHandle,
     st: &SystemTable,
+    memory_regions: &mut MemoryMapBuilder,
+    max_descriptors: usize,
 ) -> Result<Vec<EfiMemoryDescriptor>, EfiStatus> {
     let logger = logger();
 
@@ -309,36 +744,60 @@ pub fn exit_boot_services(
     let mut desc_size = 0u64;
     let mut desc_version = 0u32;
 
-    //Query size
-    let mut status = st.boot_services().get_memory_map(
+    //Query size, leave room for the descriptors the allocations below add to the map
+    st.boot_services().get_memory_map(
         &mut map_size_bytes,
         bytes.as_mut_ptr() as *mut EfiMemoryDescriptor,
         &mut map_key,
         &mut desc_size,
         &mut desc_version,
     );
-    map_size_bytes += 2 * desc_size;
+    map_size_bytes += MEMORY_MAP_SLACK * desc_size;
     bytes.resize(map_size_bytes as usize, 0);
 
     let map_len = map_size_bytes / desc_size;
-    let mut map = Vec::with_capacity(map_len as usize);
+    let mut map = allocator().with_memory_type(EfiMemoryType::BOOT_INFO, || {
+        memory_regions.reserve(map_len as usize);
+        Vec::with_capacity(map_len as usize)
+    });
 
-    status = st.boot_services().get_memory_map(
-        &mut map_size_bytes,
-        bytes.as_mut_ptr() as *mut EfiMemoryDescriptor,
-        &mut map_key,
-        &mut desc_size,
-        &mut desc_version,
-    );
+    //ExitBootServices fails if the map changed after it was read, then it has to be read again.
+    //Nothing may be allocated in between.
+    let capacity = bytes.len() as u64;
+    let mut attempts = 0;
+    let map_len = loop {
+        map_size_bytes = capacity;
+        let status = st.boot_services().get_memory_map(
+            &mut map_size_bytes,
+            bytes.as_mut_ptr() as *mut EfiMemoryDescriptor,
+            &mut map_key,
+            &mut desc_size,
+            &mut desc_version,
+        );
+        if status != 0 {
+            return Err(status);
+        }
+        //The caller sized its buffers for fewer descriptors, better fail while it can still
+        //report the error
+        if map_size_bytes / desc_size > max_descriptors as u64 {
+            return Err(EFI_BUFFER_TOO_SMALL);
+        }
 
-    //Now we need to exit the UEFI boot services and call the kernel main to hand over control
-    let exit_status = st.boot_services().exit_boot_services(handle, map_key);
+        let status = st.boot_services().exit_boot_services(handle, map_key);
+        if status == 0 {
+            //The map may have shrunk, only copy the descriptors the firmware actually wrote
+            break map_size_bytes / desc_size;
+        }
 
-    //Disable EFI Allocator as kernel will manage memory from now
-    unsafe {
-        ALLOC = EfiAllocator::new(null());
-        writeln!(*LOGGER.unwrap(), "Exiting Boot Services: {}\r", exit_status).expect("");
-    }
+        attempts += 1;
+        if attempts == EXIT_BOOT_SERVICES_ATTEMPTS {
+            return Err(status);
+        }
+    };
+
+    //The firmware allocator is gone, serve the remaining allocations from the loader pool
+    allocator().exit_boot_services();
+    writeln!(logger, "Exited Boot Services\r").unwrap();
 
     //Add the descriptors to the correctly aligned map
     unsafe {
@@ -360,23 +819,67 @@ pub fn exit_boot_services(
     return Ok(map);
 }
 
+/// Builds the tag list for the kernel in a single allocation, stored as `u64` to keep the tags
+/// aligned
 #[allow(unsafe_code)]
-pub fn identity_paging() -> Vec<u64> {
-    let mut page_table = Vec::new();
-    page_table.resize(2048, 0);
-
-    let mut address_pdp = 0;
-    unsafe {
-        address_pdp = page_table.as_ptr().add(1024) as u64;
+pub fn boot_info_tags(
+    memory_regions: &[MemoryRegion],
+    framebuffer: &FrameBufferInfo,
+    rsdp: Option<u64>,
+    smbios: Option<u64>,
+    cmdline: &str,
+    modules: &[Module],
+) -> Vec<u64> {
+    let address_size = tag_size(mem::size_of::<u64>());
+    let size = tag_size(mem::size_of_val(memory_regions))
+        + tag_size(mem::size_of::<FrameBufferInfo>())
+        + rsdp.map_or(0, |_| address_size)
+        + smbios.map_or(0, |_| address_size)
+        + tag_size(cmdline.len())
+        + modules
+            .iter()
+            .map(|m| tag_size(mem::size_of::<ModuleTag>() + m.name().len()))
+            .sum::<usize>()
+        + END_TAG_SIZE;
+
+    let mut buffer = vec![0u64; size / mem::size_of::<u64>()];
+    let bytes = unsafe { slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, size) };
+
+    let mut writer = TagWriter::new(bytes);
+    writer.memory_map(memory_regions).unwrap();
+    writer.framebuffer(framebuffer).unwrap();
+    if let Some(rsdp) = rsdp {
+        writer.rsdp(rsdp).unwrap();
+    }
+    if let Some(smbios) = smbios {
+        writer.smbios(smbios).unwrap();
+    }
+    writer.cmdline(cmdline).unwrap();
+    for module in modules {
+        let tag = ModuleTag {
+            start: module.start,
+            len: module.len,
+        };
+        writer.module(tag, module.name()).unwrap();
     }
+    writer.finish();
+
+    buffer
+}
+
+#[allow(unsafe_code)]
+pub fn identity_paging() -> Vec<PageTable> {
+    let mut page_tables =
+        allocator().with_memory_type(EfiMemoryType::PAGE_TABLES, || vec![PageTable::new(); 4]);
+
+    let address_pdp = page_tables[2].0.as_ptr() as u64;
 
     let pml4_entry: u64 = PageMapTableBuilder::from(0)
         .address(address_pdp)
         .present(true)
         .into();
 
-    let mut page = page_table.get_mut(0).unwrap();
-    *page = pml4_entry;
+    page_tables[0].0[0] = pml4_entry;
 
     for i in 0..8usize {
         let entry: u64 = PageMapTableBuilder::from(0)
@@ -386,16 +889,16 @@ pub fn identity_paging() -> Vec<u64> {
             .present(true)
             .into();
 
-        let mut page = page_table.get_mut(1024 + i).unwrap();
-        *page = entry;
+        page_tables[2].0[i] = entry;
     }
 
-    page_table
+    page_tables
 }
 
+#[cfg(not(test))]
 #[allow(unsafe_code)]
 #[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
+fn panic(info: &core::panic::PanicInfo) -> ! {
     unsafe {
         if LOGGER.is_some() {
             (*LOGGER.unwrap()).log(info.to_string().as_str());
@@ -403,29 +906,3 @@ fn panic(info: &PanicInfo) -> ! {
     }
     loop {}
 }
-
-struct MemoryMapType;
-
-impl MemoryMapType {
-    pub const UEFI: u8 = 1;
-}
-
-#[repr(C)]
-pub struct KernelArgs {
-    kernel_ptr: *const u8,
-    kernel_len: u64,
-
-    rsd_ptr: *const u8,
-
-    memory_map: *const u8,
-    memory_map_size: u64,
-    memory_map_type: u8,
-
-    framebuffer: FrameBufferInfo,
-}
-
-#[repr(C)]
-struct KernelElf {
-    loadable_headers: Vec<ProgramHeader>,
-    start_address: u64,
-}
@@ -0,0 +1,222 @@
+use crate::efi::io::EfiInputKey;
+use crate::efi::logger::EfiLogger;
+use crate::efi::{EfiHandle, SystemTable, TimerDelay, TIMER_TICKS_PER_SECOND};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// How an entry is started
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BootEntryKind {
+    /// A kernel booted through one of the supported protocols
+    Kernel,
+    /// Another EFI application started by the firmware, control comes back once it exits
+    Chainload,
+}
+
+#[derive(Clone, Debug)]
+pub struct BootEntry {
+    /// Names the entry in the configuration, e.g. the file name of a BLS entry without its
+    /// suffixes
+    pub id: String,
+    pub title: String,
+    pub kind: BootEntryKind,
+    /// Volume holding the files of the entry, they are searched for if it is not known
+    pub volume: Option<EfiHandle>,
+    /// Path of the kernel or EFI application on its volume
+    pub kernel: String,
+    /// Command line of the kernel, or the load options of a chainloaded application
+    pub cmdline: String,
+    /// Paths of the modules, optionally followed by arguments
+    pub modules: Vec<String>,
+    /// Boot attempts left before the entry counts as bad, `None` if it does not count them
+    pub tries_left: Option<u32>,
+}
+
+impl BootEntry {
+    /// Whether the entry used up its tries without being marked as good
+    pub fn is_bad(&self) -> bool {
+        self.tries_left == Some(0)
+    }
+
+    /// Whether `id` names this entry. `bootctl` names BLS entries by their file name, so a
+    /// trailing `.conf` is ignored.
+    pub fn has_id(&self, id: &str) -> bool {
+        let split = id.len().saturating_sub(".conf".len());
+        let id = match id.get(split..) {
+            Some(suffix) if suffix.eq_ignore_ascii_case(".conf") => &id[..split],
+            _ => id,
+        };
+
+        self.id == id
+    }
+
+    /// The entry booted without a choice: the one named `id`, or the next good entry after it if
+    /// it is bad. Without a matching entry the search starts at the first one.
+    pub fn default_index(entries: &[BootEntry], id: Option<&str>) -> usize {
+        let start = id
+            .and_then(|id| entries.iter().position(|e| e.has_id(id)))
+            .unwrap_or(0);
+
+        (0..entries.len())
+            .map(|i| (start + i) % entries.len())
+            .find(|i| !entries[*i].is_bad())
+            .unwrap_or(start)
+    }
+
+    /// The first good entry after the one at `index`, wrapping around. `index` itself if no other
+    /// entry is good.
+    pub fn next_good_index(entries: &[BootEntry], index: usize) -> usize {
+        (1..entries.len())
+            .map(|i| (index + i) % entries.len())
+            .find(|i| !entries[*i].is_bad())
+            .unwrap_or(index)
+    }
+
+    /// Splits a module line into the path of the file and the line passed to the kernel
+    pub fn module_path(module: &str) -> &str {
+        module.split_whitespace().next().unwrap_or("")
+    }
+}
+
+/// What the user picked in the menu
+pub enum MenuChoice<'a> {
+    Boot(&'a BootEntry),
+    /// The rescue shell, the menu is shown again once it is left
+    Shell,
+}
+
+pub struct BootMenu<'a> {
+    entries: &'a [BootEntry],
+    selected: usize,
+    /// Seconds until the selected entry is booted, `None` once a key has been pressed
+    timeout: Option<u64>,
+    /// Shown above the entries, e.g. why the last entry returned
+    message: Option<String>,
+}
+
+impl<'a> BootMenu<'a> {
+    /// Creates the menu, without a timeout it waits until an entry is chosen
+    pub fn new(entries: &'a [BootEntry], default: usize, timeout: Option<u64>) -> BootMenu<'a> {
+        BootMenu {
+            entries,
+            selected: default.min(entries.len() - 1),
+            timeout,
+            message: None,
+        }
+    }
+
+    pub fn with_message(mut self, message: String) -> BootMenu<'a> {
+        self.message = Some(message);
+        self
+    }
+
+    /// Shows the menu until an entry or the shell is chosen or the timeout expires
+    pub fn run(mut self, st: &SystemTable, logger: &mut EfiLogger) -> MenuChoice<'a> {
+        let boot_services = st.boot_services();
+        let stdin = st.stdin();
+
+        //Nobody should be reset while choosing an entry, the caller arms the watchdog again
+        boot_services.set_watchdog_timer(0);
+
+        //Without a timeout we only stop if a key has already been pressed
+        if self.timeout == Some(0) {
+            match stdin.read_key() {
+                Ok(Some(_)) => self.timeout = None,
+                _ => return MenuChoice::Boot(&self.entries[self.selected]),
+            }
+        }
+
+        let timer = boot_services
+            .create_timer()
+            .expect("Unable to create the boot menu timer");
+        if self.timeout.is_some() {
+            timer
+                .set_timer(TimerDelay::PERIODIC, TIMER_TICKS_PER_SECOND)
+                .expect("Unable to start the boot menu timer");
+        }
+
+        let choice = loop {
+            self.draw(logger);
+
+            let events = [stdin.wait_for_key_event(), timer.raw()];
+            match boot_services.wait_for_event(&events) {
+                Ok(0) => {
+                    let key = match stdin.read_key() {
+                        Ok(Some(key)) => key,
+                        _ => continue,
+                    };
+
+                    //Any key stops the countdown
+                    if self.timeout.take().is_some() {
+                        let _ = timer.set_timer(TimerDelay::CANCEL, 0);
+                    }
+
+                    if let Some(choice) = self.handle_key(key) {
+                        break choice;
+                    }
+                }
+                Ok(_) => match self.timeout {
+                    Some(0) | Some(1) => break MenuChoice::Boot(&self.entries[self.selected]),
+                    Some(remaining) => self.timeout = Some(remaining - 1),
+                    None => {}
+                },
+                Err(_) => break MenuChoice::Boot(&self.entries[self.selected]),
+            }
+        };
+
+        logger.clear();
+
+        choice
+    }
+
+    /// Returns the choice once an entry or the shell has been chosen
+    fn handle_key(&mut self, key: EfiInputKey) -> Option<MenuChoice<'a>> {
+        match key.scan_code {
+            EfiInputKey::SCAN_UP => {
+                self.selected = self
+                    .selected
+                    .checked_sub(1)
+                    .unwrap_or(self.entries.len() - 1);
+            }
+            EfiInputKey::SCAN_DOWN => {
+                self.selected = (self.selected + 1) % self.entries.len();
+            }
+            _ => match key.unicode_char {
+                EfiInputKey::CHAR_CARRIAGE_RETURN => {
+                    return Some(MenuChoice::Boot(&self.entries[self.selected]))
+                }
+                c @ 0x31..=0x39 if ((c - 0x31) as usize) < self.entries.len() => {
+                    self.selected = (c - 0x31) as usize;
+                    return Some(MenuChoice::Boot(&self.entries[self.selected]));
+                }
+                0x63 => return Some(MenuChoice::Shell),
+                _ => {}
+            },
+        }
+
+        None
+    }
+
+    fn draw(&self, logger: &mut EfiLogger) {
+        logger.clear();
+
+        if let Some(message) = &self.message {
+            let _ = writeln!(logger, "{}\r\n\r", message);
+        }
+        let _ = writeln!(logger, "Select an entry to boot:\r\n\r");
+        for (i, entry) in self.entries.iter().enumerate() {
+            let marker = if i == self.selected { '>' } else { ' ' };
+            let _ = writeln!(logger, "{} {}. {}\r", marker, i + 1, entry.title);
+        }
+
+        if let Some(remaining) = self.timeout {
+            let _ = writeln!(
+                logger,
+                "\r\nBooting {} in {}s, press any key to stop\r",
+                self.entries[self.selected].title, remaining
+            );
+        }
+        let _ = writeln!(logger, "\r\nPress c for a rescue shell\r");
+    }
+}
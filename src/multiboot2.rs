@@ -0,0 +1,889 @@
+//! Boots kernels that carry a multiboot2 header, the protocol GRUB uses. The kernel either gets
+//! control in 32-bit protected mode with paging disabled or, if it asks for it, in 64-bit mode
+//! with the boot services still running.
+
+use crate::common::bytes::{u16_at, u32_at};
+use crate::common::gdt::GdtPointer;
+use crate::common::log::FrameBufferInfo;
+use crate::common::memory::{MemoryMapBuilder, MemoryRegion, MemoryRegionKind, PAGE_SIZE};
+use crate::efi::graphics::PixelLayout;
+use crate::efi::simple_fs::FILE_MODE_READ;
+use crate::efi::simple_fs::{to_uefi_path, EfiFile, FileError, Read, Seek, SeekFrom};
+use crate::efi::{efi_status_name, EfiHandle, EfiMemoryDescriptor, EfiMemoryType, EfiStatus};
+use crate::efi::{SystemTable, ACPI_20_TABLE_GUID, ACPI_TABLE_GUID};
+use crate::elf::{ElfError, ElfLoader, ElfTarget};
+use crate::menu::BootEntry;
+use crate::{allocator, exit_boot_services, load_file, logger, memory_map_size, LOADER_POOL_SIZE};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootinfo::tags::tag_size;
+use core::convert::Infallible;
+use core::fmt::{Display, Formatter, Write};
+use core::mem::{size_of, size_of_val};
+use core::slice;
+use trampoline::{multiboot2_enter_efi64, multiboot2_enter_protected_mode};
+
+pub const HEADER_MAGIC: u32 = 0xE85250D6;
+/// Passed to the kernel in `eax`
+pub const BOOTLOADER_MAGIC: u32 = 0x36D76289;
+
+/// The header has to be in the first 32 KiB of the image, aligned to 8 bytes
+const SEARCH_LEN: usize = 32 * 1024;
+const ARCHITECTURE_I386: u32 = 0;
+
+/// Everything the kernel gets in 32-bit mode has to be addressable with 32 bits
+const MAX_ADDRESS: u64 = 0xFFFF_FFFF;
+
+/// The loader may ignore the tag if it does not support it
+const TAG_OPTIONAL: u16 = 1;
+
+/// Tags in the header of the kernel
+pub struct HeaderTag;
+
+impl HeaderTag {
+    pub const END: u16 = 0;
+    pub const INFORMATION_REQUEST: u16 = 1;
+    pub const ADDRESS: u16 = 2;
+    pub const ENTRY_ADDRESS: u16 = 3;
+    pub const CONSOLE_FLAGS: u16 = 4;
+    pub const FRAMEBUFFER: u16 = 5;
+    pub const MODULE_ALIGN: u16 = 6;
+    pub const EFI_BS: u16 = 7;
+    pub const ENTRY_ADDRESS_EFI32: u16 = 8;
+    pub const ENTRY_ADDRESS_EFI64: u16 = 9;
+    pub const RELOCATABLE: u16 = 10;
+}
+
+/// Tags in the boot information handed to the kernel
+pub struct InfoTag;
+
+impl InfoTag {
+    pub const END: u32 = 0;
+    pub const CMDLINE: u32 = 1;
+    pub const BOOT_LOADER_NAME: u32 = 2;
+    pub const MODULE: u32 = 3;
+    pub const BASIC_MEMINFO: u32 = 4;
+    pub const MMAP: u32 = 6;
+    pub const FRAMEBUFFER: u32 = 8;
+    pub const EFI64: u32 = 12;
+    pub const ACPI_OLD: u32 = 14;
+    pub const ACPI_NEW: u32 = 15;
+    pub const EFI_MMAP: u32 = 17;
+    pub const EFI_BS: u32 = 18;
+    pub const EFI64_IH: u32 = 20;
+    pub const LOAD_BASE_ADDR: u32 = 21;
+}
+
+/// Information tags a kernel may require with an information request
+const SUPPORTED_INFO: &[u32] = &[
+    InfoTag::CMDLINE,
+    InfoTag::BOOT_LOADER_NAME,
+    InfoTag::MODULE,
+    InfoTag::BASIC_MEMINFO,
+    InfoTag::MMAP,
+    InfoTag::FRAMEBUFFER,
+    InfoTag::EFI64,
+    InfoTag::ACPI_OLD,
+    InfoTag::ACPI_NEW,
+    InfoTag::EFI_MMAP,
+    InfoTag::EFI_BS,
+    InfoTag::EFI64_IH,
+    InfoTag::LOAD_BASE_ADDR,
+];
+
+/// Memory types of the `MMAP` tag
+const MEMORY_AVAILABLE: u32 = 1;
+const MEMORY_RESERVED: u32 = 2;
+const MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+const MEMORY_NVS: u32 = 4;
+
+const MMAP_ENTRY_SIZE: usize = 24;
+const EFI_MEMORY_DESCRIPTOR_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum Multiboot2Error {
+    File(FileError),
+    Io(EfiStatus),
+    Elf(ElfError),
+    UnsupportedArchitecture(u32),
+    /// A tag the kernel did not mark as optional
+    UnsupportedTag(u16),
+    /// Information the kernel can not boot without
+    UnsupportedInformation(u32),
+    /// The address tag points outside the file
+    InvalidAddressTag,
+    /// Neither an entry address tag nor an ELF image
+    MissingEntryPoint,
+    /// The segments do not have the same distance between virtual and physical address
+    ScatteredSegments,
+    /// The kernel or its entry point is not reachable from 32-bit mode
+    AboveFourGiB(u64),
+    /// The pages at the requested load address are not available
+    Allocation {
+        address: u64,
+        status: EfiStatus,
+    },
+}
+
+impl From<EfiStatus> for Multiboot2Error {
+    fn from(status: EfiStatus) -> Self {
+        Multiboot2Error::Io(status)
+    }
+}
+
+impl From<FileError> for Multiboot2Error {
+    fn from(error: FileError) -> Self {
+        Multiboot2Error::File(error)
+    }
+}
+
+impl From<ElfError> for Multiboot2Error {
+    fn from(error: ElfError) -> Self {
+        Multiboot2Error::Elf(error)
+    }
+}
+
+impl Display for Multiboot2Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Multiboot2Error::File(error) => write!(f, "{}", error),
+            Multiboot2Error::Io(status) => write!(f, "read failed: {}", efi_status_name(*status)),
+            Multiboot2Error::Elf(error) => write!(f, "{}", error),
+            Multiboot2Error::UnsupportedArchitecture(arch) => {
+                write!(f, "multiboot2 architecture {} is not supported", arch)
+            }
+            Multiboot2Error::UnsupportedTag(tag) => {
+                write!(f, "required multiboot2 header tag {} is not supported", tag)
+            }
+            Multiboot2Error::UnsupportedInformation(tag) => {
+                write!(
+                    f,
+                    "requested multiboot2 information {} is not supported",
+                    tag
+                )
+            }
+            Multiboot2Error::InvalidAddressTag => {
+                write!(f, "the multiboot2 address tag does not match the file")
+            }
+            Multiboot2Error::MissingEntryPoint => {
+                write!(
+                    f,
+                    "the kernel is not an ELF file and has no entry address tag"
+                )
+            }
+            Multiboot2Error::ScatteredSegments => write!(
+                f,
+                "the segments do not share one offset between virtual and physical address"
+            ),
+            Multiboot2Error::AboveFourGiB(address) => {
+                write!(
+                    f,
+                    "address {:#X} is not reachable from 32-bit mode",
+                    address
+                )
+            }
+            Multiboot2Error::Allocation { address, status } => write!(
+                f,
+                "can not allocate memory at {:#X}: {}",
+                address,
+                efi_status_name(*status)
+            ),
+        }
+    }
+}
+
+/// Where to load a kernel that is not an ELF file, taken from the address tag
+#[derive(Copy, Clone, Debug)]
+pub struct AddressTag {
+    /// Address the header is linked to
+    pub header_addr: u32,
+    /// Address the file contents starting at `load_addr - header_addr + header offset` go to
+    pub load_addr: u32,
+    /// End of the data to load, 0 if the rest of the file is loaded
+    pub load_end_addr: u32,
+    /// End of the zeroed bss, 0 if there is none
+    pub bss_end_addr: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct FramebufferRequest {
+    /// Preferred size and bits per pixel, 0 if the kernel has no preference
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+}
+
+impl FramebufferRequest {
+    /// Whether `framebuffer` is what the kernel asked for, GOP framebuffers always have 32 bits
+    /// per pixel
+    pub fn matches(&self, framebuffer: &FrameBufferInfo) -> bool {
+        (self.width == 0 || self.width == framebuffer.screen_width)
+            && (self.height == 0 || self.height == framebuffer.screen_height)
+            && (self.depth == 0 || self.depth == 32)
+    }
+}
+
+/// The parsed multiboot2 header of a kernel
+#[derive(Clone, Debug, Default)]
+pub struct Multiboot2Header {
+    /// Offset of the header in the file
+    pub offset: u64,
+    pub address: Option<AddressTag>,
+    pub entry: Option<u32>,
+    pub efi64_entry: Option<u32>,
+    /// The kernel can start with the boot services running
+    pub boot_services: bool,
+    /// The kernel wants a framebuffer, the loader switches to a matching mode if there is one
+    pub framebuffer: Option<FramebufferRequest>,
+    /// Modules have to be page aligned, which they always are
+    pub module_align: bool,
+    pub relocatable: bool,
+}
+
+impl Multiboot2Header {
+    /// Looks for the header at the start of `reader`, returns `None` for other kernels
+    pub fn find<R: Read + Seek>(
+        reader: &mut R,
+    ) -> Result<Option<Multiboot2Header>, Multiboot2Error> {
+        let mut data = vec![0u8; SEARCH_LEN];
+        reader.seek(SeekFrom::Start(0))?;
+        let len = reader.read_chunked(&mut data, |_, _| {})?;
+        let data = &data[..len];
+
+        for offset in (0..len.saturating_sub(16)).step_by(8) {
+            if u32_at(data, offset) != HEADER_MAGIC {
+                continue;
+            }
+
+            let architecture = u32_at(data, offset + 4);
+            let header_length = u32_at(data, offset + 8);
+            let checksum = u32_at(data, offset + 12);
+            //A stray magic number in the code is not a header
+            if HEADER_MAGIC
+                .wrapping_add(architecture)
+                .wrapping_add(header_length)
+                .wrapping_add(checksum)
+                != 0
+            {
+                continue;
+            }
+            if architecture != ARCHITECTURE_I386 {
+                return Err(Multiboot2Error::UnsupportedArchitecture(architecture));
+            }
+
+            let end = (offset + header_length as usize).min(len);
+            let tags = data.get(offset + 16..end).unwrap_or(&[]);
+
+            return Self::parse(offset as u64, tags).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    fn parse(offset: u64, tags: &[u8]) -> Result<Multiboot2Header, Multiboot2Error> {
+        let mut header = Multiboot2Header {
+            offset,
+            ..Multiboot2Header::default()
+        };
+
+        let mut position = 0;
+        while position + 8 <= tags.len() {
+            let tag_type = u16_at(tags, position);
+            let flags = u16_at(tags, position + 2);
+            let size = u32_at(tags, position + 4) as usize;
+            if tag_type == HeaderTag::END || size < 8 || position + size > tags.len() {
+                break;
+            }
+
+            let tag = &tags[position..position + size];
+            let optional = flags & TAG_OPTIONAL != 0;
+
+            match tag_type {
+                HeaderTag::INFORMATION_REQUEST if !optional => {
+                    for request in tag[8..].chunks_exact(4) {
+                        let request = u32_at(request, 0);
+                        if !SUPPORTED_INFO.contains(&request) {
+                            return Err(Multiboot2Error::UnsupportedInformation(request));
+                        }
+                    }
+                }
+                HeaderTag::INFORMATION_REQUEST => {}
+                HeaderTag::ADDRESS if size >= 24 => {
+                    header.address = Some(AddressTag {
+                        header_addr: u32_at(tag, 8),
+                        load_addr: u32_at(tag, 12),
+                        load_end_addr: u32_at(tag, 16),
+                        bss_end_addr: u32_at(tag, 20),
+                    })
+                }
+                HeaderTag::ENTRY_ADDRESS if size >= 12 => header.entry = Some(u32_at(tag, 8)),
+                HeaderTag::ENTRY_ADDRESS_EFI64 if size >= 12 => {
+                    header.efi64_entry = Some(u32_at(tag, 8))
+                }
+                HeaderTag::FRAMEBUFFER if size >= 20 => {
+                    header.framebuffer = Some(FramebufferRequest {
+                        width: u32_at(tag, 8),
+                        height: u32_at(tag, 12),
+                        depth: u32_at(tag, 16),
+                    })
+                }
+                HeaderTag::MODULE_ALIGN => header.module_align = true,
+                HeaderTag::EFI_BS => header.boot_services = true,
+                //The kernel is always loaded at the address it is linked to
+                HeaderTag::RELOCATABLE => header.relocatable = true,
+                //There is always a console, the flags only tell which one the kernel can use
+                HeaderTag::CONSOLE_FLAGS => {}
+                //Only used by loaders running on 32-bit firmware
+                HeaderTag::ENTRY_ADDRESS_EFI32 => {}
+                _ if optional => {}
+                _ => return Err(Multiboot2Error::UnsupportedTag(tag_type)),
+            }
+
+            position += (size + 7) & !7;
+        }
+
+        Ok(header)
+    }
+
+    /// The kernel wants to be started in 64-bit mode with the boot services still running
+    pub fn keeps_boot_services(&self) -> bool {
+        self.boot_services && self.efi64_entry.is_some()
+    }
+}
+
+/// Checks the kernel at `path` for a multiboot2 header
+#[allow(unsafe_code)]
+pub fn detect(root: &mut EfiFile, path: &str) -> Result<Option<Multiboot2Header>, Multiboot2Error> {
+    let file = root.open_path(path, FILE_MODE_READ)?;
+    let result = Multiboot2Header::find(unsafe { &mut *file });
+    unsafe { (*file).close() };
+
+    result
+}
+
+/// A module loaded below 4 GiB
+struct LoadedModule {
+    start: u64,
+    end: u64,
+    /// Path and arguments as written in the configuration
+    string: String,
+}
+
+/// Everything that goes into the boot information except the memory maps
+struct BootInformation<'a> {
+    cmdline: &'a str,
+    modules: Vec<LoadedModule>,
+    framebuffer: Option<(FrameBufferInfo, PixelLayout)>,
+    system_table: u64,
+    image_handle: u64,
+    acpi_old: Option<&'static [u8]>,
+    acpi_new: Option<&'static [u8]>,
+    load_base: u64,
+    boot_services: bool,
+}
+
+/// Memory maps, only available once the boot services are gone
+struct MemoryMaps<'a> {
+    regions: &'a [MemoryRegion],
+    descriptors: &'a [EfiMemoryDescriptor],
+}
+
+/// Loads the multiboot2 kernel of `entry` with its modules and starts it
+#[allow(unsafe_code)]
+pub fn boot(
+    image_handle: EfiHandle,
+    st: &SystemTable,
+    root: &mut EfiFile,
+    entry: &BootEntry,
+    header: &Multiboot2Header,
+    framebuffer: FrameBufferInfo,
+    pixels: PixelLayout,
+) -> Result<Infallible, Multiboot2Error> {
+    let logger = logger();
+
+    let file = root.open_path(&entry.kernel, FILE_MODE_READ)?;
+    let result = load_kernel(unsafe { &mut *file }, header);
+    unsafe { (*file).close() };
+    let (load_base, kernel_end, kernel_entry) = result?;
+
+    writeln!(
+        logger,
+        "\rMultiboot2 kernel at {:#X} - {:#X}, entry {:#X}\r",
+        load_base, kernel_end, kernel_entry
+    )
+    .unwrap();
+
+    let mut modules = Vec::new();
+    for module in &entry.modules {
+        let path = BootEntry::module_path(module);
+        writeln!(logger, "Loading module {}\r", to_uefi_path(path)).unwrap();
+
+        let data = load_file(
+            root,
+            path,
+            MAX_ADDRESS,
+            EfiMemoryType::MODULES,
+            |done, total| logger.progress(done, total),
+        )?;
+        writeln!(logger, "\r").unwrap();
+
+        modules.push(LoadedModule {
+            start: data.as_ptr() as u64,
+            end: data.as_ptr() as u64 + data.len() as u64,
+            string: module.clone(),
+        });
+    }
+
+    if let Some(request) = header.framebuffer {
+        if !request.matches(&framebuffer) {
+            writeln!(
+                logger,
+                "Kernel asks for a {}x{}x{} framebuffer, no such mode, using {}x{}x32\r",
+                request.width,
+                request.height,
+                request.depth,
+                framebuffer.screen_width,
+                framebuffer.screen_height
+            )
+            .unwrap();
+        }
+    }
+
+    let info = BootInformation {
+        cmdline: &entry.cmdline,
+        modules,
+        framebuffer: Some((framebuffer, pixels)),
+        system_table: st as *const SystemTable as u64,
+        image_handle: image_handle as u64,
+        acpi_old: st
+            .configuration_table(&[ACPI_TABLE_GUID])
+            .map(|a| rsdp(a, false)),
+        acpi_new: st
+            .configuration_table(&[ACPI_20_TABLE_GUID])
+            .map(|a| rsdp(a, true)),
+        load_base,
+        boot_services: header.keeps_boot_services(),
+    };
+
+    if let Some(efi64_entry) = header.efi64_entry.filter(|_| info.boot_services) {
+        let data = build_information(&info, None);
+        let mbi =
+            allocator().allocate_pages_below(MAX_ADDRESS, EfiMemoryType::BOOT_INFO, data.len())?;
+        mbi.copy_from_slice(&data);
+
+        //The kernel owns the machine now, it exits the boot services when it is ready
+        st.boot_services().set_watchdog_timer(0);
+        writeln!(logger, "Calling multiboot2 kernel with boot services\r").unwrap();
+
+        unsafe { multiboot2_enter_efi64(efi64_entry as u64, mbi.as_ptr() as u64) }
+    }
+
+    if kernel_entry > MAX_ADDRESS {
+        return Err(Multiboot2Error::AboveFourGiB(kernel_entry));
+    }
+    let trampoline = multiboot2_enter_protected_mode as *const () as u64;
+    if trampoline > MAX_ADDRESS {
+        return Err(Multiboot2Error::AboveFourGiB(trampoline));
+    }
+
+    //The memory maps are only known after exiting, reserve room for a map that grew a little
+    //The information is built once the firmware is gone, so it is sized for the worst case here.
+    //exit_boot_services refuses a map with more descriptors, and every descriptor and the
+    //framebuffer add at most two region boundaries.
+    let (descriptors, _) = memory_map_size(st);
+    let map_entries = descriptors + 16;
+    let region_entries = 2 * (map_entries + 1);
+    let mbi_size = build_information(&info, None).len()
+        + tag_size(8)
+        + tag_size(8 + region_entries * MMAP_ENTRY_SIZE)
+        + tag_size(8 + map_entries * size_of::<EfiMemoryDescriptor>());
+    let mbi = allocator().allocate_pages_below(MAX_ADDRESS, EfiMemoryType::BOOT_INFO, mbi_size)?;
+
+    allocator().reserve_pool(LOADER_POOL_SIZE)?;
+
+    let mut memory_regions = MemoryMapBuilder::new();
+    memory_regions.overlay(
+        framebuffer.address as u64,
+        framebuffer.len as u64,
+        MemoryRegionKind::FRAMEBUFFER,
+    );
+
+    let memory_map = exit_boot_services(image_handle, st, &mut memory_regions, map_entries)?;
+    let memory_regions = memory_regions.build(&memory_map);
+
+    let data = build_information(
+        &info,
+        Some(MemoryMaps {
+            regions: &memory_regions,
+            descriptors: &memory_map,
+        }),
+    );
+    //Sized for the largest map exit_boot_services accepts
+    mbi[..data.len()].copy_from_slice(&data);
+
+    writeln!(logger, "Calling multiboot2 kernel\r").unwrap();
+
+    let gdt_pointer = GdtPointer::new(&PROTECTED_MODE_GDT);
+    unsafe {
+        multiboot2_enter_protected_mode(kernel_entry as u32, mbi.as_ptr() as u32, &gdt_pointer)
+    }
+}
+
+/// Loads the kernel to the address it is linked to. Returns the load base, the end of the image
+/// and the physical entry point.
+fn load_kernel(
+    file: &mut EfiFile,
+    header: &Multiboot2Header,
+) -> Result<(u64, u64, u64), Multiboot2Error> {
+    let logger = logger();
+
+    if let Some(address) = header.address {
+        let file_len = file.seek(SeekFrom::End(0))?;
+
+        //The header is at `header_addr` in memory, everything else is placed relative to it
+        let file_offset = (address.header_addr as u64)
+            .checked_sub(address.load_addr as u64)
+            .and_then(|distance| header.offset.checked_sub(distance))
+            .ok_or(Multiboot2Error::InvalidAddressTag)?;
+        let load_start = address.load_addr as u64;
+        let load_end = match address.load_end_addr {
+            0 => load_start + (file_len - file_offset),
+            end => end as u64,
+        };
+        let end = load_end.max(address.bss_end_addr as u64);
+        if load_end < load_start || file_offset + (load_end - load_start) > file_len {
+            return Err(Multiboot2Error::InvalidAddressTag);
+        }
+
+        let image = allocate_kernel(load_start, end)?;
+        file.seek(SeekFrom::Start(file_offset))?;
+        //The bss is already zeroed
+        file.read_exact(&mut image[..(load_end - load_start) as usize])?;
+
+        //The EFI64 entry point only stands in if it is the one that gets called
+        let entry = header
+            .entry
+            .or(header.efi64_entry.filter(|_| header.keeps_boot_services()))
+            .ok_or(Multiboot2Error::MissingEntryPoint)?;
+
+        return Ok((load_start, end, entry as u64));
+    }
+
+    let mut loader = match ElfLoader::new(&mut *file, &[ElfTarget::I386, ElfTarget::X86_64]) {
+        Ok(loader) => loader,
+        Err(ElfError::NotElf) => return Err(Multiboot2Error::MissingEntryPoint),
+        Err(error) => return Err(error.into()),
+    };
+
+    //Segments are loaded to their physical address, which keeps working for kernels linked to
+    //the upper half
+    let offset = loader
+        .load_segments()
+        .next()
+        .map(|h| h.p_addr.wrapping_sub(h.v_addr))
+        .unwrap_or(0);
+    if loader
+        .load_segments()
+        .any(|h| h.p_addr.wrapping_sub(h.v_addr) != offset)
+    {
+        return Err(Multiboot2Error::ScatteredSegments);
+    }
+
+    for h in loader.load_segments() {
+        writeln!(
+            logger,
+            "Offset: {:#016X} - P: {:#016X} - {:8} Bytes\r",
+            h.offset, h.p_addr, h.memory_size
+        )
+        .unwrap();
+    }
+
+    let start = loader.base_address().wrapping_add(offset);
+    let end = start + loader.image_size() as u64;
+    let image = allocate_kernel(start, end)?;
+    loader.load(image, |done, total| logger.progress(done, total))?;
+
+    let entry = match header.entry {
+        Some(entry) => entry as u64,
+        None => loader.header.entry.wrapping_add(offset),
+    };
+
+    Ok((start, end, entry))
+}
+
+/// Allocates the pages between `start` and `end` as kernel memory and returns the bytes in that
+/// range
+fn allocate_kernel(start: u64, end: u64) -> Result<&'static mut [u8], Multiboot2Error> {
+    let page_start = start & !(PAGE_SIZE - 1);
+    let pages = allocator()
+        .allocate_pages_at(
+            page_start,
+            EfiMemoryType::KERNEL,
+            (end - page_start) as usize,
+        )
+        .map_err(|status| Multiboot2Error::Allocation {
+            address: page_start,
+            status,
+        })?;
+
+    Ok(&mut pages[(start - page_start) as usize..])
+}
+
+/// The RSDP as stored by the firmware, the ACPI 2.0 one carries its own length
+#[allow(unsafe_code)]
+fn rsdp(address: u64, extended: bool) -> &'static [u8] {
+    let len = if extended {
+        let length = unsafe { slice::from_raw_parts((address + 20) as *const u8, 4) };
+        (u32_at(length, 0) as usize).max(36)
+    } else {
+        20
+    };
+
+    unsafe { slice::from_raw_parts(address as *const u8, len) }
+}
+
+/// Builds the boot information, `memory` is left out for the size estimate before exiting the
+/// boot services and when the kernel keeps them
+fn build_information(info: &BootInformation, memory: Option<MemoryMaps>) -> Vec<u8> {
+    let mut data = Vec::new();
+    //Total size and reserved, the size is filled in at the end
+    data.extend_from_slice(&[0u8; 8]);
+
+    push_tag(
+        &mut data,
+        InfoTag::CMDLINE,
+        &[info.cmdline.as_bytes(), &[0]],
+    );
+    push_tag(
+        &mut data,
+        InfoTag::BOOT_LOADER_NAME,
+        &[
+            concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).as_bytes(),
+            &[0],
+        ],
+    );
+
+    for module in &info.modules {
+        push_tag(
+            &mut data,
+            InfoTag::MODULE,
+            &[
+                &(module.start as u32).to_le_bytes(),
+                &(module.end as u32).to_le_bytes(),
+                module.string.as_bytes(),
+                &[0],
+            ],
+        );
+    }
+
+    if let Some(memory) = &memory {
+        let (lower, upper) = basic_meminfo(memory.regions);
+        push_tag(
+            &mut data,
+            InfoTag::BASIC_MEMINFO,
+            &[&lower.to_le_bytes(), &upper.to_le_bytes()],
+        );
+
+        let mut entries = Vec::with_capacity(memory.regions.len() * MMAP_ENTRY_SIZE);
+        for region in memory.regions {
+            entries.extend_from_slice(&region.start.to_le_bytes());
+            entries.extend_from_slice(&region.len().to_le_bytes());
+            entries.extend_from_slice(&memory_type(region.kind).to_le_bytes());
+            entries.extend_from_slice(&0u32.to_le_bytes());
+        }
+        push_tag(
+            &mut data,
+            InfoTag::MMAP,
+            &[
+                &(MMAP_ENTRY_SIZE as u32).to_le_bytes(),
+                &0u32.to_le_bytes(),
+                &entries,
+            ],
+        );
+    }
+
+    if let Some((framebuffer, pixels)) = &info.framebuffer {
+        //Position and size of red, green and blue
+        let color_info = [
+            pixels.red.shift,
+            pixels.red.size,
+            pixels.green.shift,
+            pixels.green.size,
+            pixels.blue.shift,
+            pixels.blue.size,
+        ];
+        push_tag(
+            &mut data,
+            InfoTag::FRAMEBUFFER,
+            &[
+                &(framebuffer.address as u64).to_le_bytes(),
+                &(framebuffer.pixels_per_scan_line * 4).to_le_bytes(),
+                &framebuffer.screen_width.to_le_bytes(),
+                &framebuffer.screen_height.to_le_bytes(),
+                //32 bits per pixel, direct RGB, reserved
+                &[32, 1, 0, 0],
+                &color_info,
+            ],
+        );
+    }
+
+    push_tag(
+        &mut data,
+        InfoTag::EFI64,
+        &[&info.system_table.to_le_bytes()],
+    );
+    push_tag(
+        &mut data,
+        InfoTag::EFI64_IH,
+        &[&info.image_handle.to_le_bytes()],
+    );
+
+    if let Some(rsdp) = info.acpi_old {
+        push_tag(&mut data, InfoTag::ACPI_OLD, &[rsdp]);
+    }
+    if let Some(rsdp) = info.acpi_new {
+        push_tag(&mut data, InfoTag::ACPI_NEW, &[rsdp]);
+    }
+
+    if let Some(memory) = &memory {
+        let mut descriptors = Vec::with_capacity(size_of_val(memory.descriptors));
+        for descriptor in memory.descriptors {
+            //The loader's own memory types mean nothing to the kernel
+            let mut descriptor = *descriptor;
+            if descriptor.memory_type.0 >= EfiMemoryType::KERNEL.0 {
+                descriptor.memory_type = EfiMemoryType::EFI_LOADER_DATA;
+            }
+            descriptors.extend_from_slice(descriptor_bytes(&descriptor));
+        }
+        push_tag(
+            &mut data,
+            InfoTag::EFI_MMAP,
+            &[
+                &(size_of::<EfiMemoryDescriptor>() as u32).to_le_bytes(),
+                &EFI_MEMORY_DESCRIPTOR_VERSION.to_le_bytes(),
+                &descriptors,
+            ],
+        );
+    }
+
+    if info.boot_services {
+        push_tag(&mut data, InfoTag::EFI_BS, &[]);
+    }
+    push_tag(
+        &mut data,
+        InfoTag::LOAD_BASE_ADDR,
+        &[&(info.load_base as u32).to_le_bytes()],
+    );
+    push_tag(&mut data, InfoTag::END, &[]);
+
+    let total_size = data.len() as u32;
+    data[..4].copy_from_slice(&total_size.to_le_bytes());
+
+    data
+}
+
+/// Appends a tag made of the concatenated `parts` and pads it to 8 bytes
+fn push_tag(data: &mut Vec<u8>, tag_type: u32, parts: &[&[u8]]) {
+    let size = 8 + parts.iter().map(|p| p.len()).sum::<usize>();
+
+    data.extend_from_slice(&tag_type.to_le_bytes());
+    data.extend_from_slice(&(size as u32).to_le_bytes());
+    for part in parts {
+        data.extend_from_slice(part);
+    }
+    data.resize(data.len() + tag_size(size - 8) - size, 0);
+}
+
+/// KiB of usable memory below 640 KiB and KiB of usable memory starting at 1 MiB
+fn basic_meminfo(regions: &[MemoryRegion]) -> (u32, u32) {
+    let usable_at = |address: u64| {
+        regions
+            .iter()
+            .find(|r| r.kind == MemoryRegionKind::USABLE && r.start <= address && address < r.end)
+    };
+
+    let lower = usable_at(0).map_or(0, |r| r.end.min(0xA0000) / 1024);
+    let upper = usable_at(0x100000).map_or(0, |r| (r.end.min(MAX_ADDRESS) - 0x100000) / 1024);
+
+    (lower as u32, upper as u32)
+}
+
+fn memory_type(kind: MemoryRegionKind) -> u32 {
+    match kind {
+        //The boot information lives in loader memory, so only free memory is available
+        MemoryRegionKind::USABLE => MEMORY_AVAILABLE,
+        MemoryRegionKind::ACPI_RECLAIMABLE => MEMORY_ACPI_RECLAIMABLE,
+        MemoryRegionKind::ACPI_NVS => MEMORY_NVS,
+        _ => MEMORY_RESERVED,
+    }
+}
+
+#[allow(unsafe_code)]
+fn descriptor_bytes(descriptor: &EfiMemoryDescriptor) -> &[u8] {
+    unsafe {
+        slice::from_raw_parts(
+            descriptor as *const EfiMemoryDescriptor as *const u8,
+            size_of::<EfiMemoryDescriptor>(),
+        )
+    }
+}
+
+/// Null, 32-bit code at 0x08 and 32-bit data at 0x10, all flat 4 GiB segments
+static PROTECTED_MODE_GDT: [u64; 3] = [0, 0x00CF9A000000FFFF, 0x00CF92000000FFFF];
+
+#[allow(unsafe_code)]
+mod trampoline {
+    use super::BOOTLOADER_MAGIC;
+    use crate::common::gdt::GdtPointer;
+    use core::arch::global_asm;
+
+    extern "sysv64" {
+        /// Drops from long mode to 32-bit protected mode without paging and jumps to `entry`
+        /// with the multiboot2 magic in `eax` and `mbi` in `ebx`. Has to run from identity
+        /// mapped memory below 4 GiB.
+        pub fn multiboot2_enter_protected_mode(entry: u32, mbi: u32, gdt: *const GdtPointer) -> !;
+
+        /// Jumps to the 64-bit EFI `entry` with the multiboot2 magic in `eax` and `mbi` in `rbx`
+        pub fn multiboot2_enter_efi64(entry: u64, mbi: u64) -> !;
+    }
+
+    global_asm!(
+        ".global multiboot2_enter_protected_mode",
+        "multiboot2_enter_protected_mode:",
+        "cli",
+        "lgdt [rdx]",
+        "mov ebx, esi",
+        //Far return into the 32-bit code segment, which switches to compatibility mode
+        "push 0x08",
+        "lea rax, [rip + 2f]",
+        "push rax",
+        "retfq",
+        ".code32",
+        "2:",
+        "mov ax, 0x10",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov fs, ax",
+        "mov gs, ax",
+        "mov ss, ax",
+        //Disable paging, then clear EFER.LME to leave long mode
+        "mov eax, cr0",
+        "and eax, 0x7FFFFFFF",
+        "mov cr0, eax",
+        "mov ecx, 0xC0000080",
+        "rdmsr",
+        "and eax, 0xFFFFFEFF",
+        "wrmsr",
+        "mov eax, {magic}",
+        "jmp edi",
+        ".code64",
+        "",
+        ".global multiboot2_enter_efi64",
+        "multiboot2_enter_efi64:",
+        "mov eax, {magic}",
+        "mov rbx, rsi",
+        "jmp rdi",
+        magic = const BOOTLOADER_MAGIC,
+    );
+}
@@ -0,0 +1,151 @@
+//! A small rescue shell, opened from the boot menu to look around the volumes when no entry
+//! boots. The watchdog stays disarmed while it is open.
+
+use crate::efi::io::EfiInputKey;
+use crate::efi::logger::EfiLogger;
+use crate::efi::simple_fs::{to_uefi_path, FILE_MODE_READ};
+use crate::efi::SystemTable;
+use crate::read_file;
+use crate::volume::Volume;
+use alloc::string::String;
+use core::fmt::Write;
+
+/// Largest file `cat` shows, the screen does not hold more anyway
+const MAX_CAT_SIZE: usize = 64 * 1024;
+
+/// Reads and runs commands until `exit`
+pub fn run(st: &SystemTable, logger: &mut EfiLogger, volumes: &[Volume]) {
+    let mut current = 0;
+
+    logger.clear();
+    let _ = writeln!(logger, "Rescue shell, type help for the commands\r");
+
+    loop {
+        let _ = write!(logger, "\r\n{}:\\> ", current);
+        let line = match read_line(st, logger) {
+            Some(line) => line,
+            None => return,
+        };
+        let _ = writeln!(logger, "\r");
+
+        let (command, argument) = match line.trim().split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line.trim(), ""),
+        };
+
+        match command {
+            "" => {}
+            "help" => {
+                let _ = writeln!(logger, "volumes     list the volumes\r");
+                let _ = writeln!(logger, "vol <n>     switch to volume n\r");
+                let _ = writeln!(logger, "ls [path]   list a directory\r");
+                let _ = writeln!(logger, "cat <path>  show a text file\r");
+                let _ = writeln!(logger, "exit        back to the boot menu\r");
+            }
+            "volumes" => {
+                for (i, volume) in volumes.iter().enumerate() {
+                    let label = volume.label().unwrap_or_default();
+                    let _ = writeln!(logger, "{}: {}\r", i, label.trim());
+                }
+            }
+            "vol" => match argument.parse::<usize>() {
+                Ok(n) if n < volumes.len() => current = n,
+                _ => {
+                    let _ = writeln!(logger, "No volume {}\r", argument);
+                }
+            },
+            "ls" => match volumes.get(current) {
+                Some(volume) => list(logger, volume, argument),
+                None => {
+                    let _ = writeln!(logger, "No volumes\r");
+                }
+            },
+            "cat" => match volumes.get(current) {
+                Some(volume) => cat(logger, volume, argument),
+                None => {
+                    let _ = writeln!(logger, "No volumes\r");
+                }
+            },
+            "exit" => return,
+            _ => {
+                let _ = writeln!(logger, "Unknown command {}\r", command);
+            }
+        }
+    }
+}
+
+/// Reads a line with echo and backspace, `None` if escape was pressed
+fn read_line(st: &SystemTable, logger: &mut EfiLogger) -> Option<String> {
+    let stdin = st.stdin();
+    let mut line = String::new();
+
+    loop {
+        if st
+            .boot_services()
+            .wait_for_event(&[stdin.wait_for_key_event()])
+            .is_err()
+        {
+            return None;
+        }
+
+        let key = match stdin.read_key() {
+            Ok(Some(key)) => key,
+            _ => continue,
+        };
+
+        match (key.scan_code, key.unicode_char) {
+            (EfiInputKey::SCAN_ESC, _) => return None,
+            (_, EfiInputKey::CHAR_CARRIAGE_RETURN) => return Some(line),
+            (_, EfiInputKey::CHAR_BACKSPACE) if line.pop().is_some() => logger.erase_char(),
+            (_, c @ 0x20..=0x7E) => {
+                let c = c as u8 as char;
+                line.push(c);
+                let _ = write!(logger, "{}", c);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[allow(unsafe_code)]
+fn list(logger: &mut EfiLogger, volume: &Volume, path: &str) {
+    let directory = match unsafe { (*volume.root).open_path(path, FILE_MODE_READ) } {
+        Ok(directory) => directory,
+        Err(error) => {
+            let _ = writeln!(logger, "{}\r", error);
+            return;
+        }
+    };
+
+    for info in unsafe { (*directory).read_dir() } {
+        match info {
+            Ok(info) if info.is_directory() => {
+                let _ = writeln!(logger, "{:>10}  {}\\\r", "<DIR>", info.file_name);
+            }
+            Ok(info) => {
+                let _ = writeln!(logger, "{:>10}  {}\r", info.file_size, info.file_name);
+            }
+            Err(_) => {
+                let _ = writeln!(logger, "{} is not a directory\r", to_uefi_path(path));
+                break;
+            }
+        }
+    }
+
+    unsafe { (*directory).close() };
+}
+
+#[allow(unsafe_code)]
+fn cat(logger: &mut EfiLogger, volume: &Volume, path: &str) {
+    match read_file(unsafe { &mut *volume.root }, path, |_, _| {}) {
+        Ok(data) => {
+            let text = String::from_utf8_lossy(&data[..data.len().min(MAX_CAT_SIZE)]);
+            for line in text.lines() {
+                let _ = writeln!(logger, "{}\r", line);
+            }
+        }
+        Err(error) => {
+            let _ = writeln!(logger, "{}\r", error);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! EFI variables the loader shares with the running system. Names and vendor follow the Boot
+//! Loader Interface, so tools like `bootctl` can set them from the operating system. Values are
+//! null terminated UCS-2 entry ids, BLS entries may be named with or without `.conf` like
+//! `bootctl` does.
+
+use crate::efi::runtime::{RuntimeServices, VariableAttributes};
+use crate::efi::{string_from_char16, EfiGuid, EfiStatus};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const LOADER_VENDOR_GUID: EfiGuid = EfiGuid::new(
+    0x4A67B082,
+    0x0A4C,
+    0x41CF,
+    [0xB6, 0xC7, 0x44, 0x0B, 0x29, 0xBB, 0x8C, 0x4F],
+);
+
+/// Entry booted by default until it is changed
+pub const ENTRY_DEFAULT: &str = "LoaderEntryDefault";
+/// Entry booted by default on the next boot only, the loader deletes it once read
+pub const ENTRY_ONE_SHOT: &str = "LoaderEntryOneShot";
+/// Entry chosen last, kept if the configuration asks for `default @saved`
+pub const ENTRY_LAST_BOOTED: &str = "LoaderEntryLastBooted";
+
+/// The configured default that stands for the entry chosen last
+pub const SAVED_DEFAULT: &str = "@saved";
+
+const ATTRIBUTES: VariableAttributes = VariableAttributes(
+    VariableAttributes::NON_VOLATILE.0
+        | VariableAttributes::BOOTSERVICE_ACCESS.0
+        | VariableAttributes::RUNTIME_ACCESS.0,
+);
+
+/// Reads the entry id stored in `name`, `None` if it is missing or empty
+pub fn get_entry(rt: &RuntimeServices, name: &str) -> Option<String> {
+    let (data, _) = rt.get_variable(name, &LOADER_VENDOR_GUID).ok()?;
+    let id = string_from_char16(&data);
+
+    (!id.is_empty()).then_some(id)
+}
+
+pub fn set_entry(rt: &RuntimeServices, name: &str, id: &str) -> Result<(), EfiStatus> {
+    let data: Vec<u8> = id
+        .encode_utf16()
+        .chain(core::iter::once(0))
+        .flat_map(|c| c.to_le_bytes())
+        .collect();
+
+    rt.set_variable(name, &LOADER_VENDOR_GUID, ATTRIBUTES, &data)
+}
+
+/// Id of the entry to preselect: a one-shot entry, consumed here, comes first, followed by the
+/// persistent default and the configured one. `@saved` in the configuration picks the entry
+/// chosen last.
+pub fn default_entry(rt: &RuntimeServices, configured: Option<&str>) -> Option<String> {
+    if let Some(id) = get_entry(rt, ENTRY_ONE_SHOT) {
+        //Deleted right away, so it can not stick if the entry fails to boot
+        let _ = rt.delete_variable(ENTRY_ONE_SHOT, &LOADER_VENDOR_GUID);
+        return Some(id);
+    }
+
+    if let Some(id) = get_entry(rt, ENTRY_DEFAULT) {
+        return Some(id);
+    }
+
+    match configured {
+        Some(SAVED_DEFAULT) => get_entry(rt, ENTRY_LAST_BOOTED),
+        configured => configured.map(String::from),
+    }
+}
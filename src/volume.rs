@@ -0,0 +1,192 @@
+use crate::efi::block_io::{BlockIo, DiskIo};
+use crate::efi::io::DevicePathProtocol;
+use crate::efi::simple_fs::{EfiFile, SimpleFileSystem, FILE_MODE_READ};
+use crate::efi::{
+    BootServices, EfiGuid, EfiHandle, EfiStatus, OpenProtocolAttributes, ScopedProtocol,
+};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// Selects a volume by one of its identifiers, written in the configuration as `label:ESP`,
+/// `partuuid:<guid>` or `uuid:<serial>`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VolumeSelector {
+    Label(String),
+    PartitionGuid(EfiGuid),
+    /// The FAT volume serial number as shown by blkid, e.g. `1234-ABCD`
+    FilesystemUuid(String),
+}
+
+impl VolumeSelector {
+    pub fn parse(value: &str) -> Option<VolumeSelector> {
+        let (kind, id) = value.split_once(':')?;
+
+        match kind {
+            "label" => Some(VolumeSelector::Label(id.to_string())),
+            "partuuid" => EfiGuid::parse(id).map(VolumeSelector::PartitionGuid),
+            "uuid" => Some(VolumeSelector::FilesystemUuid(id.to_ascii_uppercase())),
+            _ => None,
+        }
+    }
+}
+
+/// A volume with a file system the firmware can read
+pub struct Volume<'a> {
+    pub handle: EfiHandle,
+    pub root: *mut EfiFile,
+    boot_services: &'a BootServices,
+    image: EfiHandle,
+    _file_system: ScopedProtocol<'a, SimpleFileSystem>,
+}
+
+impl<'a> Volume<'a> {
+    pub fn open(
+        boot_services: &'a BootServices,
+        handle: EfiHandle,
+        image: EfiHandle,
+    ) -> Result<Volume<'a>, EfiStatus> {
+        let mut file_system = boot_services.open_protocol::<SimpleFileSystem>(
+            handle,
+            image,
+            OpenProtocolAttributes::GET_PROTOCOL,
+        )?;
+        let root = file_system.open_volume()?;
+
+        Ok(Volume {
+            handle,
+            root,
+            boot_services,
+            image,
+            _file_system: file_system,
+        })
+    }
+
+    #[allow(unsafe_code)]
+    pub fn label(&self) -> Option<String> {
+        unsafe { (*self.root).file_system_info() }
+            .ok()
+            .map(|info| info.volume_label)
+    }
+
+    pub fn device_path(&self) -> Result<ScopedProtocol<'a, DevicePathProtocol>, EfiStatus> {
+        self.boot_services.open_protocol::<DevicePathProtocol>(
+            self.handle,
+            self.image,
+            OpenProtocolAttributes::GET_PROTOCOL,
+        )
+    }
+
+    /// The GPT partition GUID, taken from the hard drive node of the volume's device path
+    pub fn partition_guid(&self) -> Option<EfiGuid> {
+        let device_path = self.device_path().ok()?;
+
+        let node = device_path.nodes().find(|n| {
+            n.device_type() == DevicePathProtocol::TYPE_MEDIA
+                && n.sub_type() == DevicePathProtocol::SUB_TYPE_HARD_DRIVE
+        })?;
+
+        //Partition number, start and size precede the signature, the signature type follows it
+        let data = node.data();
+        if data.len() < 38 || data[37] != 0x02 {
+            return None;
+        }
+
+        let mut signature = [0u8; 16];
+        signature.copy_from_slice(&data[20..36]);
+
+        Some(EfiGuid::from_bytes(signature))
+    }
+
+    /// The FAT volume serial number, read from the boot sector
+    pub fn filesystem_uuid(&self) -> Option<String> {
+        let attributes = OpenProtocolAttributes::GET_PROTOCOL;
+        let block_io = self
+            .boot_services
+            .open_protocol::<BlockIo>(self.handle, self.image, attributes)
+            .ok()?;
+        let disk_io = self
+            .boot_services
+            .open_protocol::<DiskIo>(self.handle, self.image, attributes)
+            .ok()?;
+
+        let mut boot_sector = [0u8; 512];
+        disk_io
+            .read_disk(block_io.media().media_id, 0, &mut boot_sector)
+            .ok()?;
+
+        //FAT32 keeps the extended boot record further back than FAT12/16
+        let serial_offset = if &boot_sector[0x52..0x5A] == b"FAT32   " {
+            0x43
+        } else {
+            0x27
+        };
+        if boot_sector[serial_offset - 1] != 0x29 {
+            return None;
+        }
+
+        let serial = u32::from_le_bytes([
+            boot_sector[serial_offset],
+            boot_sector[serial_offset + 1],
+            boot_sector[serial_offset + 2],
+            boot_sector[serial_offset + 3],
+        ]);
+
+        Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF))
+    }
+
+    pub fn matches(&self, selector: &VolumeSelector) -> bool {
+        match selector {
+            VolumeSelector::Label(label) => self
+                .label()
+                .is_some_and(|l| l.trim().eq_ignore_ascii_case(label)),
+            VolumeSelector::PartitionGuid(guid) => self.partition_guid() == Some(*guid),
+            VolumeSelector::FilesystemUuid(uuid) => {
+                self.filesystem_uuid().as_deref() == Some(uuid.as_str())
+            }
+        }
+    }
+
+    #[allow(unsafe_code)]
+    pub fn contains(&self, path: &str) -> bool {
+        match unsafe { (*self.root).open_path(path, FILE_MODE_READ) } {
+            Ok(file) => {
+                unsafe { (*file).close() };
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Opens every volume with a file system the firmware knows about
+pub fn volumes(boot_services: &BootServices, image: EfiHandle) -> Vec<Volume<'_>> {
+    let handles = match boot_services.locate_handles_for_protocol::<SimpleFileSystem>() {
+        Ok(handles) => handles,
+        Err(_) => return Vec::new(),
+    };
+
+    handles
+        .iter()
+        .filter_map(|handle| Volume::open(boot_services, *handle, image).ok())
+        .collect()
+}
+
+/// Picks the volume to load `path` from. With a selector the matching volume is used, otherwise
+/// the boot volume is preferred, followed by the first other volume containing `path`.
+pub fn find_volume<'v, 'a>(
+    volumes: &'v [Volume<'a>],
+    selector: Option<&VolumeSelector>,
+    boot_volume: EfiHandle,
+    path: &str,
+) -> Option<&'v Volume<'a>> {
+    if let Some(selector) = selector {
+        return volumes.iter().find(|v| v.matches(selector));
+    }
+
+    volumes
+        .iter()
+        .filter(|v| v.handle == boot_volume)
+        .chain(volumes.iter().filter(|v| v.handle != boot_volume))
+        .find(|v| v.contains(path))
+}